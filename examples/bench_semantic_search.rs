@@ -0,0 +1,158 @@
+// Benchmarks `semantic_search` as the service runs it, against the indexes
+// the service creates at startup, on a synthetic graph. One user owns half of
+// the nodes so their searches are served by the vector index, the others own
+// a few each and exercise the owner scoped fallback. Recall is measured
+// against an exact scan over each user's nodes. Everything it creates is
+// labelled BenchNode and removed at the end.
+//
+//   NEO4J_URI=neo4j://localhost:7687 NEO4J_PASSWORD=... \
+//       cargo run --release --example bench_semantic_search -- [nodes] [runs]
+
+use std::{collections::HashSet, time::Instant};
+
+use console::{
+    types::GraphData,
+    utils::{config::Parsable, llm::EMBEDDING_DIMENSIONS},
+};
+use neo4rs::{query, ConfigBuilder, Graph};
+use rand::Rng;
+use uuid::Uuid;
+
+const USERS: usize = 20;
+const TOP_K: usize = 10;
+const THRESHOLD: f32 = 0.0;
+
+fn arg(position: usize, default: usize) -> usize {
+    std::env::args()
+        .nth(position)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(default)
+}
+
+async fn exact_top_ids(graph: &Graph, user_id: &Uuid, embedding: &[f32]) -> HashSet<String> {
+    let mut result = graph
+        .execute(
+            query(
+                r#"
+                MATCH (n:BenchNode {owner_id: $user_id})
+                WITH n, vector.similarity.cosine(n.embedding, $embedding) AS score
+                WHERE score > $threshold
+                RETURN n.id AS id
+                ORDER BY score DESC
+                LIMIT $top_k
+                "#,
+            )
+            .param("user_id", user_id.to_string())
+            .param("embedding", embedding.to_vec())
+            .param("threshold", (1.0 + THRESHOLD) / 2.0)
+            .param("top_k", TOP_K as i64),
+        )
+        .await
+        .unwrap();
+
+    let mut ids = HashSet::new();
+    while let Some(row) = result.next().await.unwrap() {
+        ids.insert(row.get::<String>("id").unwrap());
+    }
+    ids
+}
+
+async fn bench(graph: &Graph, name: &str, user_id: &Uuid, runs: usize) {
+    let mut elapsed = 0;
+    let mut found = 0;
+    let mut expected = 0;
+
+    for _ in 0..runs {
+        let embedding = (0..EMBEDDING_DIMENSIONS)
+            .map(|_| rand::thread_rng().gen_range(-1.0..1.0))
+            .collect::<Vec<f32>>();
+
+        let start = Instant::now();
+        let result: GraphData = graph
+            .semantic_search(user_id, embedding.clone(), THRESHOLD, TOP_K, &[])
+            .await
+            .unwrap()
+            .try_into()
+            .unwrap();
+        elapsed += start.elapsed().as_millis();
+
+        let exact = exact_top_ids(graph, user_id, &embedding).await;
+        let returned = result
+            .nodes
+            .into_iter()
+            .map(|node| node.id)
+            .collect::<HashSet<String>>();
+        found += exact.intersection(&returned).count();
+        expected += exact.len();
+    }
+
+    println!(
+        "{:<12} {:>6} ms avg over {} runs, recall@{} {}/{}",
+        name,
+        elapsed / runs as u128,
+        runs,
+        TOP_K,
+        found,
+        expected
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let nodes = arg(1, 5000);
+    let runs = arg(2, 10);
+
+    let graph = Graph::connect(
+        ConfigBuilder::default()
+            .uri(std::env::var("NEO4J_URI").unwrap_or_else(|_| String::from("127.0.0.1:7687")))
+            .user(std::env::var("NEO4J_USER").unwrap_or_else(|_| String::from("neo4j")))
+            .password(std::env::var("NEO4J_PASSWORD").unwrap_or_default())
+            .db("neo4j")
+            .build()
+            .unwrap(),
+    )
+    .await
+    .unwrap();
+
+    graph.ensure_search_index().await.unwrap();
+
+    let users = (0..USERS).map(|_| Uuid::new_v4()).collect::<Vec<Uuid>>();
+    println!("Creating {} embedded nodes for {} users...", nodes, USERS);
+    graph
+        .run(
+            query(
+                r#"
+                UNWIND range(1, $nodes) AS i
+                CALL {
+                    WITH i
+                    CREATE (:Goal:Embedded:BenchNode {
+                        id: randomUUID(),
+                        owner_id: CASE WHEN i % 2 = 0 THEN $owners[0] ELSE $owners[i % size($owners)] END,
+                        description: 'Synthetic goal ' + i,
+                        embedding: [x IN range(1, $dimensions) | rand() * 2 - 1]
+                    })
+                } IN TRANSACTIONS OF 1000 ROWS
+                "#,
+            )
+            .param("nodes", nodes as i64)
+            .param("dimensions", EMBEDDING_DIMENSIONS as i64)
+            .param(
+                "owners",
+                users.iter().map(|u| u.to_string()).collect::<Vec<String>>(),
+            ),
+        )
+        .await
+        .unwrap();
+    graph.run(query("CALL db.awaitIndexes(600)")).await.unwrap();
+
+    bench(&graph, "large user", &users[0], runs).await;
+    bench(&graph, "small user", &users[1], runs).await;
+
+    println!("Removing synthetic graph...");
+    graph
+        .run(query(
+            "MATCH (n:BenchNode) CALL { WITH n DETACH DELETE n } IN TRANSACTIONS OF 1000 ROWS",
+        ))
+        .await
+        .unwrap();
+}
@@ -0,0 +1,5 @@
+alter table chats
+add column title text,
+add column archived_at timestamp with time zone;
+
+create index chats_user_id_created_at_idx on chats (user_id, created_at desc);
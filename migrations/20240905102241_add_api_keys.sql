@@ -0,0 +1,12 @@
+create table api_keys (
+  id uuid primary key default gen_random_uuid (),
+  user_id uuid not null references users (id),
+  name text,
+  prefix text not null,
+  key_hash text not null unique,
+  created_at timestamp with time zone not null default now(),
+  last_used_at timestamp with time zone,
+  revoked_at timestamp with time zone
+);
+
+create index api_keys_user_id_idx on api_keys (user_id);
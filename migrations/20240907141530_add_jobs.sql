@@ -0,0 +1,34 @@
+create type job_kind as enum (
+  'extract_knowledge'
+);
+
+create type job_status as enum (
+  'pending',
+  'running',
+  'completed',
+  'failed'
+);
+
+create table jobs (
+  id uuid primary key default gen_random_uuid (),
+  user_id uuid not null references users (id),
+  kind job_kind not null,
+  status job_status not null default 'pending',
+  payload jsonb not null,
+  result jsonb,
+  idempotency_key text,
+  attempts integer not null default 0,
+  max_attempts integer not null default 5,
+  last_error text,
+  run_at timestamp with time zone not null default now(),
+  locked_at timestamp with time zone,
+  created_at timestamp with time zone not null default now(),
+  updated_at timestamp with time zone,
+  completed_at timestamp with time zone
+);
+
+create unique index jobs_kind_idempotency_key_idx on jobs (kind, idempotency_key);
+
+create index jobs_status_run_at_idx on jobs (status, run_at);
+
+create index jobs_user_id_created_at_idx on jobs (user_id, created_at desc);
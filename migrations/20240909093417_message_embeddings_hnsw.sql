@@ -0,0 +1,3 @@
+create index message_embeddings_embedding_idx on message_embeddings using hnsw (embedding vector_cosine_ops);
+
+create index message_embeddings_message_id_idx on message_embeddings (message_id);
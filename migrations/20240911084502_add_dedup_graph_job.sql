@@ -0,0 +1 @@
+alter type job_kind add value 'dedup_graph';
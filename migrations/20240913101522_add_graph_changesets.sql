@@ -0,0 +1,21 @@
+create type graph_change_source as enum (
+  'extraction',
+  'edit',
+  'task',
+  'dedup',
+  'import',
+  'rollback'
+);
+
+create table graph_changesets (
+  id uuid primary key default gen_random_uuid (),
+  user_id uuid not null references users (id),
+  version integer not null,
+  source graph_change_source not null,
+  chat_id uuid references chats (id),
+  changes jsonb not null,
+  rolled_back_at timestamp with time zone,
+  created_at timestamp with time zone not null default now()
+);
+
+create unique index graph_changesets_user_id_version_idx on graph_changesets (user_id, version);
@@ -0,0 +1,5 @@
+alter type chat_prompt add value 'weekly_review';
+
+alter type job_kind add value 'review_goals';
+
+alter type graph_change_source add value 'review';
@@ -0,0 +1,117 @@
+#!/usr/bin/env bash
+# One-off migration for graphs written before nodes carried an owner_id. Run it
+# once against each Neo4j instance before deploying the version that scopes
+# graph queries to owners; running it again only touches nodes still missing
+# an owner. Needs cypher-shell.
+#
+#   NEO4J_URI=neo4j://localhost:7687 NEO4J_PASSWORD=... scripts/backfill_owner_ids.sh
+#
+# Ownership is spread one hop per round from the user nodes. No node in the
+# schema is more than three hops from its user (User -> Goal <- Task -> Date),
+# so three rounds are enough and each one only looks at direct neighbours. A
+# node next to nodes of different users, like a date two users completed tasks
+# on, is split into one copy per user: every copy keeps the node's id, labels
+# and properties and takes over the relationships to its user's nodes, then the
+# shared node is deleted. Neighbours still without an owner are connected to
+# every copy and get split the same way in the next round.
+set -euo pipefail
+
+ROUNDS=3
+
+cypher() {
+  cypher-shell -a "${NEO4J_URI:-neo4j://localhost:7687}" -u "${NEO4J_USER:-neo4j}" \
+    -p "$NEO4J_PASSWORD" --format plain "$@"
+}
+
+duplicates=$(cypher "
+MATCH (u:User)
+WITH u.user_id AS user_id, count(*) AS copies
+WHERE copies > 1
+RETURN user_id, copies;" | tail -n +2)
+
+if [ -n "$duplicates" ]; then
+  echo "Users with more than one User node, merge them before running this again:"
+  echo "$duplicates"
+  exit 1
+fi
+
+cypher "
+MATCH (u:User) WHERE u.owner_id IS NULL
+SET u.owner_id = u.user_id;" >/dev/null
+
+# labels and relationship types can't be parameters, copies get theirs per
+# label and type found in the database
+list() {
+  cypher "$1" | tail -n +2 | tr -d '"'
+}
+labels=$(list "CALL db.labels() YIELD label WHERE label <> 'User' RETURN label;")
+types=$(list "CALL db.relationshipTypes() YIELD relationshipType RETURN relationshipType;")
+
+for round in $(seq 1 "$ROUNDS"); do
+  echo "Round $round..."
+  cypher ":auto
+MATCH (n) WHERE n.owner_id IS NULL AND NOT n:User
+CALL {
+  WITH n
+  MATCH (n)--(o) WHERE o.owner_id IS NOT NULL
+  WITH n, collect(DISTINCT o.owner_id) AS owners
+  WHERE size(owners) = 1
+  SET n.owner_id = owners[0]
+} IN TRANSACTIONS OF 10000 ROWS;" >/dev/null
+
+  # copies remember the node they were split from until it is deleted, so an
+  # interrupted run picks up where it stopped
+  cypher "
+MATCH (n) WHERE n.owner_id IS NULL AND NOT n:User
+MATCH (n)--(o) WHERE o.owner_id IS NOT NULL
+WITH n, collect(DISTINCT o.owner_id) AS owners
+WHERE size(owners) > 1
+UNWIND owners AS owner
+WITH n, owner
+WHERE NOT EXISTS { MATCH (c {split_from: elementId(n), owner_id: owner}) }
+CREATE (c)
+SET c = properties(n), c.owner_id = owner, c.split_from = elementId(n);" >/dev/null
+
+  for label in $labels; do
+    cypher "
+MATCH (c) WHERE c.split_from IS NOT NULL
+MATCH (n:\`$label\`) WHERE elementId(n) = c.split_from
+SET c:\`$label\`;" >/dev/null
+  done
+
+  # a neighbour that is split too is connected through its copy of the same user
+  for type in $types; do
+    cypher "
+MATCH (c) WHERE c.split_from IS NOT NULL
+MATCH (n)-[r:\`$type\`]->(o) WHERE elementId(n) = c.split_from AND o <> n
+OPTIONAL MATCH (oc {split_from: elementId(o), owner_id: c.owner_id})
+WITH c, r, coalesce(oc, o) AS o
+WHERE o.owner_id = c.owner_id OR o.owner_id IS NULL
+MERGE (c)-[copy:\`$type\`]->(o)
+SET copy += properties(r);" >/dev/null
+    cypher "
+MATCH (c) WHERE c.split_from IS NOT NULL
+MATCH (n)<-[r:\`$type\`]-(o) WHERE elementId(n) = c.split_from AND o <> n
+OPTIONAL MATCH (oc {split_from: elementId(o), owner_id: c.owner_id})
+WITH c, r, coalesce(oc, o) AS o
+WHERE o.owner_id = c.owner_id OR o.owner_id IS NULL
+MERGE (c)<-[copy:\`$type\`]-(o)
+SET copy += properties(r);" >/dev/null
+  done
+
+  split=$(list "
+MATCH (c) WHERE c.split_from IS NOT NULL
+WITH collect(DISTINCT c.split_from) AS originals
+MATCH (n) WHERE elementId(n) IN originals
+DETACH DELETE n
+RETURN count(n);")
+  cypher "MATCH (c) WHERE c.split_from IS NOT NULL REMOVE c.split_from;" >/dev/null
+  echo "Split $split shared nodes."
+done
+
+cypher "CREATE CONSTRAINT user_user_id_unique IF NOT EXISTS FOR (u:User) REQUIRE u.user_id IS UNIQUE;" >/dev/null
+
+echo "Nodes left without an owner, by label:"
+cypher "
+MATCH (n) WHERE n.owner_id IS NULL
+RETURN labels(n) AS labels, count(*) AS nodes;"
@@ -0,0 +1,10 @@
+#!/usr/bin/env bash
+# Benchmarks semantic_search, latency and recall against an exact scan, on a
+# synthetic graph. It runs the service's own query and indexes through
+# examples/bench_semantic_search.rs, so it needs a Neo4j 5.11+ instance.
+#
+#   NEO4J_URI=neo4j://localhost:7687 NEO4J_PASSWORD=... scripts/bench_semantic_search.sh [nodes] [runs]
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+cargo run --release --quiet --example bench_semantic_search -- "${1:-5000}" "${2:-10}"
@@ -0,0 +1,7 @@
+pub mod middleware;
+pub mod model;
+pub mod routes;
+pub mod types;
+pub mod utils;
+
+pub use utils::config::AppState;
@@ -1,19 +1,15 @@
 use actix_web::middleware::{from_fn, Logger};
 use actix_web::web::{self, ServiceConfig};
-use async_openai::config::OpenAIConfig;
-use async_openai::Client;
+use console::middleware::{self, auth::AuthConfig};
+use console::{routes, utils};
 use neo4rs::{ConfigBuilder, Graph};
 use shuttle_actix_web::ShuttleActixWeb;
 use shuttle_runtime::SecretStore;
 use sqlx::PgPool;
+use tracing::warn;
 use tracing_actix_web::TracingLogger;
-use utils::config::{AppEnv, AppState};
-
-pub mod middleware;
-pub mod model;
-pub mod routes;
-pub mod types;
-pub mod utils;
+use utils::config::{AppEnv, AppState, Parsable};
+use utils::llm::Provider;
 
 #[shuttle_runtime::main]
 async fn actix_web(
@@ -37,18 +33,32 @@ async fn actix_web(
 
     let graph = Graph::connect(neo4j_config).await.unwrap();
 
-    // init openai client
-    let api_key = app_env.openai_api_key.clone();
-    let openai_config = OpenAIConfig::new().with_api_key(api_key);
+    graph
+        .ensure_search_index()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to create the neo4j search index: {}", e))?;
+
+    if let Err(e) = graph.ensure_user_constraint().await {
+        warn!(
+            "Could not create the unique user constraint, run scripts/backfill_owner_ids.sh: {}",
+            e
+        );
+    }
+
+    // init llm provider
+    let llm = Provider::from_env(&app_env)?;
 
-    let client = Client::with_config(openai_config);
+    let auth = AuthConfig::new(&app_env)?;
 
     let app_state = web::Data::new(AppState {
         pool,
         graph,
-        openai_client: client,
+        llm,
+        auth,
     });
 
+    utils::jobs::spawn_job_workers(app_state.clone().into_inner(), app_env.job_workers);
+
     let config = move |cfg: &mut ServiceConfig| {
         cfg.service(
             web::scope("")
@@ -56,8 +66,54 @@ async fn actix_web(
                 .service(
                     web::scope("/ai")
                         .service(routes::ai::send_message)
+                        .service(routes::ai::send_message_stream)
                         .service(routes::ai::create_knowledge_graph)
-                        .service(routes::ai::search_knowledge_graph),
+                        .service(routes::ai::search_knowledge_graph)
+                        .service(routes::ai::search_messages),
+                )
+                .service(
+                    web::scope("/chats")
+                        .service(routes::chat::list_chats)
+                        .service(routes::chat::get_chat)
+                        .service(routes::chat::update_chat)
+                        .service(routes::chat::delete_chat)
+                        .service(routes::chat::restore_chat),
+                )
+                .service(
+                    web::scope("/graph")
+                        // imports carry a whole graph, well over the default limit
+                        .app_data(web::JsonConfig::default().limit(4 * 1024 * 1024))
+                        .service(routes::graph::get_graph)
+                        .service(routes::graph::export_graph_file)
+                        .service(routes::graph::import_graph_data)
+                        .service(routes::graph::create_node)
+                        .service(routes::graph::update_node)
+                        .service(routes::graph::delete_node)
+                        .service(routes::graph::get_node_provenance)
+                        .service(routes::graph::get_subgraph)
+                        .service(routes::graph::create_relationship)
+                        .service(routes::graph::delete_relationship)
+                        .service(routes::graph::get_relationship_provenance)
+                        .service(routes::graph::dedup_graph)
+                        .service(routes::graph::list_versions)
+                        .service(routes::graph::diff_versions)
+                        .service(routes::graph::rollback_version),
+                )
+                .service(
+                    web::scope("/tasks")
+                        .service(routes::task::list_tasks)
+                        .service(routes::task::update_task_status),
+                )
+                .service(
+                    web::scope("/jobs")
+                        .service(routes::job::list_jobs)
+                        .service(routes::job::get_job),
+                )
+                .service(
+                    web::scope("/api-keys")
+                        .service(routes::api_key::create_api_key)
+                        .service(routes::api_key::list_api_keys)
+                        .service(routes::api_key::revoke_api_key),
                 )
                 .wrap(from_fn(middleware::auth::authenticate_user))
                 .wrap(TracingLogger::default())
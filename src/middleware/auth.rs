@@ -4,13 +4,23 @@ use actix_web::{
     body::MessageBody,
     dev::{Payload, ServiceRequest, ServiceResponse},
     error::{ErrorInternalServerError, ErrorUnauthorized},
+    http::header::AUTHORIZATION,
     middleware::Next,
     web::Data,
     Error, FromRequest, HttpMessage, HttpRequest,
 };
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tracing::warn;
 use uuid::Uuid;
 
-use crate::{model::User, utils::config::AppState};
+use crate::{
+    model::{ApiKey, User, API_KEY_PREFIX},
+    utils::{
+        config::{AppEnv, AppState},
+        error::ApiError,
+    },
+};
 
 #[derive(Debug, Clone)]
 pub struct AuthenticatedUser {
@@ -30,33 +40,144 @@ impl FromRequest for AuthenticatedUser {
     }
 }
 
-pub async fn authenticate_user(
-    req: ServiceRequest,
-    next: Next<impl MessageBody>,
-) -> Result<ServiceResponse<impl MessageBody>, Error> {
-    let user_id = req
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+#[derive(Clone)]
+pub struct AuthConfig {
+    hs256_key: Option<DecodingKey>,
+    jwks: Option<JwkSet>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    allow_user_id_header: bool,
+}
+
+impl AuthConfig {
+    pub fn new(app_env: &AppEnv) -> Result<Self, anyhow::Error> {
+        let jwks = match &app_env.jwt_jwks_path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow::anyhow!("Failed to read JWKS file {}: {}", path, e))?;
+                Some(serde_json::from_str::<JwkSet>(&contents)?)
+            }
+            None => None,
+        };
+
+        if app_env.allow_user_id_header {
+            warn!("Trusting the user-id header. This must only be enabled in development.");
+        }
+
+        Ok(Self {
+            hs256_key: app_env
+                .jwt_secret
+                .as_ref()
+                .map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+            jwks,
+            issuer: app_env.jwt_issuer.clone(),
+            audience: app_env.jwt_audience.clone(),
+            allow_user_id_header: app_env.allow_user_id_header,
+        })
+    }
+
+    pub fn verify_jwt(&self, token: &str) -> Result<Uuid, ApiError> {
+        let unauthorized = |e: jsonwebtoken::errors::Error| ApiError::Unauthorized(e.to_string());
+
+        let header = decode_header(token).map_err(unauthorized)?;
+
+        let key = match header.alg {
+            Algorithm::HS256 => self
+                .hs256_key
+                .clone()
+                .ok_or_else(|| ApiError::Unauthorized("HS256 tokens are not accepted".into()))?,
+            Algorithm::RS256 => {
+                let jwks = self.jwks.as_ref().ok_or_else(|| {
+                    ApiError::Unauthorized("RS256 tokens are not accepted".into())
+                })?;
+
+                let jwk = match &header.kid {
+                    Some(kid) => jwks.find(kid),
+                    None if jwks.keys.len() == 1 => jwks.keys.first(),
+                    None => None,
+                }
+                .ok_or_else(|| ApiError::Unauthorized("No matching signing key".into()))?;
+
+                DecodingKey::from_jwk(jwk).map_err(unauthorized)?
+            }
+            alg => {
+                return Err(ApiError::Unauthorized(format!(
+                    "Unsupported token algorithm {:?}",
+                    alg
+                )))
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let claims = decode::<Claims>(token, &key, &validation)
+            .map_err(unauthorized)?
+            .claims;
+
+        Uuid::try_parse(&claims.sub)
+            .map_err(|_| ApiError::Unauthorized("Token subject is not a valid user id".into()))
+    }
+}
+
+async fn resolve_user_id(req: &ServiceRequest, app_state: &AppState) -> Result<Uuid, ApiError> {
+    let bearer = req
         .headers()
-        .get("user-id")
+        .get(AUTHORIZATION)
         .and_then(|h| h.to_str().ok())
-        .map(String::from);
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim);
 
-    if let Some(user_id) = user_id {
-        let uuid: Uuid = Uuid::try_parse(&user_id)
-            .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
+    if let Some(token) = bearer {
+        if token.starts_with(API_KEY_PREFIX) {
+            let api_key = ApiKey::authenticate(&app_state.pool, token)
+                .await?
+                .ok_or_else(|| ApiError::Unauthorized("Invalid API key".into()))?;
 
-        if let Some(app_state) = req.app_data::<Data<AppState>>() {
-            let pool = app_state.pool.clone();
-            User::get_or_create(&pool, uuid)
-                .await
-                .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
+            return Ok(api_key.user_id);
         }
 
-        req.extensions_mut()
-            .insert(AuthenticatedUser { user_id: uuid });
-    } else {
-        return Err(Error::from(ErrorUnauthorized("User ID is required")));
+        return app_state.auth.verify_jwt(token);
     }
 
+    if app_state.auth.allow_user_id_header {
+        if let Some(user_id) = req.headers().get("user-id").and_then(|h| h.to_str().ok()) {
+            return Uuid::try_parse(user_id)
+                .map_err(|e| ApiError::BadRequest(format!("Invalid user-id header: {}", e)));
+        }
+    }
+
+    Err(ApiError::Unauthorized("Bearer token is required".into()))
+}
+
+pub async fn authenticate_user(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let app_state = req
+        .app_data::<Data<AppState>>()
+        .cloned()
+        .ok_or_else(|| ErrorInternalServerError("App state is not configured"))?;
+
+    let user_id = resolve_user_id(&req, &app_state).await?;
+
+    User::get_or_create(&app_state.pool, user_id)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    req.extensions_mut().insert(AuthenticatedUser { user_id });
+
     let res = next.call(req).await?;
     Ok(res)
 }
@@ -0,0 +1,128 @@
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, FromRow, Pool, Postgres};
+use uuid::Uuid;
+
+pub const API_KEY_PREFIX: &str = "bk_";
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: Option<String>,
+    pub prefix: String,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    // returns the stored key alongside the plaintext secret, which is never persisted
+    pub async fn new(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        name: Option<String>,
+    ) -> Result<(Self, String), sqlx::Error> {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let secret = format!("{}{}", API_KEY_PREFIX, hex::encode(bytes));
+
+        let api_key = Self {
+            id: Uuid::new_v4(),
+            user_id,
+            name,
+            prefix: secret[..API_KEY_PREFIX.len() + 8].to_string(),
+            key_hash: Self::hash(&secret),
+            created_at: Utc::now(),
+            last_used_at: None,
+            revoked_at: None,
+        };
+
+        query!(
+            r#"
+            INSERT INTO api_keys (id, user_id, name, prefix, key_hash, created_at) 
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            api_key.id,
+            api_key.user_id,
+            api_key.name,
+            api_key.prefix,
+            api_key.key_hash,
+            api_key.created_at
+        )
+        .execute(pool)
+        .await?;
+
+        Ok((api_key, secret))
+    }
+
+    pub fn hash(secret: &str) -> String {
+        hex::encode(Sha256::digest(secret.as_bytes()))
+    }
+
+    pub async fn authenticate(
+        pool: &Pool<Postgres>,
+        secret: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let api_key = query_as!(
+            Self,
+            r#"
+            UPDATE api_keys
+            SET last_used_at = now()
+            WHERE key_hash = $1 AND revoked_at IS NULL
+            RETURNING id, user_id, name, prefix, key_hash, created_at, last_used_at, revoked_at
+            "#,
+            Self::hash(secret)
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(api_key)
+    }
+
+    pub async fn list_for_user(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let api_keys = query_as!(
+            Self,
+            r#"
+            SELECT id, user_id, name, prefix, key_hash, created_at, last_used_at, revoked_at
+            FROM api_keys
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(api_keys)
+    }
+
+    pub async fn revoke(
+        pool: &Pool<Postgres>,
+        api_key_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let api_key = query_as!(
+            Self,
+            r#"
+            UPDATE api_keys
+            SET revoked_at = now()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            RETURNING id, user_id, name, prefix, key_hash, created_at, last_used_at, revoked_at
+            "#,
+            api_key_id,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(api_key)
+    }
+}
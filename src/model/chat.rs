@@ -1,16 +1,22 @@
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use sqlx::{query, query_as, FromRow, Pool, Postgres};
 use uuid::Uuid;
 
-use crate::types::ai::ChatPrompts;
+use crate::{
+    types::{ai::ChatPrompts, ListChatsQuery},
+    utils::error::ApiError,
+};
 
-#[derive(Debug, Clone, FromRow)]
+#[derive(Debug, Clone, FromRow, Serialize)]
 pub struct Chat {
     pub id: Uuid,
     pub user_id: Uuid,
     pub flavour: ChatPrompts,
+    pub title: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: Option<DateTime<Utc>>,
+    pub archived_at: Option<DateTime<Utc>>,
     pub deleted_at: Option<DateTime<Utc>>,
 }
 
@@ -24,8 +30,10 @@ impl Chat {
         let chat = Self {
             id: chat_id.unwrap_or(Uuid::new_v4()),
             flavour,
+            title: None,
             created_at: Utc::now(),
             updated_at: Some(Utc::now()),
+            archived_at: None,
             deleted_at: None,
             user_id,
         };
@@ -52,7 +60,7 @@ impl Chat {
         let chat = query_as!(
             Self,
             r#"
-            SELECT id, flavour as "flavour: ChatPrompts", created_at, updated_at, deleted_at, user_id
+            SELECT id, flavour as "flavour: ChatPrompts", title, created_at, updated_at, archived_at, deleted_at, user_id
             FROM chats 
             WHERE id = $1
             "#,
@@ -63,4 +71,232 @@ impl Chat {
 
         Ok(chat)
     }
+
+    pub async fn get_for_user(
+        pool: &Pool<Postgres>,
+        chat_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Self, ApiError> {
+        let chat = Self::get(pool, chat_id)
+            .await?
+            .ok_or_else(|| ApiError::NotFound(format!("Chat {} not found", chat_id)))?;
+
+        chat.check_owner(user_id)?;
+
+        Ok(chat)
+    }
+
+    pub fn check_owner(&self, user_id: Uuid) -> Result<(), ApiError> {
+        if self.user_id != user_id {
+            return Err(ApiError::Forbidden(format!(
+                "Chat {} belongs to another user",
+                self.id
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn check_active(&self) -> Result<(), ApiError> {
+        if self.deleted_at.is_some() {
+            return Err(ApiError::NotFound(format!("Chat {} not found", self.id)));
+        }
+
+        Ok(())
+    }
+
+    pub async fn list_for_user(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        filter: &ListChatsQuery,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let chats = query_as!(
+            Self,
+            r#"
+            SELECT id, flavour as "flavour: ChatPrompts", title, created_at, updated_at, archived_at, deleted_at, user_id
+            FROM chats
+            WHERE user_id = $1
+                AND ($2::chat_prompt IS NULL OR flavour = $2)
+                AND ($3::timestamptz IS NULL OR created_at >= $3)
+                AND ($4::timestamptz IS NULL OR created_at < $4)
+                AND (archived_at IS NOT NULL) = $5
+                AND (deleted_at IS NOT NULL) = $6
+            ORDER BY created_at DESC
+            LIMIT $7 OFFSET $8
+            "#,
+            user_id,
+            filter.flavour.clone() as Option<ChatPrompts>,
+            filter.since,
+            filter.until,
+            filter.archived,
+            filter.deleted,
+            filter.limit(),
+            filter.offset()
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(chats)
+    }
+
+    pub async fn set_title(
+        pool: &Pool<Postgres>,
+        chat_id: Uuid,
+        user_id: Uuid,
+        title: Option<String>,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let chat = query_as!(
+            Self,
+            r#"
+            UPDATE chats
+            SET title = $3, updated_at = now()
+            WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL
+            RETURNING id, flavour as "flavour: ChatPrompts", title, created_at, updated_at, archived_at, deleted_at, user_id
+            "#,
+            chat_id,
+            user_id,
+            title
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(chat)
+    }
+
+    pub async fn set_archived(
+        pool: &Pool<Postgres>,
+        chat_id: Uuid,
+        user_id: Uuid,
+        archived: bool,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let chat = query_as!(
+            Self,
+            r#"
+            UPDATE chats
+            SET archived_at = CASE WHEN $3 THEN coalesce(archived_at, now()) ELSE NULL END,
+                updated_at = now()
+            WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL
+            RETURNING id, flavour as "flavour: ChatPrompts", title, created_at, updated_at, archived_at, deleted_at, user_id
+            "#,
+            chat_id,
+            user_id,
+            archived
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(chat)
+    }
+
+    pub async fn soft_delete(
+        pool: &Pool<Postgres>,
+        chat_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let chat = query_as!(
+            Self,
+            r#"
+            UPDATE chats
+            SET deleted_at = now()
+            WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL
+            RETURNING id, flavour as "flavour: ChatPrompts", title, created_at, updated_at, archived_at, deleted_at, user_id
+            "#,
+            chat_id,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(chat)
+    }
+
+    pub async fn restore(
+        pool: &Pool<Postgres>,
+        chat_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let chat = query_as!(
+            Self,
+            r#"
+            UPDATE chats
+            SET deleted_at = NULL, updated_at = now()
+            WHERE id = $1 AND user_id = $2 AND deleted_at IS NOT NULL
+            RETURNING id, flavour as "flavour: ChatPrompts", title, created_at, updated_at, archived_at, deleted_at, user_id
+            "#,
+            chat_id,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(chat)
+    }
+
+    pub async fn ids_for_user_since(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<Uuid>, sqlx::Error> {
+        let chat_ids = query!(
+            r#"
+            SELECT id
+            FROM chats
+            WHERE user_id = $1 AND created_at >= $2 AND deleted_at IS NULL
+            ORDER BY created_at ASC
+            "#,
+            user_id,
+            since
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect();
+
+        Ok(chat_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat_of(user_id: Uuid) -> Chat {
+        Chat {
+            id: Uuid::new_v4(),
+            user_id,
+            flavour: ChatPrompts::InitialGoals,
+            title: None,
+            created_at: Utc::now(),
+            updated_at: None,
+            archived_at: None,
+            deleted_at: None,
+        }
+    }
+
+    #[test]
+    fn check_owner_accepts_the_owner() {
+        let owner = Uuid::new_v4();
+
+        assert!(chat_of(owner).check_owner(owner).is_ok());
+    }
+
+    #[test]
+    fn check_owner_rejects_another_user() {
+        let chat = chat_of(Uuid::new_v4());
+
+        assert!(matches!(
+            chat.check_owner(Uuid::new_v4()),
+            Err(ApiError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn check_active_hides_deleted_chats() {
+        let mut chat = chat_of(Uuid::new_v4());
+        assert!(chat.check_active().is_ok());
+
+        chat.deleted_at = Some(Utc::now());
+        assert!(matches!(chat.check_active(), Err(ApiError::NotFound(_))));
+    }
 }
@@ -4,7 +4,8 @@ use neo4rs::{EndNodeId, Error, Node, StartNodeId, Type};
 use serde::Deserialize;
 use serde_json::json;
 
-use crate::types::{GraphData, GraphNode, GraphRelationship};
+use crate::types::{GraphData, GraphNode, GraphRelationship, GraphSearchResult, ScoredGraphNode};
+use crate::utils::constants::EMBEDDED_LABEL;
 
 #[derive(Debug, Clone, Deserialize)]
 pub enum Neo4jNode {
@@ -40,9 +41,9 @@ impl Neo4jNode {
     }
 }
 
-impl Into<GraphNode> for Neo4jNode {
-    fn into(self) -> GraphNode {
-        match self {
+impl From<Neo4jNode> for GraphNode {
+    fn from(node: Neo4jNode) -> Self {
+        match node {
             Neo4jNode::User(user) => user.into(),
             Neo4jNode::Interest(interest) => interest.into(),
             Neo4jNode::Goal(goal) => goal.into(),
@@ -59,44 +60,36 @@ impl TryInto<Neo4jNode> for Node {
     fn try_into(self) -> Result<Neo4jNode, Error> {
         let labels = self.labels();
         let node_type = *labels
-            .first()
+            .iter()
+            .find(|label| **label != EMBEDDED_LABEL)
             .ok_or_else(|| Error::UnsupportedScheme("Node has no labels".to_string()))?;
 
         let entity = match node_type {
             "User" => {
-                let user: UserNode = self
-                    .to::<UserNode>()
-                    .map_err(|e| Error::DeserializationError(e))?;
+                let user: UserNode = self.to::<UserNode>().map_err(Error::DeserializationError)?;
                 Neo4jNode::User(user)
             }
             "Interest" => {
-                let interest: Interest = self
-                    .to::<Interest>()
-                    .map_err(|e| Error::DeserializationError(e))?;
+                let interest: Interest =
+                    self.to::<Interest>().map_err(Error::DeserializationError)?;
                 Neo4jNode::Interest(interest)
             }
             "Goal" => {
-                let goal: Goal = self
-                    .to::<Goal>()
-                    .map_err(|e| Error::DeserializationError(e))?;
+                let goal: Goal = self.to::<Goal>().map_err(Error::DeserializationError)?;
                 Neo4jNode::Goal(goal)
             }
             "Motivation" => {
                 let motivation: Motivation = self
                     .to::<Motivation>()
-                    .map_err(|e| Error::DeserializationError(e))?;
+                    .map_err(Error::DeserializationError)?;
                 Neo4jNode::Motivation(motivation)
             }
             "Task" => {
-                let task: Task = self
-                    .to::<Task>()
-                    .map_err(|e| Error::DeserializationError(e))?;
+                let task: Task = self.to::<Task>().map_err(Error::DeserializationError)?;
                 Neo4jNode::Task(task)
             }
             "Date" => {
-                let date: Date = self
-                    .to::<Date>()
-                    .map_err(|e| Error::DeserializationError(e))?;
+                let date: Date = self.to::<Date>().map_err(Error::DeserializationError)?;
                 Neo4jNode::Date(date)
             }
             _ => {
@@ -125,6 +118,8 @@ pub struct Neo4jRelation {
 pub struct Neo4jGraph {
     pub nodes: HashMap<i64, Neo4jNode>,
     pub relations: Vec<Neo4jRelation>,
+    #[serde(default)]
+    pub scores: HashMap<i64, f32>,
 }
 
 impl TryInto<GraphData> for Neo4jGraph {
@@ -134,8 +129,8 @@ impl TryInto<GraphData> for Neo4jGraph {
         let nodes: Vec<GraphNode> = self
             .nodes
             .clone()
-            .into_iter()
-            .map(|(_, node)| node.into())
+            .into_values()
+            .map(|node| node.into())
             .collect();
         let relationships: Vec<GraphRelationship> = self
             .relations
@@ -164,29 +159,74 @@ impl TryInto<GraphData> for Neo4jGraph {
 }
 
 impl Neo4jGraph {
+    pub fn into_search_result(self) -> Result<GraphSearchResult, Error> {
+        let mut scored: Vec<(Option<f32>, GraphNode)> = self
+            .nodes
+            .iter()
+            .map(|(id, node)| (self.scores.get(id).copied(), node.clone().into()))
+            .collect();
+        scored.sort_by(|(a, a_node), (b, b_node)| {
+            b.unwrap_or(f32::MIN)
+                .total_cmp(&a.unwrap_or(f32::MIN))
+                .then(a_node.id.cmp(&b_node.id))
+        });
+
+        let graph_data: GraphData = self.try_into()?;
+
+        Ok(GraphSearchResult {
+            nodes: scored
+                .into_iter()
+                .map(|(score, node)| ScoredGraphNode { node, score })
+                .collect(),
+            relationships: graph_data.relationships,
+        })
+    }
+
     pub fn to_context(&self) -> Result<String, anyhow::Error> {
-        let mut context = String::new();
+        let context = self
+            .to_scored_lines()?
+            .into_iter()
+            .map(|(line, _)| format!("{}\n", line))
+            .collect();
+
+        Ok(context)
+    }
+
+    // One line per relationship, scored with the best similarity of its two
+    // endpoints. Unscored graphs give every line a score of 0.
+    pub fn to_scored_lines(&self) -> Result<Vec<(String, f32)>, anyhow::Error> {
+        let mut lines = vec![];
 
         for rel in &self.relations {
             let relation = &rel.rel.0;
+            let src_id = rel.src_id.0 as i64;
+            let dst_id = rel.dst_id.0 as i64;
             let src_node = self
                 .nodes
-                .get(&(rel.src_id.0 as i64))
+                .get(&src_id)
                 .ok_or_else(|| anyhow::anyhow!("Source node {} not found", rel.src_id.0))?;
             let dst_node = self
                 .nodes
-                .get(&(rel.dst_id.0 as i64))
+                .get(&dst_id)
                 .ok_or_else(|| anyhow::anyhow!("Destination node {} not found", rel.dst_id.0))?;
 
-            context.push_str(&format!(
-                "{} - {} -> {}\n",
-                src_node.to_context(),
-                relation,
-                dst_node.to_context()
+            let score = [src_id, dst_id]
+                .iter()
+                .filter_map(|id| self.scores.get(id))
+                .fold(0.0_f32, |best, score| best.max(*score));
+
+            lines.push((
+                format!(
+                    "{} - {} -> {}",
+                    src_node.to_context(),
+                    relation,
+                    dst_node.to_context()
+                ),
+                score,
             ));
         }
 
-        Ok(context)
+        Ok(lines)
     }
 }
 
@@ -202,10 +242,10 @@ impl UserNode {
     }
 }
 
-impl Into<GraphNode> for UserNode {
-    fn into(self) -> GraphNode {
+impl From<UserNode> for GraphNode {
+    fn from(node: UserNode) -> Self {
         GraphNode {
-            id: self.id,
+            id: node.id,
             label: "User".to_string(),
             properties: HashMap::new(),
         }
@@ -224,12 +264,12 @@ impl Interest {
     }
 }
 
-impl Into<GraphNode> for Interest {
-    fn into(self) -> GraphNode {
+impl From<Interest> for GraphNode {
+    fn from(node: Interest) -> Self {
         GraphNode {
-            id: self.id,
+            id: node.id,
             label: "Interest".to_string(),
-            properties: HashMap::from([("name".to_string(), json!(self.name))]),
+            properties: HashMap::from([("name".to_string(), json!(node.name))]),
         }
     }
 }
@@ -239,25 +279,32 @@ pub struct Goal {
     id: String,
     description: String,
     timeframe: Option<String>,
+    progress: Option<String>,
 }
 
 impl Goal {
     pub fn to_context(&self) -> String {
-        match &self.timeframe {
+        let context = match &self.timeframe {
             Some(timeframe) => format!("Goal: {} with timeframe {}", self.description, timeframe),
             None => format!("Goal: {}", self.description),
+        };
+
+        match &self.progress {
+            Some(progress) => format!("{} ({})", context, progress),
+            None => context,
         }
     }
 }
 
-impl Into<GraphNode> for Goal {
-    fn into(self) -> GraphNode {
+impl From<Goal> for GraphNode {
+    fn from(node: Goal) -> Self {
         GraphNode {
-            id: self.id,
+            id: node.id,
             label: "Goal".to_string(),
             properties: HashMap::from([
-                ("description".to_string(), json!(self.description)),
-                ("timeframe".to_string(), json!(self.timeframe)),
+                ("description".to_string(), json!(node.description)),
+                ("timeframe".to_string(), json!(node.timeframe)),
+                ("progress".to_string(), json!(node.progress)),
             ]),
         }
     }
@@ -276,14 +323,14 @@ impl Motivation {
     }
 }
 
-impl Into<GraphNode> for Motivation {
-    fn into(self) -> GraphNode {
+impl From<Motivation> for GraphNode {
+    fn from(node: Motivation) -> Self {
         GraphNode {
-            id: self.id,
+            id: node.id,
             label: "Motivation".to_string(),
             properties: HashMap::from([
-                ("title".to_string(), json!(self.title)),
-                ("reason".to_string(), json!(self.reason)),
+                ("title".to_string(), json!(node.title)),
+                ("reason".to_string(), json!(node.reason)),
             ]),
         }
     }
@@ -305,14 +352,14 @@ impl Task {
     }
 }
 
-impl Into<GraphNode> for Task {
-    fn into(self) -> GraphNode {
+impl From<Task> for GraphNode {
+    fn from(node: Task) -> Self {
         GraphNode {
-            id: self.id,
+            id: node.id,
             label: "Task".to_string(),
             properties: HashMap::from([
-                ("action".to_string(), json!(self.action)),
-                ("status".to_string(), json!(self.status)),
+                ("action".to_string(), json!(node.action)),
+                ("status".to_string(), json!(node.status)),
             ]),
         }
     }
@@ -332,15 +379,15 @@ impl Date {
     }
 }
 
-impl Into<GraphNode> for Date {
-    fn into(self) -> GraphNode {
+impl From<Date> for GraphNode {
+    fn from(node: Date) -> Self {
         GraphNode {
-            id: self.id,
+            id: node.id,
             label: "Date".to_string(),
             properties: HashMap::from([
-                ("day".to_string(), json!(self.day)),
-                ("month".to_string(), json!(self.month)),
-                ("year".to_string(), json!(self.year)),
+                ("day".to_string(), json!(node.day)),
+                ("month".to_string(), json!(node.month)),
+                ("year".to_string(), json!(node.year)),
             ]),
         }
     }
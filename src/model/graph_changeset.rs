@@ -0,0 +1,204 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{query, query_as, types::Json, FromRow, PgConnection, Pool, Postgres};
+use uuid::Uuid;
+
+use crate::types::{ChangeSource, GraphChanges};
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct GraphChangeset {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub version: i32,
+    pub source: ChangeSource,
+    pub chat_id: Option<Uuid>,
+    pub changes: Json<GraphChanges>,
+    pub rolled_back_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl GraphChangeset {
+    // held until the transaction ends, recorded writes of one user wait for
+    // each other
+    pub async fn lock_versions(conn: &mut PgConnection, user_id: Uuid) -> Result<(), sqlx::Error> {
+        query!(
+            r#"SELECT 1 AS "locked!" FROM pg_advisory_xact_lock(hashtextextended($1::text, 0))"#,
+            user_id.to_string()
+        )
+        .fetch_one(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    // versions count up per user, callers hold lock_versions so no other
+    // write picks the same one
+    pub async fn record(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        source: ChangeSource,
+        chat_id: Option<Uuid>,
+        changes: GraphChanges,
+    ) -> Result<Self, sqlx::Error> {
+        let changeset = query_as!(
+            Self,
+            r#"
+            INSERT INTO graph_changesets (id, user_id, version, source, chat_id, changes)
+            VALUES (
+                $1,
+                $2,
+                (SELECT coalesce(max(version), 0) + 1 FROM graph_changesets WHERE user_id = $2),
+                $3,
+                $4,
+                $5
+            )
+            RETURNING id, user_id, version as "version!", source as "source: ChangeSource", chat_id,
+                changes as "changes: Json<GraphChanges>", rolled_back_at, created_at
+            "#,
+            Uuid::new_v4(),
+            user_id,
+            source as ChangeSource,
+            chat_id,
+            Json(changes) as _
+        )
+        .fetch_one(conn)
+        .await?;
+
+        Ok(changeset)
+    }
+
+    pub async fn list_for_user(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let changesets = query_as!(
+            Self,
+            r#"
+            SELECT id, user_id, version, source as "source: ChangeSource", chat_id,
+                changes as "changes: Json<GraphChanges>", rolled_back_at, created_at
+            FROM graph_changesets
+            WHERE user_id = $1
+            ORDER BY version DESC
+            LIMIT $2
+            "#,
+            user_id,
+            limit
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(changesets)
+    }
+
+    // the changesets that lead from version `from` to version `to`, oldest
+    // first
+    pub async fn between(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        from: i32,
+        to: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let changesets = query_as!(
+            Self,
+            r#"
+            SELECT id, user_id, version, source as "source: ChangeSource", chat_id,
+                changes as "changes: Json<GraphChanges>", rolled_back_at, created_at
+            FROM graph_changesets
+            WHERE user_id = $1 AND version > $2 AND version <= $3
+            ORDER BY version ASC
+            "#,
+            user_id,
+            from,
+            to
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(changesets)
+    }
+
+    // changes that are still in effect, undone ones and their rollbacks are
+    // left out
+    pub async fn since(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let changesets = query_as!(
+            Self,
+            r#"
+            SELECT id, user_id, version, source as "source: ChangeSource", chat_id,
+                changes as "changes: Json<GraphChanges>", rolled_back_at, created_at
+            FROM graph_changesets
+            WHERE user_id = $1 AND created_at >= $2
+                AND rolled_back_at IS NULL AND source != 'rollback'
+            ORDER BY version ASC
+            "#,
+            user_id,
+            since
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(changesets)
+    }
+
+    pub async fn latest_version(pool: &Pool<Postgres>, user_id: Uuid) -> Result<i32, sqlx::Error> {
+        let version = query!(
+            r#"
+            SELECT coalesce(max(version), 0) as "version!"
+            FROM graph_changesets
+            WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await?
+        .version;
+
+        Ok(version)
+    }
+
+    // rollbacks themselves are never undone, rolling back again steps further
+    // back in history
+    pub async fn last_undoable(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let changeset = query_as!(
+            Self,
+            r#"
+            SELECT id, user_id, version, source as "source: ChangeSource", chat_id,
+                changes as "changes: Json<GraphChanges>", rolled_back_at, created_at
+            FROM graph_changesets
+            WHERE user_id = $1 AND rolled_back_at IS NULL AND source != 'rollback'
+            ORDER BY version DESC
+            LIMIT 1
+            "#,
+            user_id
+        )
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(changeset)
+    }
+
+    pub async fn mark_rolled_back(
+        conn: &mut PgConnection,
+        changeset_id: Uuid,
+    ) -> Result<(), sqlx::Error> {
+        query!(
+            r#"
+            UPDATE graph_changesets
+            SET rolled_back_at = now()
+            WHERE id = $1
+            "#,
+            changeset_id
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{query, query_as, FromRow, Pool, Postgres};
+use uuid::Uuid;
+
+use crate::types::{JobKind, JobStatus};
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Job {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    pub payload: serde_json::Value,
+    pub result: Option<serde_json::Value>,
+    pub idempotency_key: Option<String>,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub last_error: Option<String>,
+    pub run_at: DateTime<Utc>,
+    pub locked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+impl Job {
+    // Enqueuing is idempotent on (kind, idempotency_key): an existing job is
+    // returned as is, unless it failed, in which case it is rescheduled.
+    pub async fn enqueue(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        kind: JobKind,
+        payload: serde_json::Value,
+        idempotency_key: Option<String>,
+    ) -> Result<Self, sqlx::Error> {
+        let job = query_as!(
+            Self,
+            r#"
+            INSERT INTO jobs (id, user_id, kind, payload, idempotency_key, max_attempts)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (kind, idempotency_key) DO UPDATE
+            SET status = 'pending', attempts = 0, last_error = NULL, run_at = now(), updated_at = now()
+            WHERE jobs.status = 'failed'
+            RETURNING id, user_id, kind as "kind: JobKind", status as "status: JobStatus", payload, result,
+                idempotency_key, attempts, max_attempts, last_error, run_at, locked_at, created_at,
+                updated_at, completed_at
+            "#,
+            Uuid::new_v4(),
+            user_id,
+            kind.clone() as JobKind,
+            payload,
+            idempotency_key,
+            kind.max_attempts()
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        if let Some(job) = job {
+            return Ok(job);
+        }
+
+        let job = query_as!(
+            Self,
+            r#"
+            SELECT id, user_id, kind as "kind: JobKind", status as "status: JobStatus", payload, result,
+                idempotency_key, attempts, max_attempts, last_error, run_at, locked_at, created_at,
+                updated_at, completed_at
+            FROM jobs
+            WHERE kind = $1 AND idempotency_key = $2
+            "#,
+            kind as JobKind,
+            idempotency_key
+        )
+        .fetch_one(pool)
+        .await?;
+
+        Ok(job)
+    }
+
+    pub async fn get(pool: &Pool<Postgres>, job_id: Uuid) -> Result<Option<Self>, sqlx::Error> {
+        let job = query_as!(
+            Self,
+            r#"
+            SELECT id, user_id, kind as "kind: JobKind", status as "status: JobStatus", payload, result,
+                idempotency_key, attempts, max_attempts, last_error, run_at, locked_at, created_at,
+                updated_at, completed_at
+            FROM jobs
+            WHERE id = $1
+            "#,
+            job_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(job)
+    }
+
+    pub async fn list_for_user(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let jobs = query_as!(
+            Self,
+            r#"
+            SELECT id, user_id, kind as "kind: JobKind", status as "status: JobStatus", payload, result,
+                idempotency_key, attempts, max_attempts, last_error, run_at, locked_at, created_at,
+                updated_at, completed_at
+            FROM jobs
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            LIMIT $2
+            "#,
+            user_id,
+            limit
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(jobs)
+    }
+
+    // Claims the next due job. Jobs left running by a crashed worker are
+    // picked up again once their lock is older than `stale_before`, as long
+    // as they have attempts left.
+    pub async fn claim_next(
+        pool: &Pool<Postgres>,
+        stale_before: DateTime<Utc>,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let job = query_as!(
+            Self,
+            r#"
+            UPDATE jobs
+            SET status = 'running', attempts = attempts + 1, locked_at = now(), updated_at = now()
+            WHERE id = (
+                SELECT id
+                FROM jobs
+                WHERE (status = 'pending' AND run_at <= now())
+                    OR (status = 'running' AND locked_at < $1 AND attempts < max_attempts)
+                ORDER BY run_at
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, user_id, kind as "kind: JobKind", status as "status: JobStatus", payload, result,
+                idempotency_key, attempts, max_attempts, last_error, run_at, locked_at, created_at,
+                updated_at, completed_at
+            "#,
+            stale_before
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(job)
+    }
+
+    // jobs abandoned by a crashed worker on their last attempt are never
+    // claimed again, they are failed instead of staying running forever
+    pub async fn fail_abandoned(
+        pool: &Pool<Postgres>,
+        stale_before: DateTime<Utc>,
+    ) -> Result<u64, sqlx::Error> {
+        let result = query!(
+            r#"
+            UPDATE jobs
+            SET status = 'failed',
+                last_error = coalesce(last_error, 'Abandoned by its worker'),
+                locked_at = NULL,
+                updated_at = now()
+            WHERE status = 'running' AND locked_at < $1 AND attempts >= max_attempts
+            "#,
+            stale_before
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn complete(
+        pool: &Pool<Postgres>,
+        job_id: Uuid,
+        result: serde_json::Value,
+    ) -> Result<(), sqlx::Error> {
+        query!(
+            r#"
+            UPDATE jobs
+            SET status = 'completed', result = $2, last_error = NULL, locked_at = NULL,
+                completed_at = now(), updated_at = now()
+            WHERE id = $1
+            "#,
+            job_id,
+            result
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // keeps the lock of a running job fresh so it isn't taken for abandoned
+    pub async fn heartbeat(pool: &Pool<Postgres>, job_id: Uuid) -> Result<(), sqlx::Error> {
+        query!(
+            r#"
+            UPDATE jobs
+            SET locked_at = now()
+            WHERE id = $1 AND status = 'running'
+            "#,
+            job_id
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // `retry_at` reschedules the job, otherwise it is marked as failed for good
+    pub async fn fail(
+        pool: &Pool<Postgres>,
+        job_id: Uuid,
+        error: String,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<(), sqlx::Error> {
+        query!(
+            r#"
+            UPDATE jobs
+            SET status = CASE WHEN $3::timestamptz IS NULL THEN 'failed'::job_status ELSE 'pending'::job_status END,
+                run_at = coalesce($3, run_at),
+                last_error = $2,
+                locked_at = NULL,
+                updated_at = now()
+            WHERE id = $1
+            "#,
+            job_id,
+            error,
+            retry_at
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
@@ -1,13 +1,17 @@
-use async_openai::{config::OpenAIConfig, Client};
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+};
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use sqlx::{prelude::FromRow, query, query_as, Pool, Postgres};
 use uuid::Uuid;
 
-use crate::utils::config::Convinience;
+use crate::utils::llm::{LlmProvider, Provider};
 
 use super::MessageEmbedding;
 
-#[derive(Debug, Clone, FromRow)]
+#[derive(Debug, Clone, FromRow, Serialize)]
 pub struct Message {
     pub id: Uuid,
     pub chat_id: Uuid,
@@ -56,14 +60,14 @@ impl Message {
 
     pub async fn new_with_embedding(
         pool: &Pool<Postgres>,
-        openai_client: &Client<OpenAIConfig>,
+        llm: &Provider,
         chat_id: Uuid,
         role: String,
         content: String,
     ) -> Result<(Self, MessageEmbedding), sqlx::Error> {
         let message = Self::new(pool, chat_id, role, content.clone()).await?;
 
-        let embedding = openai_client
+        let embedding = llm
             .get_embedding(content.clone())
             .await
             .map_err(|e| sqlx::Error::Decode(e.into()))?;
@@ -73,6 +77,21 @@ impl Message {
         Ok((message, message_embedding))
     }
 
+    pub async fn soft_delete(pool: &Pool<Postgres>, message_id: Uuid) -> Result<(), sqlx::Error> {
+        query!(
+            r#"
+            UPDATE messages
+            SET deleted_at = now()
+            WHERE id = $1
+            "#,
+            message_id
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn get_all_messages_for_chat(
         pool: &Pool<Postgres>,
         chat_id: Uuid,
@@ -92,4 +111,73 @@ impl Message {
 
         Ok(messages)
     }
+
+    pub async fn get_system_message_for_chat(
+        pool: &Pool<Postgres>,
+        chat_id: Uuid,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let message = query_as!(
+            Self,
+            r#"
+            SELECT id, chat_id, role, content, created_at, updated_at, deleted_at 
+            FROM messages 
+            WHERE chat_id = $1 AND deleted_at IS NULL AND role = 'system'
+            ORDER BY created_at ASC
+            LIMIT 1
+            "#,
+            chat_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(message)
+    }
+
+    // the ones that still exist in the user's chats, oldest first
+    pub async fn get_many_for_user(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        message_ids: &[Uuid],
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let messages = query_as!(
+            Self,
+            r#"
+            SELECT m.id, m.chat_id, m.role, m.content, m.created_at, m.updated_at, m.deleted_at
+            FROM messages m
+            JOIN chats c ON c.id = m.chat_id
+            WHERE m.id = ANY($1) AND c.user_id = $2 AND c.deleted_at IS NULL AND m.deleted_at IS NULL
+            ORDER BY m.created_at ASC
+            "#,
+            message_ids,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(messages)
+    }
+}
+
+impl TryFrom<Message> for ChatCompletionRequestMessage {
+    type Error = anyhow::Error;
+
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
+        let request_message = match message.role.as_str() {
+            "system" => ChatCompletionRequestSystemMessageArgs::default()
+                .content(message.content)
+                .build()?
+                .into(),
+            "user" => ChatCompletionRequestUserMessageArgs::default()
+                .content(message.content)
+                .build()?
+                .into(),
+            "assistant" => ChatCompletionRequestAssistantMessageArgs::default()
+                .content(message.content)
+                .build()?
+                .into(),
+            role => return Err(anyhow::anyhow!("Unsupported message role: {}", role)),
+        };
+
+        Ok(request_message)
+    }
 }
@@ -1,4 +1,6 @@
-use sqlx::{prelude::FromRow, query, Pool, Postgres};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{prelude::FromRow, query, query_as, Pool, Postgres};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, FromRow)]
@@ -9,6 +11,16 @@ pub struct MessageEmbedding {
     pub section: Option<i16>,
 }
 
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct MessageMatch {
+    pub message_id: Uuid,
+    pub chat_id: Uuid,
+    pub role: String,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+    pub similarity: f64,
+}
+
 impl MessageEmbedding {
     pub async fn new(
         pool: &Pool<Postgres>,
@@ -36,4 +48,68 @@ impl MessageEmbedding {
 
         Ok(me)
     }
+
+    // the embedded sections of what the user said in one of their chats
+    pub async fn user_messages_in_chat(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        chat_id: Uuid,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let embeddings = query_as::<_, Self>(
+            r#"
+            SELECT e.id, e.message_id, e.embedding::real[] as embedding, e.section
+            FROM message_embeddings e
+            JOIN messages m ON m.id = e.message_id
+            JOIN chats c ON c.id = m.chat_id
+            WHERE c.user_id = $1
+                AND m.chat_id = $2
+                AND m.deleted_at IS NULL
+                AND m.role = 'user'
+            "#,
+        )
+        .bind(user_id)
+        .bind(chat_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(embeddings)
+    }
+
+    // Cosine similarity search over the user's own, non deleted messages. The
+    // embedding is bound as real[] because sqlx has no pgvector type.
+    pub async fn nearest(
+        pool: &Pool<Postgres>,
+        user_id: Uuid,
+        embedding: &[f32],
+        limit: i64,
+        chat_id: Option<Uuid>,
+        min_similarity: f64,
+    ) -> Result<Vec<MessageMatch>, sqlx::Error> {
+        let matches = query_as::<_, MessageMatch>(
+            r#"
+            SELECT m.id as message_id, m.chat_id, m.role, m.content, m.created_at,
+                1 - (e.embedding <=> $1::real[]::vector) as similarity
+            FROM message_embeddings e
+            JOIN messages m ON m.id = e.message_id
+            JOIN chats c ON c.id = m.chat_id
+            WHERE c.user_id = $2
+                AND c.deleted_at IS NULL
+                AND m.deleted_at IS NULL
+                AND m.role != 'system'
+                AND ($4::uuid IS NULL OR m.chat_id = $4)
+                AND 1 - (e.embedding <=> $1::real[]::vector) >= $5
+            ORDER BY e.embedding <=> $1::real[]::vector
+            LIMIT $3
+            "#,
+        )
+        .bind(embedding)
+        .bind(user_id)
+        .bind(limit)
+        .bind(chat_id)
+        .bind(min_similarity)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(matches)
+    }
 }
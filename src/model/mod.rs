@@ -1,11 +1,17 @@
+pub mod api_key;
 pub mod chat;
 pub mod graph;
+pub mod graph_changeset;
+pub mod job;
 pub mod message;
 pub mod message_embedding;
 pub mod user;
 
+pub use api_key::*;
 pub use chat::*;
 pub use graph::*;
+pub use graph_changeset::*;
+pub use job::*;
 pub use message::*;
 pub use message_embedding::*;
 pub use user::*;
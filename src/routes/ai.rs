@@ -1,22 +1,28 @@
-use actix_web::error::ErrorInternalServerError;
-use actix_web::{post, web, Error};
-use async_openai::types::{
-    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
-    ChatCompletionResponseMessage, CreateChatCompletionRequestArgs,
-};
-use chrono::Local;
-use tracing::info;
-use uuid::Uuid;
-
-use crate::utils::config::{Convinience, Parsable};
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use actix_web::web::Bytes;
+use actix_web::{post, web, Error, HttpResponse};
+use async_openai::types::ChatCompletionResponseMessage;
+use futures::StreamExt;
+use serde_json::json;
+use tokio::sync::mpsc;
+use tracing::error;
+
+use crate::utils::chat::{abort_chat_turn, finish_chat_turn, prepare_chat_messages};
+use crate::utils::{config::Parsable, llm::LlmProvider};
 use crate::{
     middleware::auth::AuthenticatedUser,
-    model::{Chat, Message},
-    types::{ChatPrompts, SendMessageRequest},
-    utils::graph::create_knowledge_from_chat,
+    model::{Chat, Job, Message, MessageEmbedding, MessageMatch},
+    types::{
+        CreateKnowledgeGraphRequest, ExtractKnowledgePayload, GraphSchema, GraphSearchResult,
+        JobKind, KnowledgeGraphJobs, SearchGraphRequest, SearchMessagesRequest, SendMessageRequest,
+        StreamEvent,
+    },
+    utils::error::ApiError,
     AppState,
 };
 
+const MAX_EXTRACTION_CHATS: usize = 50;
+
 #[post("/send-message")]
 async fn send_message(
     app_state: web::Data<AppState>,
@@ -24,206 +30,281 @@ async fn send_message(
     user: AuthenticatedUser,
 ) -> Result<web::Json<ChatCompletionResponseMessage>, Error> {
     let body = req_body.into_inner();
-    let chat_id = body.chat_id.clone();
-    let model = body.model.clone();
-    let flavour = body.flavour.clone();
-
-    let last_message = body.messages.last().cloned();
-
-    let chat_sys_prompt = match flavour {
-        ChatPrompts::InitialGoals => ChatPrompts::InitialGoals.prompt_template(),
-        ChatPrompts::DailyOutline => {
-            let (embedding_content, threshold) = match &last_message {
-                Some(message) => {
-                    let (_, content) = app_state
-                        .openai_client
-                        .get_data_from_message_request(message.clone())
-                        .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
-
-                    (content, 0.4)
-                }
-                None => (String::from(""), 0.0),
-            };
-
-            let embedding = app_state
-                .openai_client
-                .get_embedding(embedding_content)
-                .await
-                .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
-
-            let context = app_state
-                .graph
-                .semantic_search(&user.user_id, embedding, threshold)
-                .await
-                .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?
-                .to_context()
-                .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
-
-            &ChatPrompts::DailyOutline
-                .prompt_template()
-                .replace("{date}", &Local::now().format("%B %d, %Y").to_string())
-                .replace("{context}", &context)
+
+    let prepared = prepare_chat_messages(&app_state, &user.user_id, &body)
+        .await
+        .map_err(ApiError::from)?;
+
+    let response = app_state
+        .llm
+        .chat(&body.model, prepared.messages)
+        .await
+        .and_then(|message| match message.content.clone() {
+            Some(content) => Ok((message, content)),
+            None => Err(anyhow::anyhow!("No content in AI response")),
+        });
+    let (response_message, response_content) = match response {
+        Ok(response) => response,
+        Err(e) => {
+            // nothing was generated, so the user's message is dropped again
+            if let Err(store_error) =
+                abort_chat_turn(&app_state, body.chat_id, prepared.message_id, String::new()).await
+            {
+                error!(
+                    "Failed to store aborted turn of chat {}: {}",
+                    body.chat_id, store_error
+                );
+            }
+
+            return Err(ErrorInternalServerError(e.to_string()));
         }
     };
 
-    info!("sys prompt: {}", chat_sys_prompt);
+    finish_chat_turn(
+        app_state.into_inner(),
+        user.user_id,
+        body.chat_id,
+        prepared.flavour,
+        response_content,
+    )
+    .await
+    .map_err(|e| ErrorInternalServerError(e.to_string()))?;
 
-    let mut messages: Vec<ChatCompletionRequestMessage> =
-        vec![ChatCompletionRequestMessage::System(
-            ChatCompletionRequestSystemMessageArgs::default()
-                .content(chat_sys_prompt)
-                .build()
-                .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?,
-        )];
+    Ok(web::Json(response_message))
+}
 
-    messages.extend(body.messages.clone().iter().cloned());
+#[post("/send-message-stream")]
+async fn send_message_stream(
+    app_state: web::Data<AppState>,
+    req_body: web::Json<SendMessageRequest>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, Error> {
+    let body = req_body.into_inner();
 
-    let existing_chat = Chat::get(&app_state.pool, chat_id.clone())
+    let prepared = prepare_chat_messages(&app_state, &user.user_id, &body)
         .await
-        .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
-
-    match existing_chat {
-        None => {
-            Chat::new(
-                &app_state.pool,
-                Some(chat_id.clone()),
-                user.user_id.clone(),
-                flavour.clone(),
-            )
-            .await
-            .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
-
-            Message::new(
-                &app_state.pool,
-                chat_id.clone(),
-                String::from("system"),
-                chat_sys_prompt.to_string(),
-            )
-            .await
-            .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
-        }
-        _ => {}
-    };
+        .map_err(ApiError::from)?;
 
-    match &last_message {
-        Some(message) => {
-            let (role, content) = app_state
-                .openai_client
-                .get_data_from_message_request(message.clone())
-                .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
-
-            Message::new_with_embedding(
-                &app_state.pool,
-                &app_state.openai_client,
-                chat_id.clone(),
-                role,
-                content,
-            )
-            .await
-            .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
-        }
-        None => {}
-    }
+    let mut stream = app_state
+        .llm
+        .chat_stream(&body.model, prepared.messages)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
 
-    let client = app_state.openai_client.clone();
+    // the completion is drained in its own task so the assistant message still
+    // gets persisted if the client disconnects mid-stream
+    let (tx, rx) = mpsc::unbounded_channel::<StreamEvent>();
+    let app_state = app_state.into_inner();
 
-    let request = CreateChatCompletionRequestArgs::default()
-        .model(model)
-        .messages(messages)
-        .build()
-        .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
+    tokio::spawn(async move {
+        let mut response_content = String::new();
 
-    let response = client
-        .chat()
-        .create(request)
-        .await
-        .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
-
-    let response_message = response.choices[0].message.clone();
-    let response_content =
-        response_message
-            .clone()
-            .content
-            .ok_or(Error::from(ErrorInternalServerError(String::from(
-                "No content in AI response",
-            ))))?;
-
-    let final_message: Option<String> =
-        regex::Regex::new(r"<final_message>((?s).*?)</final_message>")
-            .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?
-            .captures(&response_content)
-            .map(|cap| Some(cap.get(1).unwrap().as_str().to_string()))
-            .unwrap_or(None);
-
-    if let Some(_) = final_message {
-        match flavour {
-            ChatPrompts::InitialGoals => {
-                info!("Spawning thread to create knowledge graph.");
-
-                let chat_id = chat_id.clone();
-                let app_state = app_state.clone();
-
-                tokio::spawn(async move {
-                    let _ = create_knowledge_from_chat(
-                        app_state.into_inner(),
-                        user.user_id.clone(),
-                        chat_id,
-                    );
-                });
+        while let Some(delta) = stream.next().await {
+            match delta {
+                Ok(content) => {
+                    response_content.push_str(&content);
+                    let _ = tx.send(StreamEvent::Delta { content });
+                }
+                Err(e) => {
+                    if let Err(store_error) = abort_chat_turn(
+                        &app_state,
+                        body.chat_id,
+                        prepared.message_id,
+                        response_content,
+                    )
+                    .await
+                    {
+                        error!(
+                            "Failed to store aborted turn of chat {}: {}",
+                            body.chat_id, store_error
+                        );
+                    }
+
+                    let _ = tx.send(StreamEvent::Error {
+                        message: e.to_string(),
+                    });
+                    return;
+                }
             }
-            _ => {}
         }
-    }
-
-    Message::new_with_embedding(
-        &app_state.pool,
-        &app_state.openai_client,
-        chat_id.clone(),
-        String::from("assistant"),
-        response_content,
-    )
-    .await
-    .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
 
-    Ok(web::Json(response_message))
+        let event = match finish_chat_turn(
+            app_state,
+            user.user_id,
+            body.chat_id,
+            prepared.flavour,
+            response_content,
+        )
+        .await
+        {
+            Ok(turn) => StreamEvent::Done {
+                final_message: turn.final_message,
+                job_id: turn.job_id,
+            },
+            Err(e) => StreamEvent::Error {
+                message: e.to_string(),
+            },
+        };
+
+        let _ = tx.send(event);
+    });
+
+    let events = futures::stream::unfold(rx, |mut rx| async move {
+        let event = rx.recv().await?;
+        Some((Ok::<Bytes, Error>(Bytes::from(event.to_sse())), rx))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events))
 }
 
 #[post("/create-knowledge-graph")]
 async fn create_knowledge_graph(
     app_state: web::Data<AppState>,
+    req_body: web::Json<CreateKnowledgeGraphRequest>,
     user: AuthenticatedUser,
-) -> Result<web::Json<String>, Error> {
-    // let chat_id = Uuid::try_parse("004a7905-f15f-4ddb-be83-6958cd4a3fa8")
-    let chat_id = Uuid::try_parse("91550d27-87ca-4005-9580-03ab2ef4edf5")
-        .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
+) -> Result<web::Json<KnowledgeGraphJobs>, Error> {
+    let body = req_body.into_inner();
+
+    let chat_ids = match (body.chat_id, body.chat_ids, body.since) {
+        (Some(chat_id), None, None) => vec![chat_id],
+        (None, Some(chat_ids), None) => chat_ids,
+        (None, None, Some(since)) => Chat::ids_for_user_since(&app_state.pool, user.user_id, since)
+            .await
+            .map_err(|e| ErrorInternalServerError(e.to_string()))?,
+        _ => {
+            return Err(ErrorBadRequest(
+                "Exactly one of chat_id, chat_ids or since is required",
+            ))
+        }
+    };
+
+    if chat_ids.len() > MAX_EXTRACTION_CHATS {
+        return Err(ErrorBadRequest(format!(
+            "At most {} chats can be extracted at once",
+            MAX_EXTRACTION_CHATS
+        )));
+    }
 
-    let schema = create_knowledge_from_chat(app_state.into_inner(), user.user_id.clone(), chat_id)
+    // ownership is checked for every chat before anything is enqueued
+    for chat_id in &chat_ids {
+        Chat::get_for_user(&app_state.pool, *chat_id, user.user_id)
+            .await?
+            .check_active()?;
+    }
+
+    let mut jobs = vec![];
+    let mut skipped_chat_ids = vec![];
+    for chat_id in chat_ids {
+        let last_message = Message::get_all_messages_for_chat(&app_state.pool, chat_id)
+            .await
+            .map_err(|e| ErrorInternalServerError(e.to_string()))?
+            .pop();
+
+        let Some(last_message) = last_message else {
+            skipped_chat_ids.push(chat_id);
+            continue;
+        };
+
+        let payload = ExtractKnowledgePayload { chat_id };
+        let job = Job::enqueue(
+            &app_state.pool,
+            user.user_id,
+            JobKind::ExtractKnowledge,
+            json!(payload),
+            Some(payload.idempotency_key(last_message.id)),
+        )
         .await
-        .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+        jobs.push(job);
+    }
 
-    Ok(web::Json(schema))
+    Ok(web::Json(KnowledgeGraphJobs {
+        jobs,
+        skipped_chat_ids,
+    }))
 }
 
 #[post("/search-graph")]
 async fn search_knowledge_graph(
     app_state: web::Data<AppState>,
+    req_body: web::Json<SearchGraphRequest>,
     user: AuthenticatedUser,
-    // req_body: web::Json<SearchGraphRequest>
-) -> Result<web::Json<String>, Error> {
+) -> Result<web::Json<GraphSearchResult>, Error> {
+    let body = req_body.into_inner();
+
+    if body.query.trim().is_empty() {
+        return Err(ErrorBadRequest("Search query must not be empty"));
+    }
+
+    let schema = GraphSchema::get();
+    if let Some(label) = body
+        .labels
+        .iter()
+        .find(|label| schema.node_type(label).is_none())
+    {
+        return Err(ErrorBadRequest(format!(
+            "Label {} is not part of the graph schema",
+            label
+        )));
+    }
+
     let embedding = app_state
-        .openai_client
-        .get_embedding(String::from("What are my goals?"))
+        .llm
+        .get_embedding(body.query.clone())
         .await
-        .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
 
     let graph = app_state
         .graph
-        .semantic_search(&user.user_id, embedding, 0.3)
+        .semantic_search(
+            &user.user_id,
+            embedding,
+            body.threshold(),
+            body.top_k(),
+            &body.labels,
+        )
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    let result = graph
+        .into_search_result()
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    Ok(web::Json(result))
+}
+
+#[post("/search-messages")]
+async fn search_messages(
+    app_state: web::Data<AppState>,
+    req_body: web::Json<SearchMessagesRequest>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Vec<MessageMatch>>, Error> {
+    let body = req_body.into_inner();
+
+    if body.query.trim().is_empty() {
+        return Err(ErrorBadRequest("Search query must not be empty"));
+    }
+
+    let embedding = app_state
+        .llm
+        .get_embedding(body.query.clone())
         .await
-        .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    let matches = MessageEmbedding::nearest(
+        &app_state.pool,
+        user.user_id,
+        &embedding,
+        body.limit(),
+        body.chat_id,
+        body.min_similarity(),
+    )
+    .await
+    .map_err(|e| ErrorInternalServerError(e.to_string()))?;
 
-    let context = graph
-        .to_context()
-        .map_err(|e| Error::from(ErrorInternalServerError(e.to_string())))?;
-    Ok(web::Json(context))
+    Ok(web::Json(matches))
 }
@@ -0,0 +1,49 @@
+use actix_web::error::{ErrorInternalServerError, ErrorNotFound};
+use actix_web::{delete, get, post, web, Error};
+use uuid::Uuid;
+
+use crate::{
+    middleware::auth::AuthenticatedUser,
+    model::ApiKey,
+    types::{CreateApiKeyRequest, CreatedApiKey},
+    AppState,
+};
+
+#[post("")]
+async fn create_api_key(
+    app_state: web::Data<AppState>,
+    req_body: web::Json<CreateApiKeyRequest>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<CreatedApiKey>, Error> {
+    let (api_key, secret) = ApiKey::new(&app_state.pool, user.user_id, req_body.into_inner().name)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    Ok(web::Json(CreatedApiKey { api_key, secret }))
+}
+
+#[get("")]
+async fn list_api_keys(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Vec<ApiKey>>, Error> {
+    let api_keys = ApiKey::list_for_user(&app_state.pool, user.user_id)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    Ok(web::Json(api_keys))
+}
+
+#[delete("/{api_key_id}")]
+async fn revoke_api_key(
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<ApiKey>, Error> {
+    let api_key = ApiKey::revoke(&app_state.pool, path.into_inner(), user.user_id)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?
+        .ok_or(ErrorNotFound("API key not found"))?;
+
+    Ok(web::Json(api_key))
+}
@@ -0,0 +1,124 @@
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound};
+use actix_web::{delete, get, patch, post, web, Error};
+use uuid::Uuid;
+
+use crate::{
+    middleware::auth::AuthenticatedUser,
+    model::{Chat, Message},
+    types::{ChatList, ChatWithMessages, ListChatsQuery, UpdateChatRequest},
+    AppState,
+};
+
+#[get("")]
+async fn list_chats(
+    app_state: web::Data<AppState>,
+    query: web::Query<ListChatsQuery>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<ChatList>, Error> {
+    let filter = query.into_inner();
+
+    let chats = Chat::list_for_user(&app_state.pool, user.user_id, &filter)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    Ok(web::Json(ChatList {
+        chats,
+        limit: filter.limit(),
+        offset: filter.offset(),
+    }))
+}
+
+#[get("/{chat_id}")]
+async fn get_chat(
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<ChatWithMessages>, Error> {
+    let chat_id = path.into_inner();
+
+    let chat = Chat::get_for_user(&app_state.pool, chat_id, user.user_id).await?;
+    chat.check_active()?;
+
+    let messages = Message::get_all_messages_for_chat(&app_state.pool, chat_id)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    Ok(web::Json(ChatWithMessages { chat, messages }))
+}
+
+#[patch("/{chat_id}")]
+async fn update_chat(
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    req_body: web::Json<UpdateChatRequest>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Chat>, Error> {
+    let chat_id = path.into_inner();
+    let body = req_body.into_inner();
+
+    if body.title.is_none() && body.archived.is_none() {
+        return Err(ErrorBadRequest("Nothing to update"));
+    }
+
+    Chat::get_for_user(&app_state.pool, chat_id, user.user_id)
+        .await?
+        .check_active()?;
+
+    let mut chat = None;
+
+    if let Some(title) = body.title {
+        let title = Some(title.trim().to_string()).filter(|t| !t.is_empty());
+
+        chat = Chat::set_title(&app_state.pool, chat_id, user.user_id, title)
+            .await
+            .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+    }
+
+    if let Some(archived) = body.archived {
+        chat = Chat::set_archived(&app_state.pool, chat_id, user.user_id, archived)
+            .await
+            .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+    }
+
+    let chat = chat.ok_or(ErrorNotFound("Chat not found"))?;
+
+    Ok(web::Json(chat))
+}
+
+#[delete("/{chat_id}")]
+async fn delete_chat(
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Chat>, Error> {
+    let chat_id = path.into_inner();
+
+    Chat::get_for_user(&app_state.pool, chat_id, user.user_id)
+        .await?
+        .check_active()?;
+
+    let chat = Chat::soft_delete(&app_state.pool, chat_id, user.user_id)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?
+        .ok_or(ErrorNotFound("Chat not found"))?;
+
+    Ok(web::Json(chat))
+}
+
+#[post("/{chat_id}/restore")]
+async fn restore_chat(
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Chat>, Error> {
+    let chat_id = path.into_inner();
+
+    Chat::get_for_user(&app_state.pool, chat_id, user.user_id).await?;
+
+    let chat = Chat::restore(&app_state.pool, chat_id, user.user_id)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?
+        .ok_or(ErrorNotFound("Deleted chat not found"))?;
+
+    Ok(web::Json(chat))
+}
@@ -0,0 +1,293 @@
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use actix_web::{delete, get, patch, post, web, Error, HttpResponse};
+use serde_json::json;
+
+use crate::{
+    middleware::auth::AuthenticatedUser,
+    model::{GraphChangeset, Job},
+    types::{
+        CreateNodeRequest, DedupGraphPayload, DedupGraphRequest, ExportQuery, GraphChanges,
+        GraphData, GraphNode, GraphRelationship, ImportGraphRequest, ImportSummary, JobKind,
+        ProvenanceExplanation, TraversalQuery, UpdateNodeRequest, VersionDiffQuery,
+    },
+    utils::{
+        config::Parsable, constants::DEDUP_LABELS, error::ApiError, export::export_graph, graph,
+        history, import::import_graph, merge::MATCH_THRESHOLD, provenance,
+    },
+    AppState,
+};
+
+#[get("")]
+async fn get_graph(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<GraphData>, Error> {
+    let graph: GraphData = app_state
+        .graph
+        .get_full_graph(&user.user_id)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?
+        .try_into()
+        .map_err(|e: neo4rs::Error| ErrorInternalServerError(e.to_string()))?;
+
+    Ok(web::Json(graph))
+}
+
+#[get("/export")]
+async fn export_graph_file(
+    app_state: web::Data<AppState>,
+    query: web::Query<ExportQuery>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, Error> {
+    let format = query.into_inner().format;
+
+    let graph = graph::get_owned_graph(&app_state, &user.user_id)
+        .await
+        .map_err(ApiError::from)?;
+    let export = export_graph(&graph, &user.user_id, format)
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"graph-{}.{}\"",
+                chrono::Utc::now().format("%Y-%m-%d"),
+                format.extension()
+            ),
+        ))
+        .body(export))
+}
+
+#[post("/import")]
+async fn import_graph_data(
+    app_state: web::Data<AppState>,
+    req_body: web::Json<ImportGraphRequest>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<ImportSummary>, Error> {
+    let summary = import_graph(&app_state, &user.user_id, req_body.into_inner())
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(web::Json(summary))
+}
+
+#[post("/nodes")]
+async fn create_node(
+    app_state: web::Data<AppState>,
+    req_body: web::Json<CreateNodeRequest>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<GraphNode>, Error> {
+    let node = graph::create_node(&app_state, &user.user_id, req_body.into_inner())
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(web::Json(node))
+}
+
+#[patch("/nodes/{node_id}")]
+async fn update_node(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    req_body: web::Json<UpdateNodeRequest>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<GraphNode>, Error> {
+    let node = graph::update_node(
+        &app_state,
+        &user.user_id,
+        &path.into_inner(),
+        req_body.into_inner(),
+    )
+    .await
+    .map_err(ApiError::from)?;
+
+    Ok(web::Json(node))
+}
+
+#[delete("/nodes/{node_id}")]
+async fn delete_node(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<GraphNode>, Error> {
+    let node = graph::delete_node(&app_state, &user.user_id, &path.into_inner())
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(web::Json(node))
+}
+
+#[get("/nodes/{node_id}/subgraph")]
+async fn get_subgraph(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<TraversalQuery>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<GraphData>, Error> {
+    let graph = graph::get_subgraph(
+        &app_state,
+        &user.user_id,
+        &path.into_inner(),
+        query.into_inner(),
+    )
+    .await
+    .map_err(ApiError::from)?;
+
+    Ok(web::Json(graph))
+}
+
+#[get("/nodes/{node_id}/provenance")]
+async fn get_node_provenance(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<ProvenanceExplanation>, Error> {
+    let explanation = provenance::explain_node(&app_state, &user.user_id, &path.into_inner())
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(web::Json(explanation))
+}
+
+#[post("/relationships")]
+async fn create_relationship(
+    app_state: web::Data<AppState>,
+    req_body: web::Json<GraphRelationship>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<GraphRelationship>, Error> {
+    let relationship = graph::create_relationship(&app_state, &user.user_id, req_body.into_inner())
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(web::Json(relationship))
+}
+
+#[delete("/relationships/{source_id}/{label}/{target_id}")]
+async fn delete_relationship(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String, String)>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<GraphRelationship>, Error> {
+    let (source_id, label, target_id) = path.into_inner();
+
+    let relationship = graph::delete_relationship(
+        &app_state,
+        &user.user_id,
+        GraphRelationship {
+            source_id,
+            target_id,
+            label,
+        },
+    )
+    .await
+    .map_err(ApiError::from)?;
+
+    Ok(web::Json(relationship))
+}
+
+#[get("/relationships/{source_id}/{label}/{target_id}/provenance")]
+async fn get_relationship_provenance(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String, String, String)>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<ProvenanceExplanation>, Error> {
+    let (source_id, label, target_id) = path.into_inner();
+
+    let explanation = provenance::explain_relationship(
+        &app_state,
+        &user.user_id,
+        &GraphRelationship {
+            source_id,
+            target_id,
+            label,
+        },
+    )
+    .await
+    .map_err(ApiError::from)?;
+
+    Ok(web::Json(explanation))
+}
+
+// the merge itself runs as a job, with dry_run its result lists the merges
+// that would be made without touching the graph
+#[post("/dedup")]
+async fn dedup_graph(
+    app_state: web::Data<AppState>,
+    req_body: web::Json<DedupGraphRequest>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Job>, Error> {
+    let body = req_body.into_inner();
+
+    let labels = body
+        .labels
+        .unwrap_or_else(|| DEDUP_LABELS.iter().map(|l| l.to_string()).collect());
+    if let Some(label) = labels.iter().find(|l| !DEDUP_LABELS.contains(&l.as_str())) {
+        return Err(ErrorBadRequest(format!(
+            "{} nodes can't be deduplicated, expected one of {}",
+            label,
+            DEDUP_LABELS.join(", ")
+        )));
+    }
+
+    let threshold = body.threshold.unwrap_or(MATCH_THRESHOLD);
+    if !(0.5..=1.0).contains(&threshold) {
+        return Err(ErrorBadRequest("threshold must be between 0.5 and 1"));
+    }
+
+    let payload = DedupGraphPayload {
+        dry_run: body.dry_run,
+        labels,
+        threshold,
+    };
+    let job = Job::enqueue(
+        &app_state.pool,
+        user.user_id,
+        JobKind::DedupGraph,
+        json!(payload),
+        None,
+    )
+    .await
+    .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    Ok(web::Json(job))
+}
+
+#[get("/versions")]
+async fn list_versions(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Vec<GraphChangeset>>, Error> {
+    let changesets = GraphChangeset::list_for_user(&app_state.pool, user.user_id, 50)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    Ok(web::Json(changesets))
+}
+
+#[get("/versions/diff")]
+async fn diff_versions(
+    app_state: web::Data<AppState>,
+    query: web::Query<VersionDiffQuery>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<GraphChanges>, Error> {
+    let changes = history::diff_versions(&app_state, &user.user_id, query.from, query.to)
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(web::Json(changes))
+}
+
+// null when undoing the last change left the graph as it was, e.g. because
+// it had already been edited back by hand
+#[post("/versions/rollback")]
+async fn rollback_version(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Option<GraphChangeset>>, Error> {
+    let changeset = history::rollback_last(&app_state, &user.user_id)
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(web::Json(changeset))
+}
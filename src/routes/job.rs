@@ -0,0 +1,35 @@
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorNotFound};
+use actix_web::{get, web, Error};
+use uuid::Uuid;
+
+use crate::{middleware::auth::AuthenticatedUser, model::Job, AppState};
+
+#[get("")]
+async fn list_jobs(
+    app_state: web::Data<AppState>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Vec<Job>>, Error> {
+    let jobs = Job::list_for_user(&app_state.pool, user.user_id, 50)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?;
+
+    Ok(web::Json(jobs))
+}
+
+#[get("/{job_id}")]
+async fn get_job(
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Job>, Error> {
+    let job = Job::get(&app_state.pool, path.into_inner())
+        .await
+        .map_err(|e| ErrorInternalServerError(e.to_string()))?
+        .ok_or(ErrorNotFound("Job not found"))?;
+
+    if job.user_id != user.user_id {
+        return Err(ErrorForbidden("Job belongs to another user"));
+    }
+
+    Ok(web::Json(job))
+}
@@ -1,2 +1,7 @@
 pub mod ai;
+pub mod api_key;
+pub mod chat;
+pub mod graph;
 pub mod hello;
+pub mod job;
+pub mod task;
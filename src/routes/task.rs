@@ -0,0 +1,42 @@
+use actix_web::{get, patch, web, Error};
+
+use crate::{
+    middleware::auth::AuthenticatedUser,
+    types::{ListTasksQuery, TaskItem, UpdateTaskStatusRequest},
+    utils::{error::ApiError, tasks},
+    AppState,
+};
+
+#[get("")]
+async fn list_tasks(
+    app_state: web::Data<AppState>,
+    query: web::Query<ListTasksQuery>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Vec<TaskItem>>, Error> {
+    let date = query.into_inner().date.unwrap_or_else(tasks::today);
+
+    let tasks = tasks::list_tasks(&app_state, &user.user_id, date)
+        .await
+        .map_err(ApiError::from)?;
+
+    Ok(web::Json(tasks))
+}
+
+#[patch("/{task_id}/status")]
+async fn update_task_status(
+    app_state: web::Data<AppState>,
+    path: web::Path<String>,
+    req_body: web::Json<UpdateTaskStatusRequest>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<TaskItem>, Error> {
+    let task = tasks::transition_task(
+        &app_state,
+        &user.user_id,
+        &path.into_inner(),
+        req_body.into_inner().status,
+    )
+    .await
+    .map_err(ApiError::from)?;
+
+    Ok(web::Json(task))
+}
@@ -2,6 +2,8 @@ use async_openai::types::ChatCompletionRequestMessage;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::utils::constants::SEARCH_TOP_K;
+
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::Type)]
 #[serde(rename_all = "lowercase")]
 #[sqlx(type_name = "chat_prompt", rename_all = "lowercase")]
@@ -12,6 +14,9 @@ pub enum ChatPrompts {
     #[serde(rename = "daily_outline")]
     #[sqlx(rename = "daily_outline")]
     DailyOutline,
+    #[serde(rename = "weekly_review")]
+    #[sqlx(rename = "weekly_review")]
+    WeeklyReview,
 }
 
 impl ChatPrompts {
@@ -29,13 +34,38 @@ impl ChatPrompts {
                 "<context>\n",
                 "{context}\n",
                 "</context>\n",
+                "<task_history>\n",
+                "{task_history}\n",
+                "</task_history>\n",
                 "Today is {date}.\n",
                 "Your name is Buddy. You are an AI companion that helps the user plan their day.\n",
                 "The context provided to you above in <context></context> tags contains inforamation about the user gathered from pervious interactions. Use it as background information as you engage with the user.\n",
+                "The user's open tasks and what they recently completed or failed are listed in <task_history></task_history> tags. Follow up on unfinished tasks and take their progress into account when suggesting what to do today.\n",
                 "Your goal is to help the user create a clear plan of action for the day. Use what you know about the user to ask informed questions and make helpful suggestions.\n",
                 "Your responses should be concise. Make inquiries/suggestions one at a time. Try to get them to elaborate on their answers, but do not overwhelm them.\n",
                 "Once you suspect the user is ready to wrap up, ask them if they are ready to get to work. If they say yes, wrap your final message in <final_message></final_message> tags to indicate the end of the chat."
             ),
+            ChatPrompts::WeeklyReview => concat!(
+                "<context>\n",
+                "{context}\n",
+                "</context>\n",
+                "<goals>\n",
+                "{goals}\n",
+                "</goals>\n",
+                "<task_changes>\n",
+                "{task_changes}\n",
+                "</task_changes>\n",
+                "<daily_outlines>\n",
+                "{daily_outlines}\n",
+                "</daily_outlines>\n",
+                "Today is {date}.\n",
+                "Your name is Buddy. You are an AI companion that helps the user look back on their week and check in on their goals.\n",
+                "The context provided to you above in <context></context> tags contains information about the user gathered from previous interactions. Use it as background information as you engage with the user.\n",
+                "The user's goals and how they were progressing so far are listed in <goals></goals> tags. Every task the user started, completed or failed over the past seven days is listed in <task_changes></task_changes> tags, and the plans they made for each day are listed in <daily_outlines></daily_outlines> tags.\n",
+                "Your goal is to help the user reflect on what went well and what did not, and to find out how each of their goals is progressing. Point out where what they planned and what they did drifted apart, and ask about it without judgement.\n",
+                "Your responses should be concise. Go through the week one topic at a time. Try to get them to elaborate on their answers, but do not overwhelm them.\n",
+                "Once every goal has been discussed, summarize how each of them is progressing. If the user agrees with your summary, wrap your final message in <final_message></final_message> tags to indicate the end of the chat."
+            ),
         }
     }
 }
@@ -50,9 +80,15 @@ pub enum ToolPrompts {
     #[serde(rename = "extract_entities")]
     #[sqlx(rename = "extract_entities")]
     ExtractEntities,
-    #[serde(rename = "merge_graph")]
-    #[sqlx(rename = "merge_graph")]
-    MergeGraph,
+    #[serde(rename = "resolve_matches")]
+    #[sqlx(rename = "resolve_matches")]
+    ResolveMatches,
+    #[serde(rename = "validation_retry")]
+    #[sqlx(rename = "validation_retry")]
+    ValidationRetry,
+    #[serde(rename = "review_goals")]
+    #[sqlx(rename = "review_goals")]
+    ReviewGoals,
 }
 
 impl ToolPrompts {
@@ -90,22 +126,40 @@ impl ToolPrompts {
                 "You will output a JSON object that conforms to the JSON schema in <graph_data></graph_data> tags.\n",
                 "Your output must be a valid JSON object!"
             ),
-            ToolPrompts::MergeGraph => concat!(
-                "<graph_schema>\n",
-                "{graph_schema}\n",
-                "</graph_schema>\n",
-                "<existing_graph>\n",
-                "{existing_graph}\n",
-                "</existing_graph>\n",
-                "<new_graph>\n",
-                "{new_graph}\n",
-                "</new_graph>\n",
-                "Today is {date}.\n",
-                "Your name is Buddy. You are an expert at merging data from two knowledge graphs into a single graph.\n",
-                "You have been provided with two knowledge graphs for the same user. The existing knowledge graph can be found in <existing_graph></existing_graph> tags. The new knowledge graph can be found in <new_graph></new_graph> tags.\n",
-                "Both graphs adhere to the schema provided to you above in <graph_schema></graph_schema> tags. This schema defines the entities and relationships that are used to represent data in the knowledge graph.\n",
-                "Your task is to extend the existing graph with data from the new graph. You will output a JSON object containing a list of nodes and a list of relationships that will be added to the existing graph. You should only output data that is not already included in the existing graph.\n",
-                "Your response must be a valid JSON object that follows the same format as the provided graphs!"
+            ToolPrompts::ResolveMatches => concat!(
+                "<candidate_pairs>\n",
+                "{candidate_pairs}\n",
+                "</candidate_pairs>\n",
+                "Your name is Buddy. You are an expert at deduplicating entities in a user's knowledge graph.\n",
+                "Each entry in <candidate_pairs></candidate_pairs> tags pairs a newly extracted node with a similar node that already exists in the user's knowledge graph.\n",
+                "For every pair, decide whether both nodes describe the same thing. Only consider them the same if the existing node could stand in for the new one without losing any information.\n",
+                "You will output a JSON object of the form {\"decisions\": [{\"pair\": <pair number>, \"same\": <true or false>}]} with one decision per pair.\n",
+                "Your response must be a valid JSON object!"
+            ),
+            ToolPrompts::ValidationRetry => concat!(
+                "<previous_response>\n",
+                "{previous_response}\n",
+                "</previous_response>\n",
+                "<validation_errors>\n",
+                "{validation_errors}\n",
+                "</validation_errors>\n",
+                "Your previous response, provided above in <previous_response></previous_response> tags, does not conform to the graph schema.\n",
+                "The problems that were found are listed in <validation_errors></validation_errors> tags.\n",
+                "Fix every listed problem and output the complete, corrected JSON object. Do not change anything that was not listed as a problem.\n",
+                "Your response must be a valid JSON object!"
+            ),
+            ToolPrompts::ReviewGoals => concat!(
+                "<review>\n",
+                "{review}\n",
+                "</review>\n",
+                "<goals>\n",
+                "{goals}\n",
+                "</goals>\n",
+                "Your name is Buddy. You have just finished a weekly review with the user. The full transcript is provided to you above in <review></review> tags.\n",
+                "The user's goals are listed in <goals></goals> tags as a JSON array, each with its id, description and the progress recorded for it so far.\n",
+                "Your task is to decide the progress of every goal that was discussed in the review. Progress must be one of \"not-started\", \"on-track\", \"off-track\" or \"achieved\". Leave out goals that were not discussed.\n",
+                "You will output a JSON object of the form {\"goals\": [{\"id\": <goal id>, \"progress\": <progress>}]}.\n",
+                "Your response must be a valid JSON object!"
             ),
         }
     }
@@ -115,6 +169,72 @@ impl ToolPrompts {
 pub struct SendMessageRequest {
     pub chat_id: Uuid,
     pub model: String,
+    #[serde(default)]
     pub messages: Vec<ChatCompletionRequestMessage>,
+    // when set, the server rebuilds the conversation from stored history and
+    // `messages` is ignored
+    #[serde(default)]
+    pub message: Option<String>,
     pub flavour: ChatPrompts,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct SearchGraphRequest {
+    pub query: String,
+    pub threshold: Option<f32>,
+    pub top_k: Option<usize>,
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+impl SearchGraphRequest {
+    pub fn threshold(&self) -> f32 {
+        self.threshold.unwrap_or(0.3)
+    }
+
+    pub fn top_k(&self) -> usize {
+        self.top_k.unwrap_or(SEARCH_TOP_K).clamp(1, 50)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchMessagesRequest {
+    pub query: String,
+    pub limit: Option<i64>,
+    pub chat_id: Option<Uuid>,
+    pub min_similarity: Option<f64>,
+}
+
+impl SearchMessagesRequest {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(10).clamp(1, 50)
+    }
+
+    pub fn min_similarity(&self) -> f64 {
+        self.min_similarity.unwrap_or(0.0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    Delta {
+        content: String,
+    },
+    Done {
+        final_message: bool,
+        job_id: Option<Uuid>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl StreamEvent {
+    pub fn to_sse(&self) -> String {
+        format!(
+            "data: {}\n\n",
+            serde_json::to_string(self).unwrap_or_default()
+        )
+    }
+}
@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+use crate::model::ApiKey;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    // only ever returned once, at creation time
+    pub secret: String,
+}
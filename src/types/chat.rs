@@ -0,0 +1,50 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    model::{Chat, Message},
+    types::ChatPrompts,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListChatsQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    pub flavour: Option<ChatPrompts>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub archived: bool,
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+impl ListChatsQuery {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(20).clamp(1, 100)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatList {
+    pub chats: Vec<Chat>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatWithMessages {
+    #[serde(flatten)]
+    pub chat: Chat,
+    pub messages: Vec<Message>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateChatRequest {
+    pub title: Option<String>,
+    pub archived: Option<bool>,
+}
@@ -1,14 +1,25 @@
 use std::collections::HashMap;
 
-use async_openai::{config::OpenAIConfig, Client};
+use chrono::{DateTime, Utc};
+use neo4rs::{query, BoltType, Query};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::utils::config::Convinience;
+use crate::{
+    model::{Job, Message},
+    types::GraphSchema,
+    utils::{
+        constants::EMBEDDED_LABEL,
+        llm::{LlmProvider, Provider},
+        merge::MergePlan,
+    },
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Clone)]
 pub struct CypherQueries {
-    pub queries: Vec<String>,
+    pub queries: Vec<Query>,
+    // ids from the graph data mapped to the ids the nodes are stored with
+    pub node_ids: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,6 +30,136 @@ pub struct GraphNode {
     pub properties: HashMap<String, serde_json::Value>,
 }
 
+impl GraphNode {
+    // the text a node's embedding is computed from, None for nodes that aren't
+    // embedded
+    pub fn embedding_content(&self) -> Result<Option<String>, anyhow::Error> {
+        let embedding_content = match self.label.as_str() {
+            "Interest" => {
+                let name = self
+                    .properties
+                    .get("name")
+                    .ok_or_else(|| anyhow::anyhow!("Interest name not found"))?
+                    .to_string();
+
+                Some(format!("Interest: {}", name))
+            }
+            "Goal" => {
+                let description = self
+                    .properties
+                    .get("description")
+                    .ok_or_else(|| anyhow::anyhow!("Goal description not found"))?
+                    .to_string();
+
+                Some(format!("Goal: {}", description))
+            }
+            "Motivation" => {
+                let title = self
+                    .properties
+                    .get("title")
+                    .ok_or_else(|| anyhow::anyhow!("Motivation title not found"))?
+                    .to_string();
+                let reason = self
+                    .properties
+                    .get("reason")
+                    .ok_or_else(|| anyhow::anyhow!("Motivation reason not found"))?
+                    .to_string();
+
+                Some(format!("Motivation: {} with reason {}", title, reason))
+            }
+            "Task" => {
+                let action = self
+                    .properties
+                    .get("action")
+                    .ok_or_else(|| anyhow::anyhow!("Task action not found"))?
+                    .to_string();
+
+                Some(format!("Task: {}", action))
+            }
+            "Date" => {
+                let day = self
+                    .properties
+                    .get("day")
+                    .ok_or_else(|| anyhow::anyhow!("Date day not found"))?
+                    .to_string();
+                let month = self
+                    .properties
+                    .get("month")
+                    .ok_or_else(|| anyhow::anyhow!("Date month not found"))?
+                    .to_string();
+                let year = self
+                    .properties
+                    .get("year")
+                    .ok_or_else(|| anyhow::anyhow!("Date year not found"))?
+                    .to_string();
+
+                Some(format!("Date: {} of {}, {}.", day, month, year))
+            }
+            _ => None,
+        };
+
+        Ok(embedding_content)
+    }
+
+    pub fn bolt_properties(&self) -> Result<HashMap<String, BoltType>, neo4rs::Error> {
+        self.properties
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), BoltType::try_from(v.clone())?)))
+            .collect()
+    }
+
+    fn stored_properties(
+        &self,
+        user_id: &Uuid,
+        embedding: Option<Vec<f32>>,
+    ) -> Result<(HashMap<String, BoltType>, String), neo4rs::Error> {
+        let mut props = self.bolt_properties()?;
+        props.insert("id".to_string(), self.id.clone().into());
+        props.insert("owner_id".to_string(), user_id.to_string().into());
+
+        let labels = match embedding {
+            Some(embedding) => {
+                props.insert("embedding".to_string(), embedding.into());
+                format!("{}:{}", self.label, EMBEDDED_LABEL)
+            }
+            None => self.label.clone(),
+        };
+
+        Ok((props, labels))
+    }
+
+    // creates the node under its own id, embedded nodes also get the
+    // Embedded label so semantic search finds them
+    pub fn create_query(
+        &self,
+        user_id: &Uuid,
+        embedding: Option<Vec<f32>>,
+    ) -> Result<Query, neo4rs::Error> {
+        let (props, labels) = self.stored_properties(user_id, embedding)?;
+
+        Ok(query(&format!("CREATE (n:{}) SET n = $props", labels)).param("props", props))
+    }
+
+    // like create_query, but a node that still exists under the id is
+    // overwritten instead of duplicated
+    pub fn restore_query(
+        &self,
+        user_id: &Uuid,
+        embedding: Option<Vec<f32>>,
+    ) -> Result<Query, neo4rs::Error> {
+        let (props, labels) = self.stored_properties(user_id, embedding)?;
+
+        Ok(query(&format!(
+            "MERGE {} SET n = $props, n:{}",
+            node_pattern("n", &self.label, "id"),
+            labels
+        ))
+        .param("id", self.id.clone())
+        .param("user_id", user_id.to_string())
+        .param("props", props))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GraphRelationship {
     #[serde(rename = "source")]
@@ -34,139 +175,616 @@ pub struct GraphData {
     pub relationships: Vec<GraphRelationship>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScoredGraphNode {
+    #[serde(flatten)]
+    pub node: GraphNode,
+    pub score: Option<f32>,
+}
+
+// nodes are ordered by score, neighbours pulled in by a match have no score
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphSearchResult {
+    pub nodes: Vec<ScoredGraphNode>,
+    pub relationships: Vec<GraphRelationship>,
+}
+
+// exactly one of the three ways of picking chats has to be used
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateKnowledgeGraphRequest {
+    pub chat_id: Option<Uuid>,
+    pub chat_ids: Option<Vec<Uuid>>,
+    pub since: Option<DateTime<Utc>>,
+}
+
+// each job's result is an `ExtractionSummary` once it has completed
+#[derive(Debug, Clone, Serialize)]
+pub struct KnowledgeGraphJobs {
+    pub jobs: Vec<Job>,
+    pub skipped_chat_ids: Vec<Uuid>,
+}
+
+// `node_id` can be left out when the other end of the relationship is the
+// user's own node
+#[derive(Debug, Clone, Deserialize)]
+pub struct NodeConnection {
+    pub relationship: String,
+    pub node_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateNodeRequest {
+    pub label: String,
+    #[serde(default, rename = "props")]
+    pub properties: HashMap<String, serde_json::Value>,
+    pub connect: Vec<NodeConnection>,
+}
+
+// props are merged into the node, a null removes a nullable property
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateNodeRequest {
+    #[serde(rename = "props")]
+    pub properties: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtractionSummary {
+    pub chat_id: Uuid,
+    pub nodes_extracted: usize,
+    pub nodes_added: usize,
+    pub nodes_updated: usize,
+    pub nodes_already_present: usize,
+    pub relationships_extracted: usize,
+    pub relationships_added: usize,
+    pub relationships_already_present: usize,
+    pub added: GraphData,
+    pub updated: Vec<GraphNode>,
+}
+
+// `relationships` is a comma separated list of relationship types to follow,
+// all of them are followed when it is left out
+#[derive(Debug, Clone, Deserialize)]
+pub struct TraversalQuery {
+    pub depth: Option<usize>,
+    pub relationships: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Graphml,
+    Cypher,
+    Dot,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Graphml => "application/graphml+xml",
+            ExportFormat::Cypher => "text/plain; charset=utf-8",
+            ExportFormat::Dot => "text/vnd.graphviz",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Graphml => "graphml",
+            ExportFormat::Cypher => "cypher",
+            ExportFormat::Dot => "dot",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    // matched against the existing graph the same way extractions are
+    #[default]
+    Merge,
+    // everything but the user node is removed first
+    Replace,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImportGraphRequest {
+    #[serde(default)]
+    pub mode: ImportMode,
+    pub graph: GraphData,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportSummary {
+    pub mode: ImportMode,
+    pub nodes_removed: usize,
+    pub nodes_added: usize,
+    pub nodes_updated: usize,
+    pub nodes_already_present: usize,
+    pub relationships_added: usize,
+    pub relationships_already_present: usize,
+    pub repairs: Vec<String>,
+    // imported ids mapped to the ids the nodes are stored with
+    pub node_ids: HashMap<String, String>,
+}
+
+// where a node or relationship came from, stored as properties on it. chats
+// and messages accumulate over extractions, the timestamp and model are the
+// latest ones.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Provenance {
+    pub chat_ids: Vec<String>,
+    pub message_ids: Vec<String>,
+    pub extracted_at: Option<String>,
+    pub extraction_model: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProvenanceExplanation {
+    pub provenance: Provenance,
+    // the source messages that still exist, oldest first
+    pub messages: Vec<Message>,
+}
+
+// labels default to every label that can be deduplicated and the threshold
+// to the one extractions are merged with
+#[derive(Debug, Clone, Deserialize)]
+pub struct DedupGraphRequest {
+    #[serde(default)]
+    pub dry_run: bool,
+    pub labels: Option<Vec<String>>,
+    pub threshold: Option<f32>,
+}
+
+// duplicates are scored by their similarity to the survivor, exact key
+// matches score 1
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProposedMerge {
+    pub label: String,
+    pub survivor: GraphNode,
+    pub duplicates: Vec<ScoredGraphNode>,
+    pub relationships_rewired: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DedupSummary {
+    pub dry_run: bool,
+    pub nodes_scanned: usize,
+    pub nodes_removed: usize,
+    pub relationships_rewired: usize,
+    pub merges: Vec<ProposedMerge>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GoalProgressUpdate {
+    pub goal_id: String,
+    pub description: String,
+    pub before: Option<String>,
+    pub after: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GoalReviewSummary {
+    pub chat_id: Uuid,
+    pub updates: Vec<GoalProgressUpdate>,
+}
+
+impl ExtractionSummary {
+    pub fn new(chat_id: Uuid, extracted: &GraphData, plan: &MergePlan) -> Self {
+        Self {
+            chat_id,
+            nodes_extracted: extracted.nodes.len(),
+            nodes_added: plan.add.nodes.len(),
+            nodes_updated: plan.updates.len(),
+            nodes_already_present: plan.skipped_nodes.len(),
+            relationships_extracted: extracted.relationships.len(),
+            relationships_added: plan.add.relationships.len(),
+            relationships_already_present: plan.skipped_relationships,
+            added: plan.add.clone(),
+            updated: plan
+                .updates
+                .iter()
+                .map(|update| update.node.clone())
+                .collect(),
+        }
+    }
+}
+
 impl GraphData {
     pub async fn into_queries(
         self,
         user_id: &Uuid,
-        openai_client: &Client<OpenAIConfig>,
+        llm: &Provider,
     ) -> Result<CypherQueries, anyhow::Error> {
-        let mut node_id_map: HashMap<String, String> = HashMap::new();
-        let mut node_queries: Vec<String> = vec![];
-        for mut node in self.nodes {
-            let embedding_content: Option<String> = match node.label.as_str() {
-                "Interest" => {
-                    let name = node
-                        .properties
-                        .get("name")
-                        .ok_or_else(|| anyhow::anyhow!("Interest name not found"))?
-                        .to_string();
-
-                    Some(format!("Interest: {}", name))
-                }
-                "Goal" => {
-                    let description = node
-                        .properties
-                        .get("description")
-                        .ok_or_else(|| anyhow::anyhow!("Goal description not found"))?
-                        .to_string();
-
-                    Some(format!("Goal: {}", description))
-                }
-                "Motivation" => {
-                    let title = node
-                        .properties
-                        .get("title")
-                        .ok_or_else(|| anyhow::anyhow!("Motivation title not found"))?
-                        .to_string();
-                    let reason = node
-                        .properties
-                        .get("reason")
-                        .ok_or_else(|| anyhow::anyhow!("Motivation reason not found"))?
-                        .to_string();
-
-                    Some(format!("Motivation: {} with reason {}", title, reason))
-                }
-                "Task" => {
-                    let action = node
-                        .properties
-                        .get("action")
-                        .ok_or_else(|| anyhow::anyhow!("Task action not found"))?
-                        .to_string();
-
-                    Some(format!("Task: {}", action))
-                }
-                "Date" => {
-                    let day = node
-                        .properties
-                        .get("day")
-                        .ok_or_else(|| anyhow::anyhow!("Date day not found"))?
-                        .to_string();
-                    let month = node
-                        .properties
-                        .get("month")
-                        .ok_or_else(|| anyhow::anyhow!("Date month not found"))?
-                        .to_string();
-                    let year = node
-                        .properties
-                        .get("year")
-                        .ok_or_else(|| anyhow::anyhow!("Date year not found"))?
-                        .to_string();
-
-                    Some(format!("Date: {} of {}, {}.", day, month, year))
-                }
-                _ => None,
-            };
+        self.into_queries_with(user_id, llm, HashMap::new()).await
+    }
 
-            if node.label == "User" {
-                node.properties.insert(
-                    "user_id".to_string(),
-                    serde_json::json!(user_id.to_string()),
-                );
-            }
+    // `embeddings` holds embeddings already computed for some of the nodes,
+    // keyed by node id
+    pub async fn into_queries_with(
+        self,
+        user_id: &Uuid,
+        llm: &Provider,
+        mut embeddings: HashMap<String, Vec<f32>>,
+    ) -> Result<CypherQueries, anyhow::Error> {
+        let schema = GraphSchema::get();
+
+        // labels can't be bound as parameters, so only schema labels are ever
+        // formatted into a query
+        if let Some(node) = self
+            .nodes
+            .iter()
+            .find(|node| schema.node_type(&node.label).is_none())
+        {
+            return Err(anyhow::anyhow!(
+                "Node label {} is not part of the graph schema",
+                node.label
+            ));
+        }
 
+        // the missing embeddings are requested in one go
+        let embedding_contents = self
+            .nodes
+            .iter()
+            .map(|node| node.embedding_content())
+            .collect::<Result<Vec<Option<String>>, anyhow::Error>>()?;
+        let missing = self
+            .nodes
+            .iter()
+            .zip(&embedding_contents)
+            .filter(|(node, _)| !embeddings.contains_key(&node.id))
+            .filter_map(|(node, content)| Some((node.id.clone(), content.clone()?)))
+            .collect::<Vec<(String, String)>>();
+        let computed = llm
+            .get_embeddings(missing.iter().map(|(_, content)| content.clone()).collect())
+            .await?;
+        embeddings.extend(missing.into_iter().map(|(id, _)| id).zip(computed));
+
+        let mut node_id_map: HashMap<String, String> = HashMap::new();
+        let mut node_labels: HashMap<String, String> = HashMap::new();
+        let mut node_queries: Vec<Query> = vec![];
+        for (node, embedding_content) in self.nodes.into_iter().zip(embedding_contents) {
             // Gross hack
             let new_id = Uuid::new_v4().to_string();
             node_id_map.insert(node.id.clone(), new_id.clone());
+            node_labels.insert(node.id.clone(), node.label.clone());
 
-            if let Some(embedding_content) = embedding_content {
-                let embedding = openai_client.get_embedding(embedding_content).await?;
-
-                node_queries.push(format!(
-                    "CREATE ({}:{} {{ id: \"{}\", embedding: {:?}, {} }})",
-                    node.id,
-                    node.label,
-                    new_id.clone(),
-                    embedding,
-                    node.properties
-                        .into_iter()
-                        .map(|(k, v)| format!("{}: {}", k, v))
-                        .collect::<Vec<String>>()
-                        .join(", ")
-                ));
-            } else {
-                node_queries.push(format!(
-                    "CREATE ({}:{} {{ id: \"{}\", {} }})",
-                    node.id,
-                    node.label,
-                    new_id.clone(),
-                    node.properties
-                        .into_iter()
-                        .map(|(k, v)| format!("{}: {}", k, v))
-                        .collect::<Vec<String>>()
-                        .join(", ")
-                ));
+            if node.label == "User" {
+                node_queries.push(
+                    query(
+                        "MERGE (n:User {user_id: $user_id}) ON CREATE SET n.id = $id, n.owner_id = $user_id",
+                    )
+                        .param("user_id", user_id.to_string())
+                        .param("id", new_id),
+                );
+                continue;
             }
+
+            let embedding =
+                match embedding_content {
+                    Some(_) => Some(embeddings.remove(&node.id).ok_or_else(|| {
+                        anyhow::anyhow!("Missing embedding for node {}", node.id)
+                    })?),
+                    None => None,
+                };
+
+            let node = GraphNode { id: new_id, ..node };
+            node_queries.push(node.create_query(user_id, embedding)?);
         }
 
         let rel_queries = self
             .relationships
             .into_iter()
-            .map(|rel| {
-                format!(
+            .map(|rel| -> Result<Query, anyhow::Error> {
+                let rel_type = schema.relationship_type(&rel.label).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Relationship type {} is not part of the graph schema",
+                        rel.label
+                    )
+                })?;
+
+                for (node_id, expected_label) in [
+                    (&rel.source_id, &rel_type.source_node_type),
+                    (&rel.target_id, &rel_type.target_node_type),
+                ] {
+                    if let Some(label) = node_labels.get(node_id) {
+                        if label != expected_label {
+                            return Err(anyhow::anyhow!(
+                                "Relationship {} cannot connect a {} node ({})",
+                                rel.label,
+                                label,
+                                node_id
+                            ));
+                        }
+                    }
+                }
+
+                Ok(query(&format!(
                     r#"
-                    MATCH (n), (m)
-                    WHERE n.id = "{}" AND m.id = "{}"
+                    MATCH {}, {}
                     CREATE (n)-[:{}]->(m)
                     "#,
-                    node_id_map.get(&rel.source_id).unwrap_or(&rel.source_id),
-                    node_id_map.get(&rel.target_id).unwrap_or(&rel.target_id),
-                    rel.label
+                    endpoint_pattern("n", "source_id", &rel_type.source_node_type),
+                    endpoint_pattern("m", "target_id", &rel_type.target_node_type),
+                    rel_type.label
+                ))
+                .param(
+                    "source_id",
+                    node_id_map
+                        .get(&rel.source_id)
+                        .unwrap_or(&rel.source_id)
+                        .clone(),
+                )
+                .param(
+                    "target_id",
+                    node_id_map
+                        .get(&rel.target_id)
+                        .unwrap_or(&rel.target_id)
+                        .clone(),
                 )
+                .param("user_id", user_id.to_string()))
             })
-            .collect::<Vec<String>>();
+            .collect::<Result<Vec<Query>, anyhow::Error>>()?;
 
         Ok(CypherQueries {
             queries: node_queries.into_iter().chain(rel_queries).collect(),
+            node_ids: node_id_map,
         })
     }
 }
+
+impl GraphRelationship {
+    pub fn delete_query(&self, user_id: &Uuid) -> Result<Query, anyhow::Error> {
+        let rel_type = GraphSchema::get()
+            .relationship_type(&self.label)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Relationship type {} is not part of the graph schema",
+                    self.label
+                )
+            })?;
+
+        Ok(query(&format!(
+            "MATCH {}-[r:{}]->{} DELETE r",
+            endpoint_pattern("n", "source_id", &rel_type.source_node_type),
+            rel_type.label,
+            endpoint_pattern("m", "target_id", &rel_type.target_node_type),
+        ))
+        .param("source_id", self.source_id.clone())
+        .param("target_id", self.target_id.clone())
+        .param("user_id", user_id.to_string()))
+    }
+
+    // moves the relationship onto other endpoints, keeping its properties. An
+    // existing relationship between them keeps its own and takes over the
+    // chats and messages of the moved one.
+    pub fn rewire_query(
+        &self,
+        to: &GraphRelationship,
+        user_id: &Uuid,
+    ) -> Result<Query, anyhow::Error> {
+        let rel_type = GraphSchema::get()
+            .relationship_type(&self.label)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Relationship type {} is not part of the graph schema",
+                    self.label
+                )
+            })?;
+
+        Ok(query(&format!(
+            r#"
+            MATCH {}-[old:{}]->{}
+            MATCH {}, {}
+            MERGE (s)-[r:{}]->(t)
+            ON CREATE SET r = properties(old)
+            ON MATCH SET r.source_chat_ids = coalesce(r.source_chat_ids, []) + [x IN coalesce(old.source_chat_ids, []) WHERE NOT x IN coalesce(r.source_chat_ids, [])],
+                r.source_message_ids = coalesce(r.source_message_ids, []) + [x IN coalesce(old.source_message_ids, []) WHERE NOT x IN coalesce(r.source_message_ids, [])]
+            "#,
+            endpoint_pattern("n", "source_id", &rel_type.source_node_type),
+            rel_type.label,
+            endpoint_pattern("m", "target_id", &rel_type.target_node_type),
+            endpoint_pattern("s", "new_source_id", &rel_type.source_node_type),
+            endpoint_pattern("t", "new_target_id", &rel_type.target_node_type),
+            rel_type.label,
+        ))
+        .param("source_id", self.source_id.clone())
+        .param("target_id", self.target_id.clone())
+        .param("new_source_id", to.source_id.clone())
+        .param("new_target_id", to.target_id.clone())
+        .param("user_id", user_id.to_string()))
+    }
+
+    // recreates the relationship between existing nodes unless it is still
+    // there
+    pub fn restore_query(&self, user_id: &Uuid) -> Result<Query, anyhow::Error> {
+        let rel_type = GraphSchema::get()
+            .relationship_type(&self.label)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Relationship type {} is not part of the graph schema",
+                    self.label
+                )
+            })?;
+
+        Ok(query(&format!(
+            "MATCH {}, {} MERGE (n)-[:{}]->(m)",
+            endpoint_pattern("n", "source_id", &rel_type.source_node_type),
+            endpoint_pattern("m", "target_id", &rel_type.target_node_type),
+            rel_type.label,
+        ))
+        .param("source_id", self.source_id.clone())
+        .param("target_id", self.target_id.clone())
+        .param("user_id", user_id.to_string()))
+    }
+}
+
+impl Provenance {
+    fn set_clause(var: &str) -> String {
+        format!(
+            r#"
+            SET {0}.source_chat_ids = [x IN coalesce({0}.source_chat_ids, []) WHERE NOT x IN $chat_ids] + $chat_ids,
+                {0}.source_message_ids = [x IN coalesce({0}.source_message_ids, []) WHERE NOT x IN $message_ids] + $message_ids,
+                {0}.extracted_at = $extracted_at,
+                {0}.extraction_model = $extraction_model
+            "#,
+            var
+        )
+    }
+
+    fn with_params(&self, query: Query) -> Query {
+        query
+            .param("chat_ids", self.chat_ids.clone())
+            .param("message_ids", self.message_ids.clone())
+            .param(
+                "extracted_at",
+                self.extracted_at.clone().unwrap_or_default(),
+            )
+            .param(
+                "extraction_model",
+                self.extraction_model.clone().unwrap_or_default(),
+            )
+    }
+
+    pub fn node_query(&self, label: &str, node_id: &str, user_id: &Uuid) -> Query {
+        self.with_params(
+            query(&format!(
+                "MATCH {} {}",
+                node_pattern("n", label, "id"),
+                Self::set_clause("n")
+            ))
+            .param("id", node_id)
+            .param("user_id", user_id.to_string()),
+        )
+    }
+
+    pub fn relationship_query(
+        &self,
+        rel: &GraphRelationship,
+        user_id: &Uuid,
+    ) -> Result<Query, anyhow::Error> {
+        let rel_type = GraphSchema::get()
+            .relationship_type(&rel.label)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Relationship type {} is not part of the graph schema",
+                    rel.label
+                )
+            })?;
+
+        Ok(self.with_params(
+            query(&format!(
+                "MATCH {}-[r:{}]->{} {}",
+                endpoint_pattern("n", "source_id", &rel_type.source_node_type),
+                rel_type.label,
+                endpoint_pattern("m", "target_id", &rel_type.target_node_type),
+                Self::set_clause("r")
+            ))
+            .param("source_id", rel.source_id.clone())
+            .param("target_id", rel.target_id.clone())
+            .param("user_id", user_id.to_string()),
+        ))
+    }
+
+    // hands the chats and messages of a node that is merged away to the node
+    // it is merged into
+    pub fn absorb_query(
+        label: &str,
+        survivor_id: &str,
+        duplicate_id: &str,
+        user_id: &Uuid,
+    ) -> Query {
+        query(&format!(
+            r#"
+            MATCH {}, {}
+            SET s.source_chat_ids = coalesce(s.source_chat_ids, []) + [x IN coalesce(d.source_chat_ids, []) WHERE NOT x IN coalesce(s.source_chat_ids, [])],
+                s.source_message_ids = coalesce(s.source_message_ids, []) + [x IN coalesce(d.source_message_ids, []) WHERE NOT x IN coalesce(s.source_message_ids, [])]
+            "#,
+            node_pattern("s", label, "survivor_id"),
+            node_pattern("d", label, "duplicate_id")
+        ))
+        .param("survivor_id", survivor_id)
+        .param("duplicate_id", duplicate_id)
+        .param("user_id", user_id.to_string())
+    }
+}
+
+// nodes are always matched together with their owner so a query can never
+// reach into another user's graph, `$user_id` has to be bound
+pub fn node_pattern(var: &str, label: &str, id_param: &str) -> String {
+    format!(
+        "({}:{} {{id: ${}, owner_id: $user_id}})",
+        var, label, id_param
+    )
+}
+
+// the user node is matched on its user_id so extractions never spawn a second
+// node for the same user
+fn endpoint_pattern(var: &str, id_param: &str, label: &str) -> String {
+    match label {
+        "User" => format!("({}:User {{user_id: $user_id}})", var),
+        _ => node_pattern(var, label, id_param),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_patterns_are_scoped_to_the_user() {
+        assert_eq!(
+            node_pattern("n", "Goal", "id"),
+            "(n:Goal {id: $id, owner_id: $user_id})"
+        );
+
+        for label in ["User", "Interest", "Goal", "Motivation", "Task", "Date"] {
+            assert!(endpoint_pattern("n", "source_id", label).contains("$user_id"));
+        }
+    }
+
+    #[test]
+    fn relationship_queries_are_scoped_to_the_user() {
+        let rel = GraphRelationship {
+            source_id: String::from("task"),
+            target_id: String::from("goal"),
+            label: String::from("PART_OF"),
+        };
+        let user_id = Uuid::new_v4();
+
+        let query = rel.delete_query(&user_id).unwrap();
+        assert!(query.has_param_key("user_id"));
+
+        let query = rel.restore_query(&user_id).unwrap();
+        assert!(query.has_param_key("user_id"));
+
+        let moved = GraphRelationship {
+            source_id: String::from("survivor"),
+            ..rel.clone()
+        };
+        let query = rel.rewire_query(&moved, &user_id).unwrap();
+        assert!(query.has_param_key("user_id"));
+        assert!(query.has_param_key("new_source_id"));
+    }
+
+    #[test]
+    fn restored_nodes_are_merged_on_id_and_owner() {
+        let node = GraphNode {
+            id: String::from("goal"),
+            label: String::from("Goal"),
+            properties: HashMap::new(),
+        };
+
+        let query = node.restore_query(&Uuid::new_v4(), None).unwrap();
+        assert!(query.has_param_key("id"));
+        assert!(query.has_param_key("user_id"));
+    }
+}
@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+use crate::types::{GraphNode, GraphRelationship};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "graph_change_source", rename_all = "lowercase")]
+pub enum ChangeSource {
+    Extraction,
+    Edit,
+    Task,
+    Dedup,
+    Import,
+    Review,
+    Rollback,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeChange {
+    pub before: GraphNode,
+    pub after: GraphNode,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GraphChanges {
+    pub added_nodes: Vec<GraphNode>,
+    pub updated_nodes: Vec<NodeChange>,
+    pub removed_nodes: Vec<GraphNode>,
+    pub added_relationships: Vec<GraphRelationship>,
+    pub removed_relationships: Vec<GraphRelationship>,
+}
+
+impl GraphChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added_nodes.is_empty()
+            && self.updated_nodes.is_empty()
+            && self.removed_nodes.is_empty()
+            && self.added_relationships.is_empty()
+            && self.removed_relationships.is_empty()
+    }
+}
+
+// version 0 is the empty graph before the first changeset
+#[derive(Debug, Clone, Deserialize)]
+pub struct VersionDiffQuery {
+    pub from: i32,
+    pub to: i32,
+}
@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "job_kind", rename_all = "lowercase")]
+pub enum JobKind {
+    #[serde(rename = "extract_knowledge")]
+    #[sqlx(rename = "extract_knowledge")]
+    ExtractKnowledge,
+    #[serde(rename = "dedup_graph")]
+    #[sqlx(rename = "dedup_graph")]
+    DedupGraph,
+    #[serde(rename = "review_goals")]
+    #[sqlx(rename = "review_goals")]
+    ReviewGoals,
+}
+
+impl JobKind {
+    pub fn max_attempts(&self) -> i32 {
+        match self {
+            JobKind::ExtractKnowledge => 5,
+            JobKind::DedupGraph => 3,
+            JobKind::ReviewGoals => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "job_status", rename_all = "lowercase")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtractKnowledgePayload {
+    pub chat_id: Uuid,
+}
+
+impl ExtractKnowledgePayload {
+    // keyed on the newest message so a chat is only extracted again once it
+    // has changed
+    pub fn idempotency_key(&self, last_message_id: Uuid) -> String {
+        format!("{}:{}", self.chat_id, last_message_id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DedupGraphPayload {
+    pub dry_run: bool,
+    pub labels: Vec<String>,
+    pub threshold: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReviewGoalsPayload {
+    pub chat_id: Uuid,
+}
+
+impl ReviewGoalsPayload {
+    pub fn idempotency_key(&self, last_message_id: Uuid) -> String {
+        format!("review:{}:{}", self.chat_id, last_message_id)
+    }
+}
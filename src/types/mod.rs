@@ -1,5 +1,17 @@
 pub mod ai;
+pub mod auth;
+pub mod chat;
 pub mod graph;
+pub mod history;
+pub mod job;
+pub mod schema;
+pub mod task;
 
 pub use ai::*;
+pub use auth::*;
+pub use chat::*;
 pub use graph::*;
+pub use history::*;
+pub use job::*;
+pub use schema::*;
+pub use task::*;
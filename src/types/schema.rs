@@ -0,0 +1,670 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::OnceLock,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{types::GraphData, utils::constants::GRAPH_SCHEMA};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PropertyType {
+    String,
+    Integer,
+    Float,
+    Boolean,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PropertyDefinition {
+    pub nullable: bool,
+    #[serde(rename = "type")]
+    pub property_type: PropertyType,
+    #[serde(rename = "enum")]
+    pub allowed_values: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NodeType {
+    pub id_format: String,
+    pub label: String,
+    pub properties: HashMap<String, PropertyDefinition>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RelationshipType {
+    pub label: String,
+    pub source_node_type: String,
+    pub target_node_type: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphSchema {
+    pub node_types: Vec<NodeType>,
+    pub relationship_types: Vec<RelationshipType>,
+}
+
+#[derive(Deserialize)]
+struct GraphSchemaDocument {
+    #[serde(rename = "graphSchema")]
+    graph_schema: GraphSchema,
+}
+
+impl GraphSchema {
+    pub fn get() -> &'static GraphSchema {
+        static SCHEMA: OnceLock<GraphSchema> = OnceLock::new();
+
+        SCHEMA.get_or_init(|| {
+            serde_json::from_str::<GraphSchemaDocument>(GRAPH_SCHEMA)
+                .expect("GRAPH_SCHEMA is not a valid graph schema")
+                .graph_schema
+        })
+    }
+
+    pub fn node_type(&self, label: &str) -> Option<&NodeType> {
+        self.node_types.iter().find(|n| n.label == label)
+    }
+
+    pub fn relationship_type(&self, label: &str) -> Option<&RelationshipType> {
+        self.relationship_types.iter().find(|r| r.label == label)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SchemaViolation {
+    Node {
+        id: String,
+        label: String,
+        message: String,
+    },
+    Relationship {
+        source: String,
+        target: String,
+        label: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaViolation::Node { id, label, message } => {
+                write!(f, "Node {} ({}): {}", id, label, message)
+            }
+            SchemaViolation::Relationship {
+                source,
+                target,
+                label,
+                message,
+            } => write!(
+                f,
+                "Relationship {} -[{}]-> {}: {}",
+                source, label, target, message
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ValidationReport {
+    pub violations: Vec<SchemaViolation>,
+    pub repairs: Vec<String>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl GraphSchema {
+    // Validates graph data in place, repairing what can be fixed without
+    // guessing (unknown props, loosely typed values, reversed edges) and
+    // reporting everything else. `existing_nodes` maps ids of nodes that are
+    // already in the user's graph to their labels.
+    pub fn validate(
+        &self,
+        graph: &mut GraphData,
+        existing_nodes: &HashMap<String, String>,
+    ) -> ValidationReport {
+        let mut report = ValidationReport::default();
+        let mut node_labels: HashMap<String, String> = existing_nodes.clone();
+        let mut seen_ids: HashSet<String> = HashSet::new();
+
+        for node in graph.nodes.iter_mut() {
+            let mut violation = |message: String| {
+                report.violations.push(SchemaViolation::Node {
+                    id: node.id.clone(),
+                    label: node.label.clone(),
+                    message,
+                })
+            };
+
+            if node.id.trim().is_empty() {
+                violation("node id is empty".to_string());
+            } else if !seen_ids.insert(node.id.clone()) {
+                violation("node id is used more than once".to_string());
+            }
+
+            let Some(node_type) = self.node_type(&node.label) else {
+                violation(format!(
+                    "unknown label, expected one of {}",
+                    self.node_types
+                        .iter()
+                        .map(|n| n.label.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                ));
+                continue;
+            };
+
+            node_labels.insert(node.id.clone(), node.label.clone());
+
+            let unknown_props = node
+                .properties
+                .keys()
+                .filter(|k| !node_type.properties.contains_key(*k))
+                .cloned()
+                .collect::<Vec<String>>();
+            for key in unknown_props {
+                node.properties.remove(&key);
+                report.repairs.push(format!(
+                    "Dropped unknown property {} from node {} ({})",
+                    key, node.id, node.label
+                ));
+            }
+
+            for (key, definition) in &node_type.properties {
+                let value = node.properties.get(key).cloned().unwrap_or(Value::Null);
+
+                match definition.check(&value) {
+                    Ok(None) => {}
+                    Ok(Some(repaired)) => {
+                        report.repairs.push(format!(
+                            "Coerced property {} of node {} ({}) from {} to {}",
+                            key, node.id, node.label, value, repaired
+                        ));
+                        node.properties.insert(key.clone(), repaired);
+                    }
+                    Err(message) => report.violations.push(SchemaViolation::Node {
+                        id: node.id.clone(),
+                        label: node.label.clone(),
+                        message: format!("property {} {}", key, message),
+                    }),
+                }
+            }
+        }
+
+        let mut seen_relationships: HashSet<(String, String, String)> = HashSet::new();
+        let mut relationships = Vec::with_capacity(graph.relationships.len());
+
+        for mut rel in graph.relationships.drain(..) {
+            let mut violation = |message: String| {
+                report.violations.push(SchemaViolation::Relationship {
+                    source: rel.source_id.clone(),
+                    target: rel.target_id.clone(),
+                    label: rel.label.clone(),
+                    message,
+                })
+            };
+
+            let Some(rel_type) = self.relationship_type(&rel.label) else {
+                violation("unknown relationship type".to_string());
+                continue;
+            };
+
+            let (Some(source_label), Some(target_label)) = (
+                node_labels.get(&rel.source_id).cloned(),
+                node_labels.get(&rel.target_id).cloned(),
+            ) else {
+                violation("references a node that does not exist".to_string());
+                continue;
+            };
+
+            if source_label == rel_type.target_node_type
+                && target_label == rel_type.source_node_type
+                && source_label != target_label
+            {
+                std::mem::swap(&mut rel.source_id, &mut rel.target_id);
+                report.repairs.push(format!(
+                    "Reversed relationship {} between {} and {}",
+                    rel.label, rel.target_id, rel.source_id
+                ));
+            } else if source_label != rel_type.source_node_type
+                || target_label != rel_type.target_node_type
+            {
+                violation(format!(
+                    "must connect {} to {}, found {} to {}",
+                    rel_type.source_node_type,
+                    rel_type.target_node_type,
+                    source_label,
+                    target_label
+                ));
+                continue;
+            }
+
+            if !seen_relationships.insert((
+                rel.source_id.clone(),
+                rel.label.clone(),
+                rel.target_id.clone(),
+            )) {
+                report.repairs.push(format!(
+                    "Dropped duplicate relationship {} -[{}]-> {}",
+                    rel.source_id, rel.label, rel.target_id
+                ));
+                continue;
+            }
+
+            relationships.push(rel);
+        }
+
+        graph.relationships = relationships;
+
+        report
+    }
+}
+
+impl PropertyDefinition {
+    // Ok(None) when the value is fine as is, Ok(Some(_)) with a repaired value
+    // when it can be losslessly coerced
+    pub fn check(&self, value: &Value) -> Result<Option<Value>, String> {
+        if value.is_null() {
+            return match self.nullable {
+                true => Ok(None),
+                false => Err("is required".to_string()),
+            };
+        }
+
+        let repaired = match (&self.property_type, value) {
+            (PropertyType::String, Value::String(_)) => None,
+            (PropertyType::String, Value::Number(n)) => Some(Value::String(n.to_string())),
+            (PropertyType::String, Value::Bool(b)) => Some(Value::String(b.to_string())),
+            (PropertyType::Integer, Value::Number(n)) if n.is_i64() || n.is_u64() => None,
+            (PropertyType::Integer, Value::Number(n)) => match n.as_f64() {
+                Some(f) if f.fract() == 0.0 => Some(Value::from(f as i64)),
+                _ => return Err(format!("must be an integer, found {}", n)),
+            },
+            (PropertyType::Integer, Value::String(s)) => match s.trim().parse::<i64>() {
+                Ok(i) => Some(Value::from(i)),
+                Err(_) => return Err(format!("must be an integer, found \"{}\"", s)),
+            },
+            (PropertyType::Float, Value::Number(_)) => None,
+            (PropertyType::Float, Value::String(s)) => match s.trim().parse::<f64>() {
+                Ok(f) => Some(Value::from(f)),
+                Err(_) => return Err(format!("must be a number, found \"{}\"", s)),
+            },
+            (PropertyType::Boolean, Value::Bool(_)) => None,
+            (PropertyType::Boolean, Value::String(s)) => match s.trim().to_lowercase().as_str() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => return Err(format!("must be a boolean, found \"{}\"", s)),
+            },
+            (property_type, value) => {
+                return Err(format!(
+                    "must be of type {:?}, found {}",
+                    property_type, value
+                ))
+            }
+        };
+
+        let Some(allowed_values) = &self.allowed_values else {
+            return Ok(repaired);
+        };
+
+        let current = repaired.as_ref().unwrap_or(value);
+        let Some(text) = current.as_str() else {
+            return Ok(repaired);
+        };
+
+        if allowed_values.iter().any(|v| v == text) {
+            return Ok(repaired);
+        }
+
+        let normalize = |s: &str| s.trim().to_lowercase().replace([' ', '-', '_'], "");
+        match allowed_values
+            .iter()
+            .find(|v| normalize(v) == normalize(text))
+        {
+            Some(allowed) => Ok(Some(Value::String(allowed.clone()))),
+            None => Err(format!(
+                "must be one of {}, found \"{}\"",
+                allowed_values.join(", "),
+                text
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::types::{GraphNode, GraphRelationship};
+
+    fn schema() -> GraphSchema {
+        serde_json::from_value(json!({
+            "nodeTypes": [
+                {
+                    "id_format": "uuid",
+                    "label": "Goal",
+                    "properties": {
+                        "description": {"nullable": false, "type": "string"},
+                        "progress": {
+                            "nullable": true,
+                            "type": "string",
+                            "enum": ["not_started", "in_progress", "done"]
+                        },
+                    }
+                },
+                {
+                    "id_format": "uuid",
+                    "label": "Task",
+                    "properties": {
+                        "description": {"nullable": false, "type": "string"},
+                        "priority": {"nullable": true, "type": "integer"},
+                        "hours": {"nullable": true, "type": "float"},
+                        "recurring": {"nullable": true, "type": "boolean"},
+                    }
+                }
+            ],
+            "relationshipTypes": [
+                {"label": "PART_OF", "source_node_type": "Task", "target_node_type": "Goal"}
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn node(id: &str, label: &str, properties: Value) -> GraphNode {
+        GraphNode {
+            id: id.to_string(),
+            label: label.to_string(),
+            properties: serde_json::from_value(properties).unwrap(),
+        }
+    }
+
+    fn part_of(source_id: &str, target_id: &str) -> GraphRelationship {
+        GraphRelationship {
+            source_id: source_id.to_string(),
+            target_id: target_id.to_string(),
+            label: String::from("PART_OF"),
+        }
+    }
+
+    fn validate(graph: &mut GraphData) -> ValidationReport {
+        schema().validate(graph, &HashMap::new())
+    }
+
+    fn task(properties: Value) -> GraphData {
+        GraphData {
+            nodes: vec![node("task", "Task", properties)],
+            relationships: vec![],
+        }
+    }
+
+    // the single violation of a report, as its message
+    fn violation(report: &ValidationReport) -> String {
+        assert_eq!(report.violations.len(), 1, "{:?}", report.violations);
+        match &report.violations[0] {
+            SchemaViolation::Node { message, .. } => message.clone(),
+            SchemaViolation::Relationship { message, .. } => message.clone(),
+        }
+    }
+
+    #[test]
+    fn valid_graphs_pass_untouched() {
+        let mut graph = GraphData {
+            nodes: vec![
+                node("goal", "Goal", json!({"description": "Run a marathon"})),
+                node(
+                    "task",
+                    "Task",
+                    json!({"description": "Run 5k", "priority": 1}),
+                ),
+            ],
+            relationships: vec![part_of("task", "goal")],
+        };
+
+        let report = validate(&mut graph);
+        assert!(report.is_valid());
+        assert!(report.repairs.is_empty());
+        assert_eq!(graph.relationships.len(), 1);
+    }
+
+    #[test]
+    fn enum_values_are_normalised() {
+        let mut graph = GraphData {
+            nodes: vec![node(
+                "goal",
+                "Goal",
+                json!({"description": "Run a marathon", "progress": "In Progress"}),
+            )],
+            relationships: vec![],
+        };
+
+        let report = validate(&mut graph);
+        assert!(report.is_valid());
+        assert_eq!(report.repairs.len(), 1);
+        assert_eq!(graph.nodes[0].properties["progress"], json!("in_progress"));
+    }
+
+    #[test]
+    fn integers_are_coerced() {
+        for (value, expected) in [(json!("3"), json!(3)), (json!(4.0), json!(4))] {
+            let mut graph = task(json!({"description": "Run 5k", "priority": value}));
+
+            let report = validate(&mut graph);
+            assert!(report.is_valid());
+            assert_eq!(report.repairs.len(), 1);
+            assert_eq!(graph.nodes[0].properties["priority"], expected);
+        }
+    }
+
+    #[test]
+    fn strings_are_coerced() {
+        let mut graph = task(json!({"description": 5}));
+
+        let report = validate(&mut graph);
+        assert!(report.is_valid());
+        assert_eq!(report.repairs.len(), 1);
+        assert_eq!(graph.nodes[0].properties["description"], json!("5"));
+    }
+
+    #[test]
+    fn floats_are_coerced() {
+        let mut graph = task(json!({"description": "Run 5k", "hours": " 1.5 "}));
+
+        let report = validate(&mut graph);
+        assert!(report.is_valid());
+        assert_eq!(report.repairs.len(), 1);
+        assert_eq!(graph.nodes[0].properties["hours"], json!(1.5));
+    }
+
+    #[test]
+    fn booleans_are_coerced() {
+        let mut graph = task(json!({"description": "Run 5k", "recurring": "TRUE"}));
+
+        let report = validate(&mut graph);
+        assert!(report.is_valid());
+        assert_eq!(report.repairs.len(), 1);
+        assert_eq!(graph.nodes[0].properties["recurring"], json!(true));
+    }
+
+    #[test]
+    fn reversed_relationships_are_swapped() {
+        let mut graph = GraphData {
+            nodes: vec![node(
+                "goal",
+                "Goal",
+                json!({"description": "Run a marathon"}),
+            )],
+            relationships: vec![part_of("goal", "task")],
+        };
+        let existing = HashMap::from([(String::from("task"), String::from("Task"))]);
+
+        let report = schema().validate(&mut graph, &existing);
+        assert!(report.is_valid());
+        assert_eq!(report.repairs.len(), 1);
+        assert_eq!(graph.relationships[0].source_id, "task");
+        assert_eq!(graph.relationships[0].target_id, "goal");
+    }
+
+    #[test]
+    fn duplicate_relationships_are_dropped() {
+        let mut graph = GraphData {
+            nodes: vec![
+                node("goal", "Goal", json!({"description": "Run a marathon"})),
+                node("task", "Task", json!({"description": "Run 5k"})),
+            ],
+            relationships: vec![part_of("task", "goal"), part_of("task", "goal")],
+        };
+
+        let report = validate(&mut graph);
+        assert!(report.is_valid());
+        assert_eq!(report.repairs.len(), 1);
+        assert_eq!(graph.relationships.len(), 1);
+    }
+
+    #[test]
+    fn unknown_properties_are_dropped() {
+        let mut graph = task(json!({"description": "Run 5k", "mood": "eager"}));
+
+        let report = validate(&mut graph);
+        assert!(report.is_valid());
+        assert_eq!(report.repairs.len(), 1);
+        assert!(!graph.nodes[0].properties.contains_key("mood"));
+    }
+
+    #[test]
+    fn empty_ids_are_rejected() {
+        let mut graph = GraphData {
+            nodes: vec![node(" ", "Task", json!({"description": "Run 5k"}))],
+            relationships: vec![],
+        };
+
+        assert_eq!(violation(&validate(&mut graph)), "node id is empty");
+    }
+
+    #[test]
+    fn reused_ids_are_rejected() {
+        let mut graph = GraphData {
+            nodes: vec![
+                node("task", "Task", json!({"description": "Run 5k"})),
+                node("task", "Task", json!({"description": "Run 10k"})),
+            ],
+            relationships: vec![],
+        };
+
+        assert_eq!(
+            violation(&validate(&mut graph)),
+            "node id is used more than once"
+        );
+    }
+
+    #[test]
+    fn unknown_labels_are_rejected() {
+        let mut graph = GraphData {
+            nodes: vec![node("hobby", "Hobby", json!({}))],
+            relationships: vec![],
+        };
+
+        assert!(violation(&validate(&mut graph)).starts_with("unknown label"));
+    }
+
+    #[test]
+    fn required_properties_are_rejected_when_missing() {
+        let mut graph = task(json!({}));
+
+        assert_eq!(
+            violation(&validate(&mut graph)),
+            "property description is required"
+        );
+    }
+
+    #[test]
+    fn values_that_cant_be_coerced_are_rejected() {
+        let cases = [
+            (json!({"priority": "high"}), "must be an integer"),
+            (json!({"priority": 1.5}), "must be an integer"),
+            (json!({"hours": "long"}), "must be a number"),
+            (json!({"recurring": "sometimes"}), "must be a boolean"),
+            (json!({"recurring": 1}), "must be of type Boolean"),
+        ];
+
+        for (mut properties, expected) in cases {
+            properties["description"] = json!("Run 5k");
+            let mut graph = task(properties);
+
+            let message = violation(&validate(&mut graph));
+            assert!(message.contains(expected), "{}", message);
+        }
+    }
+
+    #[test]
+    fn values_outside_the_enum_are_rejected() {
+        let mut graph = GraphData {
+            nodes: vec![node(
+                "goal",
+                "Goal",
+                json!({"description": "Run a marathon", "progress": "halfway"}),
+            )],
+            relationships: vec![],
+        };
+
+        assert!(violation(&validate(&mut graph)).starts_with("property progress must be one of"));
+    }
+
+    #[test]
+    fn unknown_relationship_types_are_rejected() {
+        let mut graph = GraphData {
+            nodes: vec![
+                node("goal", "Goal", json!({"description": "Run a marathon"})),
+                node("task", "Task", json!({"description": "Run 5k"})),
+            ],
+            relationships: vec![GraphRelationship {
+                label: String::from("BLOCKS"),
+                ..part_of("task", "goal")
+            }],
+        };
+
+        assert_eq!(
+            violation(&validate(&mut graph)),
+            "unknown relationship type"
+        );
+        assert!(graph.relationships.is_empty());
+    }
+
+    #[test]
+    fn relationships_to_missing_nodes_are_rejected() {
+        let mut graph = GraphData {
+            nodes: vec![node("task", "Task", json!({"description": "Run 5k"}))],
+            relationships: vec![part_of("task", "goal")],
+        };
+
+        assert_eq!(
+            violation(&validate(&mut graph)),
+            "references a node that does not exist"
+        );
+    }
+
+    #[test]
+    fn relationships_between_the_wrong_labels_are_rejected() {
+        let mut graph = GraphData {
+            nodes: vec![
+                node("task", "Task", json!({"description": "Run 5k"})),
+                node("other", "Task", json!({"description": "Run 10k"})),
+            ],
+            relationships: vec![part_of("task", "other")],
+        };
+
+        assert_eq!(
+            violation(&validate(&mut graph)),
+            "must connect Task to Goal, found Task to Task"
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    Pending,
+    InProgress,
+    Completed,
+    Failed,
+}
+
+impl TaskStatus {
+    // tasks extracted without a status are treated as pending
+    pub fn parse(status: Option<&str>) -> Self {
+        match status {
+            Some("in_progress") => TaskStatus::InProgress,
+            Some("completed") => TaskStatus::Completed,
+            Some("failed") => TaskStatus::Failed,
+            _ => TaskStatus::Pending,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskStatus::Pending => "pending",
+            TaskStatus::InProgress => "in_progress",
+            TaskStatus::Completed => "completed",
+            TaskStatus::Failed => "failed",
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(self, TaskStatus::Pending | TaskStatus::InProgress)
+    }
+
+    // completed tasks are final, failed ones can be picked up again
+    pub fn can_transition_to(&self, next: TaskStatus) -> bool {
+        matches!(
+            (self, next),
+            (
+                TaskStatus::Pending,
+                TaskStatus::InProgress | TaskStatus::Completed | TaskStatus::Failed
+            ) | (
+                TaskStatus::InProgress,
+                TaskStatus::Pending | TaskStatus::Completed | TaskStatus::Failed
+            ) | (
+                TaskStatus::Failed,
+                TaskStatus::Pending | TaskStatus::InProgress
+            )
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskItem {
+    pub id: String,
+    pub action: String,
+    pub status: TaskStatus,
+    pub created_on: Option<NaiveDate>,
+    pub completed_on: Option<NaiveDate>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListTasksQuery {
+    pub date: Option<NaiveDate>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateTaskStatusRequest {
+    pub status: TaskStatus,
+}
@@ -0,0 +1,310 @@
+use std::sync::Arc;
+
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestUserMessageContent,
+};
+use chrono::Local;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    model::{Chat, Job, Message},
+    types::{
+        ChatPrompts, ExtractKnowledgePayload, JobKind, ReviewGoalsPayload, SendMessageRequest,
+    },
+    utils::{
+        config::AppState,
+        graph::get_owned_graph,
+        retrieval::retrieve_context,
+        review::{daily_outlines, goal_overview, task_changes},
+        tasks::task_history,
+    },
+};
+
+pub struct PreparedChat {
+    pub messages: Vec<ChatCompletionRequestMessage>,
+    pub flavour: ChatPrompts,
+    // the user message stored for this turn
+    pub message_id: Option<Uuid>,
+}
+
+// the part of the user's graph related to what they just said, or all of it
+// when the chat is just starting
+async fn chat_context(
+    app_state: &AppState,
+    user_id: &Uuid,
+    chat_id: &Uuid,
+    last_content: Option<&str>,
+) -> Result<String, anyhow::Error> {
+    let (query, threshold) = match last_content {
+        Some(content) => (content.to_string(), 0.4),
+        None => (String::from(""), 0.0),
+    };
+
+    retrieve_context(app_state, user_id, query, threshold, Some(*chat_id)).await
+}
+
+async fn build_system_prompt(
+    app_state: &AppState,
+    user_id: &Uuid,
+    chat_id: &Uuid,
+    flavour: &ChatPrompts,
+    last_content: Option<&str>,
+) -> Result<String, anyhow::Error> {
+    let chat_sys_prompt = match flavour {
+        ChatPrompts::InitialGoals => ChatPrompts::InitialGoals.prompt_template().to_string(),
+        ChatPrompts::DailyOutline => {
+            let context = chat_context(app_state, user_id, chat_id, last_content).await?;
+
+            let task_history = task_history(app_state, user_id).await?;
+
+            ChatPrompts::DailyOutline
+                .prompt_template()
+                .replace("{date}", &Local::now().format("%B %d, %Y").to_string())
+                .replace("{context}", &context)
+                .replace("{task_history}", &task_history)
+        }
+        ChatPrompts::WeeklyReview => {
+            let context = chat_context(app_state, user_id, chat_id, last_content).await?;
+            let graph = get_owned_graph(app_state, user_id).await?;
+
+            ChatPrompts::WeeklyReview
+                .prompt_template()
+                .replace("{date}", &Local::now().format("%B %d, %Y").to_string())
+                .replace("{context}", &context)
+                .replace("{goals}", &goal_overview(&graph))
+                .replace("{task_changes}", &task_changes(app_state, user_id).await?)
+                .replace(
+                    "{daily_outlines}",
+                    &daily_outlines(app_state, user_id).await?,
+                )
+        }
+    };
+
+    Ok(chat_sys_prompt)
+}
+
+pub fn get_data_from_message_request(
+    message: ChatCompletionRequestMessage,
+) -> Result<(String, String), anyhow::Error> {
+    match message {
+        ChatCompletionRequestMessage::System(system) => {
+            Ok((String::from("system"), system.content))
+        }
+        ChatCompletionRequestMessage::User(user) => match user.content {
+            ChatCompletionRequestUserMessageContent::Text(text) => Ok((String::from("user"), text)),
+            _ => Err(anyhow::anyhow!("Only text content messages are supported")),
+        },
+        ChatCompletionRequestMessage::Assistant(assistant) => match assistant.content {
+            Some(text) => Ok((String::from("assistant"), text)),
+            None => Err(anyhow::anyhow!("Assistant message content is missing")),
+        },
+        _ => Err(anyhow::anyhow!("Unsupported message type")),
+    }
+}
+
+pub async fn prepare_chat_messages(
+    app_state: &AppState,
+    user_id: &Uuid,
+    body: &SendMessageRequest,
+) -> Result<PreparedChat, anyhow::Error> {
+    let chat_id = body.chat_id;
+    let server_history = body.message.is_some();
+
+    let last_message = match &body.message {
+        Some(content) => Some((String::from("user"), content.clone())),
+        None => body
+            .messages
+            .last()
+            .cloned()
+            .map(get_data_from_message_request)
+            .transpose()?,
+    };
+
+    let existing_chat = Chat::get(&app_state.pool, chat_id).await?;
+
+    if let Some(chat) = &existing_chat {
+        chat.check_owner(*user_id)?;
+        chat.check_active()?;
+    }
+
+    let (mut messages, flavour) = match existing_chat {
+        Some(chat) if server_history => {
+            let system_message = Message::get_system_message_for_chat(&app_state.pool, chat_id)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("No system prompt stored for chat {}", chat_id))?;
+            let history = Message::get_all_messages_for_chat(&app_state.pool, chat_id).await?;
+
+            let messages = std::iter::once(system_message)
+                .chain(history)
+                .map(ChatCompletionRequestMessage::try_from)
+                .collect::<Result<Vec<ChatCompletionRequestMessage>, anyhow::Error>>()?;
+
+            (messages, chat.flavour)
+        }
+        existing_chat => {
+            let chat_sys_prompt = build_system_prompt(
+                app_state,
+                user_id,
+                &chat_id,
+                &body.flavour,
+                last_message.as_ref().map(|(_, content)| content.as_str()),
+            )
+            .await?;
+
+            info!("sys prompt: {}", chat_sys_prompt);
+
+            let mut messages: Vec<ChatCompletionRequestMessage> =
+                vec![ChatCompletionRequestMessage::System(
+                    ChatCompletionRequestSystemMessageArgs::default()
+                        .content(chat_sys_prompt.clone())
+                        .build()?,
+                )];
+
+            if !server_history {
+                messages.extend(body.messages.iter().cloned());
+            }
+
+            if existing_chat.is_none() {
+                Chat::new(
+                    &app_state.pool,
+                    Some(chat_id),
+                    *user_id,
+                    body.flavour.clone(),
+                )
+                .await?;
+
+                Message::new(
+                    &app_state.pool,
+                    chat_id,
+                    String::from("system"),
+                    chat_sys_prompt,
+                )
+                .await?;
+            }
+
+            (messages, body.flavour.clone())
+        }
+    };
+
+    let mut message_id = None;
+    if let Some((role, content)) = last_message {
+        let (message, _) =
+            Message::new_with_embedding(&app_state.pool, &app_state.llm, chat_id, role, content)
+                .await?;
+        message_id = Some(message.id);
+
+        if server_history {
+            messages.push(message.try_into()?);
+        }
+    }
+
+    Ok(PreparedChat {
+        messages,
+        flavour,
+        message_id,
+    })
+}
+
+pub fn extract_final_message(content: &str) -> Result<Option<String>, anyhow::Error> {
+    let final_message = regex::Regex::new(r"<final_message>((?s).*?)</final_message>")?
+        .captures(content)
+        .and_then(|cap| cap.get(1))
+        .map(|m| m.as_str().to_string());
+
+    Ok(final_message)
+}
+
+pub struct ChatTurn {
+    pub final_message: bool,
+    pub job_id: Option<Uuid>,
+}
+
+// A turn whose completion failed partway keeps what was generated so far, so
+// the stored history never ends on an unanswered message. When nothing was
+// generated the user's message is dropped instead and can simply be resent.
+pub async fn abort_chat_turn(
+    app_state: &AppState,
+    chat_id: Uuid,
+    message_id: Option<Uuid>,
+    partial_content: String,
+) -> Result<(), anyhow::Error> {
+    match (partial_content.is_empty(), message_id) {
+        (false, _) => {
+            Message::new(
+                &app_state.pool,
+                chat_id,
+                String::from("assistant"),
+                partial_content,
+            )
+            .await?;
+        }
+        (true, Some(message_id)) => Message::soft_delete(&app_state.pool, message_id).await?,
+        (true, None) => {}
+    }
+
+    Ok(())
+}
+
+pub async fn finish_chat_turn(
+    app_state: Arc<AppState>,
+    user_id: Uuid,
+    chat_id: Uuid,
+    flavour: ChatPrompts,
+    response_content: String,
+) -> Result<ChatTurn, anyhow::Error> {
+    let final_message = extract_final_message(&response_content)?;
+
+    let (message, _) = Message::new_with_embedding(
+        &app_state.pool,
+        &app_state.llm,
+        chat_id,
+        String::from("assistant"),
+        response_content,
+    )
+    .await?;
+
+    let mut job_id = None;
+
+    if final_message.is_some() {
+        match flavour {
+            ChatPrompts::InitialGoals => {
+                info!("Enqueuing knowledge graph extraction.");
+
+                let payload = ExtractKnowledgePayload { chat_id };
+                let job = Job::enqueue(
+                    &app_state.pool,
+                    user_id,
+                    JobKind::ExtractKnowledge,
+                    serde_json::to_value(&payload)?,
+                    Some(payload.idempotency_key(message.id)),
+                )
+                .await?;
+
+                job_id = Some(job.id);
+            }
+            ChatPrompts::WeeklyReview => {
+                info!("Enqueuing goal progress review.");
+
+                let payload = ReviewGoalsPayload { chat_id };
+                let job = Job::enqueue(
+                    &app_state.pool,
+                    user_id,
+                    JobKind::ReviewGoals,
+                    serde_json::to_value(&payload)?,
+                    Some(payload.idempotency_key(message.id)),
+                )
+                .await?;
+
+                job_id = Some(job.id);
+            }
+            ChatPrompts::DailyOutline => {}
+        }
+    }
+
+    Ok(ChatTurn {
+        final_message: final_message.is_some(),
+        job_id,
+    })
+}
@@ -3,16 +3,6 @@ use std::{
     future::Future,
 };
 
-use async_openai::{
-    config::OpenAIConfig,
-    types::{
-        ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
-        ChatCompletionRequestUserMessageContent, ChatCompletionResponseFormat,
-        ChatCompletionResponseFormatType, CreateChatCompletionRequestArgs,
-        CreateEmbeddingRequestArgs,
-    },
-    Client,
-};
 use neo4rs::{query, Error, Graph, Node, Query};
 use serde::Deserialize;
 use shuttle_runtime::SecretStore;
@@ -20,21 +10,39 @@ use sqlx::PgPool;
 use tracing::info;
 use uuid::Uuid;
 
+use crate::middleware::auth::AuthConfig;
 use crate::model::{Neo4jGraph, Neo4jNode, Neo4jRelation};
+use crate::types::{GraphNode, GraphRelationship, Provenance};
+use crate::utils::{
+    constants::{EMBEDDED_LABEL, EMBEDDING_INDEX, SEARCH_CANDIDATE_FACTOR, SEARCH_OWNER_INDEX},
+    llm::{Provider, EMBEDDING_DIMENSIONS},
+};
 
 #[derive(Clone)]
 pub struct AppState {
     pub pool: PgPool,
     pub graph: Graph,
-    pub openai_client: Client<OpenAIConfig>,
+    pub llm: Provider,
+    pub auth: AuthConfig,
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct AppEnv {
     pub database_url: String,
-    pub openai_api_key: String,
+    pub openai_api_key: Option<String>,
     pub neo4j_uri: String,
     pub neo4j_password: String,
+    pub jwt_secret: Option<String>,
+    pub jwt_jwks_path: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    pub allow_user_id_header: bool,
+    pub job_workers: usize,
+    pub llm_provider: String,
+    pub llm_base_url: Option<String>,
+    pub llm_chat_model: Option<String>,
+    pub llm_tool_model: Option<String>,
+    pub llm_embedding_model: Option<String>,
 }
 
 impl AppEnv {
@@ -43,37 +51,119 @@ impl AppEnv {
             database_url: secret_store
                 .get("DATABASE_URL")
                 .ok_or_else(|| anyhow::anyhow!("DATABASE_URL is not set"))?,
-            openai_api_key: secret_store
-                .get("OPENAI_API_KEY")
-                .ok_or_else(|| anyhow::anyhow!("OPENAI_API_KEY is not set"))?,
+            openai_api_key: secret_store.get("OPENAI_API_KEY"),
             neo4j_uri: secret_store
                 .get("NEO4J_URI")
                 .ok_or_else(|| anyhow::anyhow!("NEO4J_URI is not set"))?,
             neo4j_password: secret_store
                 .get("NEO4J_PASSWORD")
                 .ok_or_else(|| anyhow::anyhow!("NEO4J_PASSWORD is not set"))?,
+            jwt_secret: secret_store.get("JWT_SECRET"),
+            jwt_jwks_path: secret_store.get("JWT_JWKS_PATH"),
+            jwt_issuer: secret_store.get("JWT_ISSUER"),
+            jwt_audience: secret_store.get("JWT_AUDIENCE"),
+            // dev only: trust a raw `user-id` header
+            allow_user_id_header: secret_store
+                .get("AUTH_ALLOW_USER_ID_HEADER")
+                .is_some_and(|v| v == "true"),
+            job_workers: secret_store
+                .get("JOB_WORKERS")
+                .map(|v| v.parse::<usize>())
+                .transpose()
+                .map_err(|e| anyhow::anyhow!("JOB_WORKERS is not a number: {}", e))?
+                .unwrap_or(2),
+            // one of openai, openai_compatible or mock
+            llm_provider: secret_store
+                .get("LLM_PROVIDER")
+                .unwrap_or_else(|| String::from("openai")),
+            llm_base_url: secret_store.get("LLM_BASE_URL"),
+            llm_chat_model: secret_store.get("LLM_CHAT_MODEL"),
+            llm_tool_model: secret_store.get("LLM_TOOL_MODEL"),
+            llm_embedding_model: secret_store.get("LLM_EMBEDDING_MODEL"),
         })
     }
 }
 
 pub trait Parsable {
-    fn run_queries(&self, queries: Vec<String>) -> impl Future<Output = Result<(), Error>>;
+    fn run_queries(&self, queries: Vec<Query>) -> impl Future<Output = Result<(), Error>>;
     fn parse_query_result(&self, query: Query) -> impl Future<Output = Result<Neo4jGraph, Error>>;
     fn semantic_search(
         &self,
         user_id: &Uuid,
         search_query_embedding: Vec<f32>,
         threshold: f32,
+        top_k: usize,
+        labels: &[String],
     ) -> impl Future<Output = Result<Neo4jGraph, Error>>;
+    fn traverse(
+        &self,
+        user_id: &Uuid,
+        node_id: &str,
+        relationship_types: &[String],
+        max_depth: usize,
+    ) -> impl Future<Output = Result<Option<Neo4jGraph>, Error>>;
+    fn ensure_search_index(&self) -> impl Future<Output = Result<(), Error>>;
+    fn ensure_user_constraint(&self) -> impl Future<Output = Result<(), Error>>;
     fn get_full_graph(&self, user_id: &Uuid) -> impl Future<Output = Result<Neo4jGraph, Error>>;
+    fn get_subgraph(
+        &self,
+        user_id: &Uuid,
+        node_ids: &[String],
+    ) -> impl Future<Output = Result<Neo4jGraph, Error>>;
+    fn get_node(
+        &self,
+        user_id: &Uuid,
+        node_id: &str,
+    ) -> impl Future<Output = Result<Option<GraphNode>, Error>>;
+    fn get_node_embeddings(
+        &self,
+        user_id: &Uuid,
+    ) -> impl Future<Output = Result<HashMap<String, Vec<f32>>, Error>>;
+    fn get_node_provenance(
+        &self,
+        user_id: &Uuid,
+        node_id: &str,
+    ) -> impl Future<Output = Result<Option<Provenance>, Error>>;
+    fn get_relationship_provenance(
+        &self,
+        user_id: &Uuid,
+        relationship: &GraphRelationship,
+    ) -> impl Future<Output = Result<Option<Provenance>, Error>>;
+}
+
+// reads the first row of a query returning the provenance properties, None
+// when nothing matched
+async fn read_provenance(graph: &Graph, query: Query) -> Result<Option<Provenance>, Error> {
+    let mut result = graph.execute(query).await?;
+
+    let Some(record) = result.next().await? else {
+        return Ok(None);
+    };
+
+    let list = |key: &str| -> Result<Vec<String>, Error> {
+        Ok(record
+            .get::<Option<Vec<String>>>(key)
+            .map_err(Error::DeserializationError)?
+            .unwrap_or_default())
+    };
+    let text = |key: &str| -> Result<Option<String>, Error> {
+        record
+            .get::<Option<String>>(key)
+            .map_err(Error::DeserializationError)
+    };
+
+    Ok(Some(Provenance {
+        chat_ids: list("chat_ids")?,
+        message_ids: list("message_ids")?,
+        extracted_at: text("extracted_at")?,
+        extraction_model: text("extraction_model")?,
+    }))
 }
 
 impl Parsable for Graph {
-    async fn run_queries(&self, queries: Vec<String>) -> Result<(), Error> {
+    async fn run_queries(&self, queries: Vec<Query>) -> Result<(), Error> {
         let mut txn = self.start_txn().await?;
-        let _ = txn
-            .run_queries(queries.iter().map(|q| query(q)).collect::<Vec<Query>>())
-            .await?;
+        txn.run_queries(queries).await?;
 
         txn.commit().await?;
 
@@ -81,11 +171,11 @@ impl Parsable for Graph {
     }
 
     async fn get_full_graph(&self, user_id: &Uuid) -> Result<Neo4jGraph, Error> {
+        // nodes without relationships are part of the graph too
         let graph_query = query(
             r#"
-            MATCH (:User {user_id: $user_id})-[*]-(n)
-            WITH n
-            MATCH (n)-[r]-(m)
+            MATCH (n {owner_id: $user_id})
+            OPTIONAL MATCH (n)-[r]-(m {owner_id: $user_id})
             RETURN DISTINCT n, r as rel, m
             "#,
         )
@@ -96,72 +186,297 @@ impl Parsable for Graph {
         Ok(graph)
     }
 
-    async fn semantic_search(
-        &self,
-        user_id: &Uuid,
-        search_query_embedding: Vec<f32>,
-        threshold: f32,
-    ) -> Result<Neo4jGraph, Error> {
+    // the given nodes with all their relationships, neighbours come along as
+    // the other ends of those
+    async fn get_subgraph(&self, user_id: &Uuid, node_ids: &[String]) -> Result<Neo4jGraph, Error> {
         let graph_query = query(
             r#"
-            MATCH (u:User {user_id: $user_id})
-            MATCH (u)-[*]-(n)
-            WHERE n.embedding IS NOT NULL
-            WITH n, n.embedding as vec1, $embedding as vec2
-            WITH n, vec1, vec2,
-                reduce(dot = 0.0, i IN range(0, size(vec1)-1) | dot + vec1[i] * vec2[i]) AS dotProduct,
-                sqrt(reduce(norm1 = 0.0, i IN range(0, size(vec1)-1) | norm1 + vec1[i] * vec1[i])) AS norm1,
-                sqrt(reduce(norm2 = 0.0, i IN range(0, size(vec2)-1) | norm2 + vec2[i] * vec2[i])) AS norm2
-            WITH n, dotProduct / (norm1 * norm2) AS score
-            WHERE score > $threshold
-            MATCH (n)-[r]-(m)
-            RETURN DISTINCT n, r as rel, m, score
-            ORDER BY score DESC
+            MATCH (n {owner_id: $user_id})
+            WHERE n.id IN $ids
+            OPTIONAL MATCH (n)-[r]-(m {owner_id: $user_id})
+            RETURN DISTINCT n, r as rel, m
             "#,
         )
         .param("user_id", user_id.to_string())
-        .param("embedding", search_query_embedding)
-        .param("threshold", threshold);
+        .param("ids", node_ids.to_vec());
 
         let graph = self.parse_query_result(graph_query).await?;
 
         Ok(graph)
     }
 
+    async fn get_node(&self, user_id: &Uuid, node_id: &str) -> Result<Option<GraphNode>, Error> {
+        let mut result = self
+            .execute(
+                query("MATCH (n {id: $id, owner_id: $user_id}) RETURN n LIMIT 1")
+                    .param("user_id", user_id.to_string())
+                    .param("id", node_id),
+            )
+            .await?;
+
+        let Some(record) = result.next().await? else {
+            return Ok(None);
+        };
+        let node: Node = record.get("n").map_err(Error::DeserializationError)?;
+        let node: Neo4jNode = node.try_into()?;
+
+        Ok(Some(node.into()))
+    }
+
+    async fn get_node_embeddings(
+        &self,
+        user_id: &Uuid,
+    ) -> Result<HashMap<String, Vec<f32>>, Error> {
+        let mut result = self
+            .execute(
+                query(
+                    r#"
+                    MATCH (n {owner_id: $user_id})
+                    WHERE n.embedding IS NOT NULL
+                    RETURN n.id as id, n.embedding as embedding
+                    "#,
+                )
+                .param("user_id", user_id.to_string()),
+            )
+            .await?;
+
+        let mut embeddings: HashMap<String, Vec<f32>> = HashMap::new();
+        while let Some(record) = result.next().await? {
+            let id: String = record.get("id").map_err(Error::DeserializationError)?;
+            let embedding: Vec<f64> = record
+                .get("embedding")
+                .map_err(Error::DeserializationError)?;
+
+            embeddings.insert(id, embedding.into_iter().map(|v| v as f32).collect());
+        }
+
+        Ok(embeddings)
+    }
+
+    async fn get_node_provenance(
+        &self,
+        user_id: &Uuid,
+        node_id: &str,
+    ) -> Result<Option<Provenance>, Error> {
+        read_provenance(
+            self,
+            query(
+                r#"
+                MATCH (n {id: $id, owner_id: $user_id})
+                RETURN n.source_chat_ids as chat_ids, n.source_message_ids as message_ids,
+                    n.extracted_at as extracted_at, n.extraction_model as extraction_model
+                LIMIT 1
+                "#,
+            )
+            .param("user_id", user_id.to_string())
+            .param("id", node_id),
+        )
+        .await
+    }
+
+    async fn get_relationship_provenance(
+        &self,
+        user_id: &Uuid,
+        relationship: &GraphRelationship,
+    ) -> Result<Option<Provenance>, Error> {
+        read_provenance(
+            self,
+            query(
+                r#"
+                MATCH (n {id: $source_id, owner_id: $user_id})-[r]->(m {id: $target_id, owner_id: $user_id})
+                WHERE type(r) = $label
+                RETURN r.source_chat_ids as chat_ids, r.source_message_ids as message_ids,
+                    r.extracted_at as extracted_at, r.extraction_model as extraction_model
+                LIMIT 1
+                "#,
+            )
+            .param("user_id", user_id.to_string())
+            .param("source_id", relationship.source_id.clone())
+            .param("target_id", relationship.target_id.clone())
+            .param("label", relationship.label.clone()),
+        )
+        .await
+    }
+
+    async fn ensure_search_index(&self) -> Result<(), Error> {
+        self.run(query(&format!(
+            r#"
+            CREATE VECTOR INDEX {} IF NOT EXISTS
+            FOR (n:{}) ON (n.embedding)
+            OPTIONS {{indexConfig: {{
+                `vector.dimensions`: {},
+                `vector.similarity_function`: 'cosine'
+            }}}}
+            "#,
+            EMBEDDING_INDEX, EMBEDDED_LABEL, EMBEDDING_DIMENSIONS
+        )))
+        .await?;
+
+        // backs the owner scoped fallback of semantic_search
+        self.run(query(&format!(
+            "CREATE INDEX {} IF NOT EXISTS FOR (n:{}) ON (n.owner_id)",
+            SEARCH_OWNER_INDEX, EMBEDDED_LABEL
+        )))
+        .await?;
+
+        // nodes written before the label existed only carry their schema label
+        self.run(query(&format!(
+            "MATCH (n) WHERE n.embedding IS NOT NULL AND NOT n:{0} SET n:{0}",
+            EMBEDDED_LABEL
+        )))
+        .await?;
+
+        Ok(())
+    }
+
+    async fn traverse(
+        &self,
+        user_id: &Uuid,
+        node_id: &str,
+        relationship_types: &[String],
+        max_depth: usize,
+    ) -> Result<Option<Neo4jGraph>, Error> {
+        // path bounds can't be parameters, the depth is validated by the caller.
+        // the start node gets a row of its own so it is part of the result even
+        // without matching relationships
+        let graph_query = query(&format!(
+            r#"
+            MATCH (s {{id: $id, owner_id: $user_id}})
+            RETURN s as n, null as rel, null as m
+            UNION
+            MATCH p = (s {{id: $id, owner_id: $user_id}})-[*1..{}]-()
+            WHERE all(x IN nodes(p) WHERE x.owner_id = $user_id)
+                AND all(r IN relationships(p) WHERE size($types) = 0 OR type(r) IN $types)
+            UNWIND relationships(p) AS rel
+            WITH DISTINCT rel
+            RETURN startNode(rel) as n, rel, endNode(rel) as m
+            "#,
+            max_depth
+        ))
+        .param("id", node_id)
+        .param("user_id", user_id.to_string())
+        .param("types", relationship_types.to_vec());
+
+        let graph = self.parse_query_result(graph_query).await?;
+        if graph.nodes.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(graph))
+    }
+
+    // graphs written before nodes carried an owner are backfilled once with
+    // scripts/backfill_owner_ids.sh, which also clears duplicate user nodes
+    // that would make this fail
+    async fn ensure_user_constraint(&self) -> Result<(), Error> {
+        self.run(query(
+            "CREATE CONSTRAINT user_user_id_unique IF NOT EXISTS FOR (u:User) REQUIRE u.user_id IS UNIQUE",
+        ))
+        .await?;
+
+        Ok(())
+    }
+
+    async fn semantic_search(
+        &self,
+        user_id: &Uuid,
+        search_query_embedding: Vec<f32>,
+        threshold: f32,
+        top_k: usize,
+        labels: &[String],
+    ) -> Result<Neo4jGraph, Error> {
+        // The vector index is shared by all users, so it is asked for more
+        // candidates than needed before filtering down to the user's own
+        // nodes. neo4j reports cosine scores as (1 + cos) / 2, they are mapped
+        // back to the plain cosine similarity.
+        let search_query = |candidates: &str| {
+            query(&format!(
+                r#"
+                {}
+                WITH n, score
+                WHERE score > $threshold
+                    AND (size($labels) = 0 OR any(label IN labels(n) WHERE label IN $labels))
+                WITH n, score
+                ORDER BY score DESC
+                LIMIT $top_k
+                OPTIONAL MATCH (n)-[r]-(m {{owner_id: $user_id}})
+                RETURN DISTINCT n, r as rel, m, 2 * score - 1 as score
+                ORDER BY score DESC
+                "#,
+                candidates
+            ))
+            .param("top_k", top_k as i64)
+            .param("user_id", user_id.to_string())
+            .param("embedding", search_query_embedding.clone())
+            .param("threshold", (1.0 + threshold) / 2.0)
+            .param("labels", labels.to_vec())
+        };
+
+        let graph = self
+            .parse_query_result(
+                search_query(
+                    r#"
+                    CALL db.index.vector.queryNodes($index, $candidates, $embedding)
+                    YIELD node AS n, score
+                    WHERE n.owner_id = $user_id
+                    "#,
+                )
+                .param("index", EMBEDDING_INDEX)
+                .param("candidates", (top_k * SEARCH_CANDIDATE_FACTOR) as i64),
+            )
+            .await?;
+        if graph.scores.len() >= top_k {
+            return Ok(graph);
+        }
+
+        // other users' nodes took up the candidates, or the user simply has
+        // fewer matches, only an exact scan over their own nodes can tell
+        let graph = self
+            .parse_query_result(search_query(&format!(
+                r#"
+                MATCH (n:{} {{owner_id: $user_id}})
+                WITH n, vector.similarity.cosine(n.embedding, $embedding) AS score
+                "#,
+                EMBEDDED_LABEL
+            )))
+            .await?;
+
+        Ok(graph)
+    }
+
     async fn parse_query_result(&self, query: Query) -> Result<Neo4jGraph, Error> {
         let mut result = self.execute(query).await?;
 
         let mut entities: HashMap<i64, Neo4jNode> = HashMap::new();
         let mut relations: HashSet<Neo4jRelation> = HashSet::new();
 
+        let mut scores: HashMap<i64, f32> = HashMap::new();
+
         let mut count = 0;
         while let Some(record) = result.next().await? {
-            // let score = record.get::<f32>("score").map_err(|e| Error::DeserializationError(e))?;
-            // info!("score: {:?}", score);
-            let src_node: Node = record
-                .get("n")
-                .map_err(|e| Error::DeserializationError(e))?;
-            let dst_node: Node = record
-                .get("m")
-                .map_err(|e| Error::DeserializationError(e))?;
-            let relation: Neo4jRelation = record
-                .get("rel")
-                .map_err(|e| Error::DeserializationError(e))?;
+            let src_node: Node = record.get("n").map_err(Error::DeserializationError)?;
+            // optional matches leave the other end empty for unconnected nodes
+            let dst_node: Option<Node> = record.get("m").map_err(Error::DeserializationError)?;
+            let relation: Option<Neo4jRelation> =
+                record.get("rel").map_err(Error::DeserializationError)?;
 
             let src_id = src_node.id();
-            let dst_id = dst_node.id();
-
             let src_entity: Neo4jNode = src_node.clone().try_into()?;
-            let dst_entity: Neo4jNode = dst_node.clone().try_into()?;
+            entities.entry(src_id).or_insert_with(|| src_entity.clone());
 
-            if !entities.contains_key(&src_id) {
-                entities.insert(src_id, src_entity.clone());
+            if let Some(dst_node) = dst_node {
+                let dst_entity: Neo4jNode = dst_node.clone().try_into()?;
+                entities
+                    .entry(dst_node.id())
+                    .or_insert_with(|| dst_entity.clone());
             }
-            if !entities.contains_key(&dst_id) {
-                entities.insert(dst_id, dst_entity.clone());
+
+            // only similarity queries return a score, it belongs to the matched node
+            if let Ok(score) = record.get::<f64>("score") {
+                scores.insert(src_id, score as f32);
             }
 
-            relations.insert(relation);
+            relations.extend(relation);
             count += 1;
         }
 
@@ -175,88 +490,7 @@ impl Parsable for Graph {
         Ok(Neo4jGraph {
             nodes: entities,
             relations: relations.into_iter().collect(),
+            scores,
         })
     }
 }
-
-pub trait Convinience {
-    fn get_embedding(
-        &self,
-        content: String,
-    ) -> impl Future<Output = Result<Vec<f32>, anyhow::Error>>;
-    fn get_tool_response(
-        &self,
-        prompt: String,
-    ) -> impl Future<Output = Result<String, anyhow::Error>>;
-    fn get_data_from_message_request(
-        &self,
-        message: ChatCompletionRequestMessage,
-    ) -> Result<(String, String), anyhow::Error>;
-}
-
-impl Convinience for Client<OpenAIConfig> {
-    async fn get_embedding(&self, content: String) -> Result<Vec<f32>, anyhow::Error> {
-        let request = CreateEmbeddingRequestArgs::default()
-            .model("text-embedding-3-small")
-            .dimensions(384 as u32)
-            .input(content.clone())
-            .build()?;
-
-        let embedding = self
-            .embeddings()
-            .create(request)
-            .await?
-            .data
-            .first()
-            .ok_or(anyhow::anyhow!("Error creating embedding"))?
-            .embedding
-            .clone();
-
-        Ok(embedding)
-    }
-
-    async fn get_tool_response(&self, prompt: String) -> Result<String, anyhow::Error> {
-        let request = CreateChatCompletionRequestArgs::default()
-            .model("gpt-4o")
-            .response_format(ChatCompletionResponseFormat {
-                r#type: ChatCompletionResponseFormatType::JsonObject,
-            })
-            .messages(vec![ChatCompletionRequestMessage::System(
-                ChatCompletionRequestSystemMessageArgs::default()
-                    .content(prompt)
-                    .build()?,
-            )])
-            .build()?;
-
-        let response = self.chat().create(request).await?;
-        let content = response.choices[0]
-            .message
-            .content
-            .clone()
-            .ok_or(anyhow::anyhow!("No content in AI response"))?;
-
-        Ok(content)
-    }
-
-    fn get_data_from_message_request(
-        &self,
-        message: ChatCompletionRequestMessage,
-    ) -> Result<(String, String), anyhow::Error> {
-        match message {
-            ChatCompletionRequestMessage::System(system) => {
-                Ok((String::from("system"), system.content))
-            }
-            ChatCompletionRequestMessage::User(user) => match user.content {
-                ChatCompletionRequestUserMessageContent::Text(text) => {
-                    Ok((String::from("user"), text))
-                }
-                _ => Err(anyhow::anyhow!("Only text content messages are supported")),
-            },
-            ChatCompletionRequestMessage::Assistant(assistant) => match assistant.content {
-                Some(text) => Ok((String::from("assistant"), text)),
-                None => Err(anyhow::anyhow!("Assistant message content is missing")),
-            },
-            _ => Err(anyhow::anyhow!("Unsupported message type")),
-        }
-    }
-}
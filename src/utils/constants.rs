@@ -1,3 +1,14 @@
+// every node that carries an embedding also gets this label so a single
+// vector index covers all of them
+pub const EMBEDDED_LABEL: &str = "Embedded";
+pub const EMBEDDING_INDEX: &str = "node_embeddings";
+pub const SEARCH_OWNER_INDEX: &str = "embedded_owner";
+pub const SEARCH_CANDIDATE_FACTOR: usize = 10;
+pub const SEARCH_TOP_K: usize = 10;
+pub const TRAVERSAL_DEFAULT_DEPTH: usize = 2;
+pub const TRAVERSAL_MAX_DEPTH: usize = 4;
+pub const DEDUP_LABELS: [&str; 5] = ["Interest", "Goal", "Motivation", "Task", "Date"];
+
 pub const NEO4J_SCHEMA_DEFINITION: &str = r##"{
   "type": "object",
   "properties": {
@@ -258,6 +269,16 @@ pub const GRAPH_SCHEMA: &str = r##"{
               "medium-term",
               "long-term"
             ]
+          },
+          "progress": {
+            "nullable": true,
+            "type": "string",
+            "enum": [
+              "not-started",
+              "on-track",
+              "off-track",
+              "achieved"
+            ]
           }
         }
       },
@@ -344,8 +365,13 @@ pub const GRAPH_SCHEMA: &str = r##"{
         "label": "CREATED_ON",
         "source_node_type": "Task",
         "target_node_type": "Date"
+      },
+      {
+        "label": "COMPLETED_ON",
+        "source_node_type": "Task",
+        "target_node_type": "Date"
       }
-    ],      
+    ]
   }
 }"##;
 
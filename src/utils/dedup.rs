@@ -0,0 +1,297 @@
+use std::collections::{HashMap, HashSet};
+
+use neo4rs::{query, Query};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    types::{
+        node_pattern, ChangeSource, DedupGraphPayload, DedupSummary, GraphData, GraphNode,
+        GraphRelationship, ProposedMerge, Provenance, ScoredGraphNode,
+    },
+    utils::{
+        config::{AppState, Parsable},
+        graph::get_owned_graph,
+        history::{run_recorded, Scope},
+        merge::{cosine_similarity, normalized_key, refresh_embeddings, NodeUpdate},
+    },
+};
+
+struct Cluster<'a> {
+    survivor: &'a GraphNode,
+    duplicates: Vec<(&'a GraphNode, f32)>,
+}
+
+// Clusters are grown around a seed rather than by chaining similar pairs, so
+// "running" and "running marathons" can end up together but a chain of
+// slightly different nodes never collapses into one. Better connected nodes
+// are seeded first and survive the merge.
+fn cluster_nodes<'a>(
+    graph: &'a GraphData,
+    embeddings: &HashMap<String, Vec<f32>>,
+    labels: &[String],
+    threshold: f32,
+) -> Vec<Cluster<'a>> {
+    let mut degrees: HashMap<&str, usize> = HashMap::new();
+    for rel in &graph.relationships {
+        *degrees.entry(rel.source_id.as_str()).or_default() += 1;
+        *degrees.entry(rel.target_id.as_str()).or_default() += 1;
+    }
+
+    let mut candidates = graph
+        .nodes
+        .iter()
+        .filter(|node| labels.contains(&node.label))
+        .collect::<Vec<&GraphNode>>();
+    candidates.sort_by(|a, b| {
+        degrees
+            .get(b.id.as_str())
+            .cmp(&degrees.get(a.id.as_str()))
+            .then(a.id.cmp(&b.id))
+    });
+
+    let mut assigned: HashSet<&str> = HashSet::new();
+    let mut clusters = vec![];
+
+    for seed in &candidates {
+        if !assigned.insert(seed.id.as_str()) {
+            continue;
+        }
+
+        let key = normalized_key(seed);
+        let mut duplicates = vec![];
+
+        for node in &candidates {
+            if node.label != seed.label || assigned.contains(node.id.as_str()) {
+                continue;
+            }
+
+            let score = match (embeddings.get(&seed.id), embeddings.get(&node.id)) {
+                _ if key.is_some() && normalized_key(node) == key => 1.0,
+                // dates only ever match on their exact value
+                _ if seed.label == "Date" => continue,
+                (Some(a), Some(b)) => cosine_similarity(a, b),
+                _ => continue,
+            };
+
+            if score >= threshold {
+                assigned.insert(node.id.as_str());
+                duplicates.push((*node, score));
+            }
+        }
+
+        if !duplicates.is_empty() {
+            clusters.push(Cluster {
+                survivor: seed,
+                duplicates,
+            });
+        }
+    }
+
+    clusters
+}
+
+// properties the survivor is missing are filled in from its duplicates, the
+// survivor's own values always win
+fn filled_properties(cluster: &Cluster) -> HashMap<String, serde_json::Value> {
+    let mut changed = HashMap::new();
+
+    for (duplicate, _) in &cluster.duplicates {
+        for (key, value) in &duplicate.properties {
+            let missing = cluster
+                .survivor
+                .properties
+                .get(key)
+                .is_none_or(|v| v.is_null());
+
+            if missing && !value.is_null() && !changed.contains_key(key) {
+                changed.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    changed
+}
+
+pub async fn dedup_graph(
+    app_state: &AppState,
+    user_id: &Uuid,
+    payload: DedupGraphPayload,
+) -> Result<DedupSummary, anyhow::Error> {
+    let owned = get_owned_graph(app_state, user_id).await?;
+    let embeddings = app_state.graph.get_node_embeddings(user_id).await?;
+
+    let clusters = cluster_nodes(&owned, &embeddings, &payload.labels, payload.threshold);
+
+    let survivor_of = clusters
+        .iter()
+        .flat_map(|cluster| {
+            cluster
+                .duplicates
+                .iter()
+                .map(|(duplicate, _)| (duplicate.id.clone(), cluster.survivor.id.clone()))
+        })
+        .collect::<HashMap<String, String>>();
+
+    let mut seen = owned
+        .relationships
+        .iter()
+        .filter(|rel| !survivor_of.contains_key(&rel.source_id))
+        .filter(|rel| !survivor_of.contains_key(&rel.target_id))
+        .map(|rel| {
+            (
+                rel.source_id.clone(),
+                rel.label.clone(),
+                rel.target_id.clone(),
+            )
+        })
+        .collect::<HashSet<(String, String, String)>>();
+
+    // relationships of a duplicate are recreated on its survivor unless the
+    // survivor already has them, the originals go with the duplicate. Every
+    // original hands its properties to the relationship that replaces it.
+    let mut rewired: Vec<GraphRelationship> = vec![];
+    let mut rewired_per_survivor: HashMap<String, usize> = HashMap::new();
+    let mut rewire_queries: Vec<Query> = vec![];
+    for old in &owned.relationships {
+        if !survivor_of.contains_key(&old.source_id) && !survivor_of.contains_key(&old.target_id) {
+            continue;
+        }
+
+        let rel = GraphRelationship {
+            source_id: survivor_of
+                .get(&old.source_id)
+                .unwrap_or(&old.source_id)
+                .clone(),
+            target_id: survivor_of
+                .get(&old.target_id)
+                .unwrap_or(&old.target_id)
+                .clone(),
+            label: old.label.clone(),
+        };
+
+        if rel.source_id == rel.target_id {
+            continue;
+        }
+        rewire_queries.push(old.rewire_query(&rel, user_id)?);
+
+        if !seen.insert((
+            rel.source_id.clone(),
+            rel.label.clone(),
+            rel.target_id.clone(),
+        )) {
+            continue;
+        }
+
+        for id in [&rel.source_id, &rel.target_id] {
+            if clusters.iter().any(|c| &c.survivor.id == id) {
+                *rewired_per_survivor.entry(id.clone()).or_default() += 1;
+            }
+        }
+        rewired.push(rel);
+    }
+
+    let mut updates = clusters
+        .iter()
+        .filter_map(|cluster| {
+            let changed = filled_properties(cluster);
+            if changed.is_empty() {
+                return None;
+            }
+
+            Some(NodeUpdate::new(cluster.survivor, changed))
+        })
+        .collect::<Vec<NodeUpdate>>();
+
+    let merges = clusters
+        .iter()
+        .map(|cluster| ProposedMerge {
+            label: cluster.survivor.label.clone(),
+            survivor: updates
+                .iter()
+                .find(|update| update.node.id == cluster.survivor.id)
+                .map(|update| update.node.clone())
+                .unwrap_or_else(|| cluster.survivor.clone()),
+            duplicates: cluster
+                .duplicates
+                .iter()
+                .map(|(node, score)| ScoredGraphNode {
+                    node: (*node).clone(),
+                    score: Some(*score),
+                })
+                .collect(),
+            relationships_rewired: rewired_per_survivor
+                .get(&cluster.survivor.id)
+                .copied()
+                .unwrap_or(0),
+        })
+        .collect::<Vec<ProposedMerge>>();
+
+    let summary = DedupSummary {
+        dry_run: payload.dry_run,
+        nodes_scanned: owned
+            .nodes
+            .iter()
+            .filter(|node| payload.labels.contains(&node.label))
+            .count(),
+        nodes_removed: survivor_of.len(),
+        relationships_rewired: rewired.len(),
+        merges,
+    };
+
+    if payload.dry_run || clusters.is_empty() {
+        return Ok(summary);
+    }
+
+    info!(
+        "Merging {} duplicate nodes into {} survivors.",
+        survivor_of.len(),
+        clusters.len()
+    );
+
+    let survivors = clusters
+        .iter()
+        .map(|cluster| (cluster.survivor.id.as_str(), cluster.survivor))
+        .collect::<HashMap<&str, &GraphNode>>();
+    refresh_embeddings(&app_state.llm, &survivors, &mut updates).await?;
+
+    let delete_queries = clusters.iter().flat_map(|cluster| {
+        cluster.duplicates.iter().flat_map(|(duplicate, _)| {
+            [
+                Provenance::absorb_query(
+                    &duplicate.label,
+                    &cluster.survivor.id,
+                    &duplicate.id,
+                    user_id,
+                ),
+                query(&format!(
+                    "MATCH {} DETACH DELETE n",
+                    node_pattern("n", &duplicate.label, "id")
+                ))
+                .param("id", duplicate.id.clone())
+                .param("user_id", user_id.to_string()),
+            ]
+        })
+    });
+
+    let update_queries = updates
+        .iter()
+        .map(|update| update.query(user_id))
+        .collect::<Result<Vec<Query>, neo4rs::Error>>()?;
+
+    run_recorded(
+        app_state,
+        user_id,
+        ChangeSource::Dedup,
+        None,
+        Scope::Graph,
+        rewire_queries
+            .into_iter()
+            .chain(update_queries)
+            .chain(delete_queries)
+            .collect::<Vec<Query>>(),
+    )
+    .await?;
+
+    Ok(summary)
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+
+use actix_web::{http::StatusCode, ResponseError};
+
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Internal(anyhow::Error),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message) => write!(f, "{}", message),
+            ApiError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// helpers return anyhow errors, so recover the original status when an
+// ApiError was raised further down the stack
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<ApiError>() {
+            Ok(api_error) => api_error,
+            Err(e) => ApiError::Internal(e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        ApiError::Internal(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_codes() {
+        let cases = [
+            (ApiError::BadRequest(String::new()), StatusCode::BAD_REQUEST),
+            (
+                ApiError::Unauthorized(String::new()),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (ApiError::Forbidden(String::new()), StatusCode::FORBIDDEN),
+            (ApiError::NotFound(String::new()), StatusCode::NOT_FOUND),
+            (
+                ApiError::Internal(anyhow::anyhow!("boom")),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+
+        for (error, status) in cases {
+            assert_eq!(error.status_code(), status, "{:?}", error);
+            assert_eq!(error.error_response().status(), status);
+        }
+    }
+
+    #[test]
+    fn api_errors_survive_anyhow() {
+        let forbidden: anyhow::Error = ApiError::Forbidden(String::from("not yours")).into();
+        assert_eq!(
+            ApiError::from(forbidden).status_code(),
+            StatusCode::FORBIDDEN
+        );
+
+        let not_found: anyhow::Error = ApiError::NotFound(String::from("gone")).into();
+        assert_eq!(
+            ApiError::from(not_found).status_code(),
+            StatusCode::NOT_FOUND
+        );
+
+        let other = anyhow::anyhow!("connection reset");
+        assert_eq!(
+            ApiError::from(other).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::Utc;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    types::{ExportFormat, GraphData, GraphNode, GraphSchema, PropertyType},
+    utils::merge::key_properties,
+};
+
+pub fn export_graph(
+    graph: &GraphData,
+    user_id: &Uuid,
+    format: ExportFormat,
+) -> Result<String, anyhow::Error> {
+    let mut graph = graph.clone();
+    // sorted so exports of the same graph are identical and diff cleanly
+    graph
+        .nodes
+        .sort_by(|a, b| (&a.label, &a.id).cmp(&(&b.label, &b.id)));
+    graph.relationships.sort_by(|a, b| {
+        (&a.source_id, &a.label, &a.target_id).cmp(&(&b.source_id, &b.label, &b.target_id))
+    });
+
+    let export = match format {
+        ExportFormat::Json => serde_json::to_string_pretty(&graph)?,
+        ExportFormat::Graphml => to_graphml(&graph),
+        ExportFormat::Cypher => to_cypher(&graph, user_id)?,
+        ExportFormat::Dot => to_dot(&graph),
+    };
+
+    Ok(export)
+}
+
+// what a node is shown as in tools that only display a single label
+fn display_name(node: &GraphNode) -> String {
+    let parts = key_properties(&node.label)
+        .iter()
+        .filter_map(|key| match node.properties.get(*key)? {
+            Value::String(s) => Some(s.clone()),
+            Value::Null => None,
+            value => Some(value.to_string()),
+        })
+        .collect::<Vec<String>>();
+
+    match parts.is_empty() {
+        true => node.label.clone(),
+        false => format!("{}: {}", node.label, parts.join("/")),
+    }
+}
+
+fn property_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn to_graphml(graph: &GraphData) -> String {
+    let schema = GraphSchema::get();
+
+    let property_keys = graph
+        .nodes
+        .iter()
+        .flat_map(|node| node.properties.keys().cloned())
+        .collect::<BTreeSet<String>>();
+
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+    );
+    out.push_str("  <key id=\"label\" for=\"node\" attr.name=\"label\" attr.type=\"string\"/>\n");
+    out.push_str("  <key id=\"type\" for=\"node\" attr.name=\"type\" attr.type=\"string\"/>\n");
+    for key in &property_keys {
+        let attr_type = schema
+            .node_types
+            .iter()
+            .find_map(|node_type| node_type.properties.get(key))
+            .map(|definition| match definition.property_type {
+                PropertyType::String => "string",
+                PropertyType::Integer => "long",
+                PropertyType::Float => "double",
+                PropertyType::Boolean => "boolean",
+            })
+            .unwrap_or("string");
+
+        out.push_str(&format!(
+            "  <key id=\"p_{0}\" for=\"node\" attr.name=\"{0}\" attr.type=\"{1}\"/>\n",
+            xml_escape(key),
+            attr_type
+        ));
+    }
+    out.push_str("  <key id=\"e_label\" for=\"edge\" attr.name=\"label\" attr.type=\"string\"/>\n");
+    out.push_str("  <graph id=\"knowledge_graph\" edgedefault=\"directed\">\n");
+
+    for node in &graph.nodes {
+        out.push_str(&format!("    <node id=\"{}\">\n", xml_escape(&node.id)));
+        out.push_str(&format!(
+            "      <data key=\"label\">{}</data>\n",
+            xml_escape(&display_name(node))
+        ));
+        out.push_str(&format!(
+            "      <data key=\"type\">{}</data>\n",
+            xml_escape(&node.label)
+        ));
+
+        let properties = node
+            .properties
+            .iter()
+            .collect::<BTreeMap<&String, &Value>>();
+        for (key, value) in properties {
+            if let Some(text) = property_text(value) {
+                out.push_str(&format!(
+                    "      <data key=\"p_{}\">{}</data>\n",
+                    xml_escape(key),
+                    xml_escape(&text)
+                ));
+            }
+        }
+        out.push_str("    </node>\n");
+    }
+
+    for (i, rel) in graph.relationships.iter().enumerate() {
+        out.push_str(&format!(
+            "    <edge id=\"e{}\" source=\"{}\" target=\"{}\">\n      <data key=\"e_label\">{}</data>\n    </edge>\n",
+            i,
+            xml_escape(&rel.source_id),
+            xml_escape(&rel.target_id),
+            xml_escape(&rel.label)
+        ));
+    }
+
+    out.push_str("  </graph>\n</graphml>\n");
+    out
+}
+
+fn cypher_string(text: &str) -> String {
+    format!(
+        "'{}'",
+        text.replace('\\', "\\\\")
+            .replace('\'', "\\'")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r")
+    )
+}
+
+// json values written as cypher literals, map keys are always quoted with
+// backticks since property names aren't guaranteed to be identifiers
+fn cypher_literal(value: &Value) -> String {
+    match value {
+        Value::Null => String::from("null"),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => cypher_string(s),
+        Value::Array(items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .map(cypher_literal)
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Value::Object(map) => format!(
+            "{{{}}}",
+            map.iter()
+                .map(|(k, v)| format!("`{}`: {}", k.replace('`', "``"), cypher_literal(v)))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    }
+}
+
+// Writes a script for cypher-shell that recreates the graph from parameters,
+// one statement per label and relationship type since neither can be
+// parameterized. The user node is merged on the importing user's id.
+fn to_cypher(graph: &GraphData, user_id: &Uuid) -> Result<String, serde_json::Error> {
+    let schema = GraphSchema::get();
+
+    let nodes = Value::Array(
+        graph
+            .nodes
+            .iter()
+            .map(|node| {
+                let properties = node
+                    .properties
+                    .iter()
+                    .filter(|(_, value)| !value.is_null())
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect::<serde_json::Map<String, Value>>();
+
+                serde_json::json!({
+                    "id": node.id,
+                    "label": node.label,
+                    "props": properties,
+                })
+            })
+            .collect(),
+    );
+    let relationships = serde_json::to_value(&graph.relationships)?;
+
+    let mut out = format!(
+        "// knowledge graph of user {}, exported {}\n// replay with cypher-shell -f, embeddings are not part of the export\n\n",
+        user_id,
+        Utc::now().to_rfc3339()
+    );
+    out.push_str(&format!(
+        ":param user_id => {}\n",
+        cypher_string(&user_id.to_string())
+    ));
+    out.push_str(&format!(":param nodes => {}\n", cypher_literal(&nodes)));
+    out.push_str(&format!(
+        ":param relationships => {}\n\n",
+        cypher_literal(&relationships)
+    ));
+
+    let labels = graph
+        .nodes
+        .iter()
+        .map(|node| node.label.as_str())
+        .collect::<BTreeSet<&str>>();
+    for label in labels {
+        let statement = match label {
+            "User" => String::from(
+                "UNWIND [node IN $nodes WHERE node.label = 'User'] AS node\nMERGE (n:User {user_id: $user_id}) ON CREATE SET n.id = node.id, n.owner_id = $user_id;\n",
+            ),
+            label => format!(
+                "UNWIND [node IN $nodes WHERE node.label = '{0}'] AS node\nCREATE (n:{0}) SET n = node.props, n.id = node.id, n.owner_id = $user_id;\n",
+                label
+            ),
+        };
+        out.push_str(&statement);
+    }
+
+    let endpoint = |var: &str, id: &str, label: Option<&str>| match label {
+        Some("User") => format!("({}:User {{user_id: $user_id}})", var),
+        Some(label) => format!("({}:{} {{id: rel.{}, owner_id: $user_id}})", var, label, id),
+        None => format!("({} {{id: rel.{}, owner_id: $user_id}})", var, id),
+    };
+
+    let rel_labels = graph
+        .relationships
+        .iter()
+        .map(|rel| rel.label.as_str())
+        .collect::<BTreeSet<&str>>();
+    for label in rel_labels {
+        let rel_type = schema.relationship_type(label);
+
+        out.push_str(&format!(
+            "UNWIND [rel IN $relationships WHERE rel.label = '{}'] AS rel\nMATCH {}, {}\nCREATE (n)-[:{}]->(m);\n",
+            label,
+            endpoint(
+                "n",
+                "source",
+                rel_type.map(|r| r.source_node_type.as_str())
+            ),
+            endpoint(
+                "m",
+                "target",
+                rel_type.map(|r| r.target_node_type.as_str())
+            ),
+            label
+        ));
+    }
+
+    Ok(out)
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn to_dot(graph: &GraphData) -> String {
+    let mut out = String::from("digraph knowledge_graph {\n  node [shape=box];\n");
+
+    for node in &graph.nodes {
+        out.push_str(&format!(
+            "  \"{}\" [label=\"{}\"];\n",
+            dot_escape(&node.id),
+            dot_escape(&display_name(node))
+        ));
+    }
+
+    for rel in &graph.relationships {
+        out.push_str(&format!(
+            "  \"{}\" -> \"{}\" [label=\"{}\"];\n",
+            dot_escape(&rel.source_id),
+            dot_escape(&rel.target_id),
+            dot_escape(&rel.label)
+        ));
+    }
+
+    out.push_str("}\n");
+    out
+}
@@ -1,22 +1,94 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use tracing::info;
+use neo4rs::query;
+use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::model::Message;
-use crate::types::{CypherQueries, GraphData, ToolPrompts};
-use crate::utils::config::Convinience;
+use crate::model::{Chat, Message};
+use crate::types::{
+    node_pattern, ChangeSource, CreateNodeRequest, CypherQueries, ExtractionSummary, GraphData,
+    GraphNode, GraphRelationship, GraphSchema, ToolPrompts, TraversalQuery, UpdateNodeRequest,
+    ValidationReport,
+};
+use crate::utils::llm::LlmProvider;
 use crate::utils::{
     config::{AppState, Parsable},
-    constants::{GRAPH_DATA_DEF, GRAPH_SCHEMA},
+    constants::{GRAPH_DATA_DEF, GRAPH_SCHEMA, TRAVERSAL_DEFAULT_DEPTH, TRAVERSAL_MAX_DEPTH},
+    error::ApiError,
+    history::{run_recorded, Scope},
+    merge::plan_merge,
+    provenance::provenance_queries,
 };
 
+const MAX_EXTRACTION_ATTEMPTS: usize = 3;
+
+// Asks the model for graph data and validates it against GRAPH_SCHEMA,
+// feeding the violations back into the prompt until the output is valid.
+async fn get_validated_graph_data(
+    app_state: &AppState,
+    prompt: String,
+    existing_nodes: &HashMap<String, String>,
+) -> Result<GraphData, anyhow::Error> {
+    let schema = GraphSchema::get();
+    let mut attempt_prompt = prompt.clone();
+    let mut errors: Vec<String> = vec![];
+
+    for attempt in 1..=MAX_EXTRACTION_ATTEMPTS {
+        let content = app_state
+            .llm
+            .get_tool_response(attempt_prompt.clone())
+            .await?;
+
+        errors = match serde_json::from_str::<GraphData>(&content) {
+            Ok(mut graph_data) => {
+                let report = schema.validate(&mut graph_data, existing_nodes);
+
+                for repair in &report.repairs {
+                    info!("{}", repair);
+                }
+
+                if report.is_valid() {
+                    return Ok(graph_data);
+                }
+
+                report.violations.iter().map(|v| v.to_string()).collect()
+            }
+            Err(e) => vec![format!("Response is not valid graph data JSON: {}", e)],
+        };
+
+        warn!(
+            "Graph data attempt {} failed validation with {} errors.",
+            attempt,
+            errors.len()
+        );
+
+        attempt_prompt = format!(
+            "{}\n{}",
+            prompt,
+            ToolPrompts::ValidationRetry
+                .prompt_template()
+                .replace("{previous_response}", &content)
+                .replace("{validation_errors}", &errors.join("\n"))
+        );
+    }
+
+    Err(anyhow::anyhow!(
+        "Graph data failed schema validation after {} attempts: {}",
+        MAX_EXTRACTION_ATTEMPTS,
+        errors.join("; ")
+    ))
+}
+
 pub async fn create_knowledge_from_chat(
     app_state: Arc<AppState>,
     user_id: Uuid,
     chat_id: Uuid,
-) -> Result<String, anyhow::Error> {
-    let messages = Message::get_all_messages_for_chat(&app_state.pool, chat_id.clone()).await?;
+) -> Result<ExtractionSummary, anyhow::Error> {
+    Chat::get_for_user(&app_state.pool, chat_id, user_id)
+        .await?
+        .check_active()?;
+
+    let messages = Message::get_all_messages_for_chat(&app_state.pool, chat_id).await?;
 
     let interview = messages
         .iter()
@@ -24,71 +96,468 @@ pub async fn create_knowledge_from_chat(
         .collect::<Vec<String>>()
         .join("\n");
 
-    let content = app_state
-        .openai_client
-        .get_tool_response(
-            ToolPrompts::ExtractEntities
-                .prompt_template()
-                .replace("{interview}", &interview)
-                .replace("{graph_schema}", GRAPH_SCHEMA)
-                .replace("{graph_data}", GRAPH_DATA_DEF)
-                .replace(
-                    "{date}",
-                    &chrono::Local::now().format("%B %d, %Y").to_string(),
-                ),
-        )
-        .await?;
+    let new_graph_data = get_validated_graph_data(
+        &app_state,
+        ToolPrompts::ExtractEntities
+            .prompt_template()
+            .replace("{interview}", &interview)
+            .replace("{graph_schema}", GRAPH_SCHEMA)
+            .replace("{graph_data}", GRAPH_DATA_DEF)
+            .replace(
+                "{date}",
+                &chrono::Local::now().format("%B %d, %Y").to_string(),
+            ),
+        &HashMap::new(),
+    )
+    .await?;
 
     info!("Generated AI response.");
 
-    let new_graph_data: GraphData = serde_json::from_str(&content)?;
     let old_graph_data: GraphData = app_state.graph.get_full_graph(&user_id).await?.try_into()?;
+    let old_embeddings = app_state.graph.get_node_embeddings(&user_id).await?;
+
+    let plan = plan_merge(
+        &app_state,
+        &old_graph_data,
+        &old_embeddings,
+        new_graph_data.clone(),
+    )
+    .await?;
+
+    info!(
+        "Merge plan: {} nodes to add, {} to update, {} already present.",
+        plan.add.nodes.len(),
+        plan.updates.len(),
+        plan.skipped_nodes.len()
+    );
+
+    let queries: CypherQueries = plan
+        .add
+        .clone()
+        .into_queries_with(&user_id, &app_state.llm, plan.embeddings.clone())
+        .await?;
+    let update_queries = plan.update_queries(&user_id)?;
+
+    info!(
+        "Generated {} Cypher queries.",
+        queries.queries.len() + update_queries.len()
+    );
+
+    let stored_ids = queries
+        .node_ids
+        .iter()
+        .chain(plan.matches.iter())
+        .map(|(extracted, stored)| (extracted.clone(), stored.clone()))
+        .collect::<HashMap<String, String>>();
+
+    let provenance_queries = provenance_queries(
+        &app_state,
+        &user_id,
+        chat_id,
+        &new_graph_data,
+        &stored_ids,
+        &plan.embeddings,
+        &old_embeddings,
+    )
+    .await?;
+
+    run_recorded(
+        &app_state,
+        &user_id,
+        ChangeSource::Extraction,
+        Some(chat_id),
+        Scope::Graph,
+        queries
+            .queries
+            .into_iter()
+            .chain(update_queries)
+            .chain(provenance_queries)
+            .collect(),
+    )
+    .await?;
+
+    info!("Knowledge graph created.");
+
+    Ok(ExtractionSummary::new(chat_id, &new_graph_data, &plan))
+}
+
+// placeholder ids for nodes that don't exist yet, mapped to real ids by
+// `into_queries`
+const NEW_NODE_ID: &str = "new_node";
+const NEW_USER_NODE_ID: &str = "new_user";
+
+// only nodes stamped with the user as their owner are ever returned
+pub async fn get_owned_graph(
+    app_state: &AppState,
+    user_id: &Uuid,
+) -> Result<GraphData, anyhow::Error> {
+    let graph: GraphData = app_state.graph.get_full_graph(user_id).await?.try_into()?;
+
+    Ok(graph)
+}
+
+async fn find_owned_node(
+    app_state: &AppState,
+    user_id: &Uuid,
+    node_id: &str,
+) -> Result<GraphNode, anyhow::Error> {
+    let node = app_state
+        .graph
+        .get_node(user_id, node_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("Node {} not found", node_id)))?;
+
+    Ok(node)
+}
+
+fn check_editable(node: &GraphNode) -> Result<(), ApiError> {
+    match node.label.as_str() {
+        "User" => Err(ApiError::BadRequest(
+            "User nodes are managed by the service".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub fn check_report(report: ValidationReport) -> Result<(), ApiError> {
+    match report.is_valid() {
+        true => Ok(()),
+        false => Err(ApiError::BadRequest(
+            report
+                .violations
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<String>>()
+                .join("; "),
+        )),
+    }
+}
+
+pub async fn create_node(
+    app_state: &AppState,
+    user_id: &Uuid,
+    request: CreateNodeRequest,
+) -> Result<GraphNode, anyhow::Error> {
+    let schema = GraphSchema::get();
+
+    let node = GraphNode {
+        id: NEW_NODE_ID.to_string(),
+        label: request.label,
+        properties: request.properties,
+    };
+    check_editable(&node)?;
 
-    let queries: CypherQueries = match (
-        old_graph_data.nodes.len(),
-        old_graph_data.relationships.len(),
-    ) {
-        (0, 0) => {
-            new_graph_data
-                .clone()
-                .into_queries(&user_id, &app_state.openai_client)
-                .await?
+    // a node has to hang off the user's graph to be reached by traversals
+    if request.connect.is_empty() {
+        return Err(ApiError::BadRequest(
+            "A node must be connected to at least one node of the user's graph".into(),
+        )
+        .into());
+    }
+
+    let owned = get_owned_graph(app_state, user_id).await?;
+    let existing_nodes = owned
+        .nodes
+        .iter()
+        .map(|n| (n.id.clone(), n.label.clone()))
+        .collect::<HashMap<String, String>>();
+    let user_node = owned.nodes.iter().find(|n| n.label == "User");
+
+    let mut graph_data = GraphData {
+        nodes: vec![node],
+        relationships: vec![],
+    };
+
+    for connection in request.connect {
+        let rel_type = schema
+            .relationship_type(&connection.relationship)
+            .ok_or_else(|| {
+                ApiError::BadRequest(format!(
+                    "Relationship type {} is not part of the graph schema",
+                    connection.relationship
+                ))
+            })?;
+
+        let label = &graph_data.nodes[0].label;
+        let (other_label, new_is_source) = if rel_type.source_node_type == *label {
+            (&rel_type.target_node_type, true)
+        } else if rel_type.target_node_type == *label {
+            (&rel_type.source_node_type, false)
+        } else {
+            return Err(ApiError::BadRequest(format!(
+                "Relationship {} cannot connect a {} node",
+                rel_type.label, label
+            ))
+            .into());
+        };
+
+        let other_id = match (connection.node_id, user_node) {
+            (Some(node_id), _) => node_id,
+            (None, Some(user_node)) if other_label == "User" => user_node.id.clone(),
+            (None, None) if other_label == "User" => {
+                if !graph_data.nodes.iter().any(|n| n.id == NEW_USER_NODE_ID) {
+                    graph_data.nodes.push(GraphNode {
+                        id: NEW_USER_NODE_ID.to_string(),
+                        label: String::from("User"),
+                        properties: HashMap::new(),
+                    });
+                }
+                NEW_USER_NODE_ID.to_string()
+            }
+            (None, _) => {
+                return Err(ApiError::BadRequest(format!(
+                    "node_id is required to connect through {}",
+                    rel_type.label
+                ))
+                .into())
+            }
+        };
+
+        let (source_id, target_id) = match new_is_source {
+            true => (NEW_NODE_ID.to_string(), other_id),
+            false => (other_id, NEW_NODE_ID.to_string()),
+        };
+
+        graph_data.relationships.push(GraphRelationship {
+            source_id,
+            target_id,
+            label: rel_type.label.clone(),
+        });
+    }
+
+    check_report(schema.validate(&mut graph_data, &existing_nodes))?;
+
+    let queries = graph_data
+        .clone()
+        .into_queries(user_id, &app_state.llm)
+        .await?;
+    run_recorded(
+        app_state,
+        user_id,
+        ChangeSource::Edit,
+        None,
+        Scope::written(&graph_data, &queries.node_ids),
+        queries.queries,
+    )
+    .await?;
+
+    let mut node = graph_data.nodes.swap_remove(0);
+    node.id = queries
+        .node_ids
+        .get(NEW_NODE_ID)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("Created node has no id"))?;
+
+    Ok(node)
+}
+
+pub async fn update_node(
+    app_state: &AppState,
+    user_id: &Uuid,
+    node_id: &str,
+    request: UpdateNodeRequest,
+) -> Result<GraphNode, anyhow::Error> {
+    let node = find_owned_node(app_state, user_id, node_id).await?;
+    check_editable(&node)?;
+
+    // a task's status follows its transitions, which also record completions
+    if node.label == "Task" && request.properties.contains_key("status") {
+        return Err(ApiError::BadRequest(format!(
+            "The status of a task is changed through PATCH /tasks/{}/status",
+            node_id
+        ))
+        .into());
+    }
+
+    let mut updated = node.clone();
+    updated.properties.extend(request.properties);
+
+    let mut graph_data = GraphData {
+        nodes: vec![updated],
+        relationships: vec![],
+    };
+    check_report(GraphSchema::get().validate(&mut graph_data, &HashMap::new()))?;
+    let updated = graph_data.nodes.swap_remove(0);
+
+    let mut props = updated.bolt_properties()?;
+    // nulls are sent so `SET +=` removes cleared properties
+    for key in node.properties.keys() {
+        if !updated.properties.contains_key(key) {
+            props.insert(key.clone(), neo4rs::BoltType::Null(neo4rs::BoltNull));
         }
-        _ => {
-            info!("Merging graphs.");
-
-            let content = app_state
-                .openai_client
-                .get_tool_response(
-                    ToolPrompts::MergeGraph
-                        .prompt_template()
-                        .replace("{graph_schema}", GRAPH_SCHEMA)
-                        .replace("{existing_graph}", &serde_json::to_string(&old_graph_data)?)
-                        .replace(
-                            "{new_graph}",
-                            &serde_json::to_string(&new_graph_data.clone())?,
-                        )
-                        .replace(
-                            "{date}",
-                            &chrono::Local::now().format("%B %d, %Y").to_string(),
-                        ),
-                )
-                .await?;
-
-            // info!("content: {}", content);
-
-            let graph_data: GraphData = serde_json::from_str(&content)?;
-            graph_data
-                .into_queries(&user_id, &app_state.openai_client)
-                .await?
+    }
+
+    let embedding_content = updated.embedding_content()?;
+    if embedding_content != node.embedding_content()? {
+        if let Some(content) = embedding_content {
+            let embedding = app_state.llm.get_embedding(content).await?;
+            props.insert("embedding".to_string(), embedding.into());
         }
+    }
+
+    run_recorded(
+        app_state,
+        user_id,
+        ChangeSource::Edit,
+        None,
+        Scope::Nodes(vec![node_id.to_string()]),
+        vec![query(&format!(
+            "MATCH {} SET n += $props",
+            node_pattern("n", &updated.label, "id")
+        ))
+        .param("id", node_id)
+        .param("user_id", user_id.to_string())
+        .param("props", props)],
+    )
+    .await?;
+
+    Ok(updated)
+}
+
+pub async fn delete_node(
+    app_state: &AppState,
+    user_id: &Uuid,
+    node_id: &str,
+) -> Result<GraphNode, anyhow::Error> {
+    let node = find_owned_node(app_state, user_id, node_id).await?;
+    check_editable(&node)?;
+
+    run_recorded(
+        app_state,
+        user_id,
+        ChangeSource::Edit,
+        None,
+        Scope::Nodes(vec![node_id.to_string()]),
+        vec![query(&format!(
+            "MATCH {} DETACH DELETE n",
+            node_pattern("n", &node.label, "id")
+        ))
+        .param("id", node_id)
+        .param("user_id", user_id.to_string())],
+    )
+    .await?;
+
+    Ok(node)
+}
+
+pub async fn create_relationship(
+    app_state: &AppState,
+    user_id: &Uuid,
+    relationship: GraphRelationship,
+) -> Result<GraphRelationship, anyhow::Error> {
+    let owned = get_owned_graph(app_state, user_id).await?;
+    let existing_nodes = owned
+        .nodes
+        .iter()
+        .map(|n| (n.id.clone(), n.label.clone()))
+        .collect::<HashMap<String, String>>();
+
+    let mut graph_data = GraphData {
+        nodes: vec![],
+        relationships: vec![relationship],
     };
+    check_report(GraphSchema::get().validate(&mut graph_data, &existing_nodes))?;
+    let relationship = graph_data.relationships[0].clone();
 
-    info!("Generated {} Cypher queries.", queries.queries.len());
+    if owned.relationships.iter().any(|r| {
+        r.source_id == relationship.source_id
+            && r.target_id == relationship.target_id
+            && r.label == relationship.label
+    }) {
+        return Err(ApiError::BadRequest("Relationship already exists".into()).into());
+    }
 
-    app_state.graph.run_queries(queries.queries).await?;
+    let queries = graph_data.into_queries(user_id, &app_state.llm).await?;
+    run_recorded(
+        app_state,
+        user_id,
+        ChangeSource::Edit,
+        None,
+        Scope::Nodes(vec![
+            relationship.source_id.clone(),
+            relationship.target_id.clone(),
+        ]),
+        queries.queries,
+    )
+    .await?;
 
-    info!("Knowledge graph created.");
+    Ok(relationship)
+}
+
+pub async fn delete_relationship(
+    app_state: &AppState,
+    user_id: &Uuid,
+    relationship: GraphRelationship,
+) -> Result<GraphRelationship, anyhow::Error> {
+    let owned = get_owned_graph(app_state, user_id).await?;
+
+    if !owned.relationships.iter().any(|r| {
+        r.source_id == relationship.source_id
+            && r.target_id == relationship.target_id
+            && r.label == relationship.label
+    }) {
+        return Err(ApiError::NotFound(format!(
+            "Relationship {} -[{}]-> {} not found",
+            relationship.source_id, relationship.label, relationship.target_id
+        ))
+        .into());
+    }
+
+    run_recorded(
+        app_state,
+        user_id,
+        ChangeSource::Edit,
+        None,
+        Scope::Nodes(vec![
+            relationship.source_id.clone(),
+            relationship.target_id.clone(),
+        ]),
+        vec![relationship.delete_query(user_id)?],
+    )
+    .await?;
+
+    Ok(relationship)
+}
+
+// the part of the user's graph within `depth` hops of a node, following only
+// the requested relationship types
+pub async fn get_subgraph(
+    app_state: &AppState,
+    user_id: &Uuid,
+    node_id: &str,
+    traversal: TraversalQuery,
+) -> Result<GraphData, anyhow::Error> {
+    let depth = traversal.depth.unwrap_or(TRAVERSAL_DEFAULT_DEPTH);
+    if !(1..=TRAVERSAL_MAX_DEPTH).contains(&depth) {
+        return Err(ApiError::BadRequest(format!(
+            "depth must be between 1 and {}",
+            TRAVERSAL_MAX_DEPTH
+        ))
+        .into());
+    }
+
+    let schema = GraphSchema::get();
+    let relationship_types = traversal
+        .relationships
+        .iter()
+        .flat_map(|types| types.split(','))
+        .map(|label| label.trim())
+        .filter(|label| !label.is_empty())
+        .map(|label| match schema.relationship_type(label) {
+            Some(rel_type) => Ok(rel_type.label.clone()),
+            None => Err(ApiError::BadRequest(format!(
+                "Relationship type {} is not part of the graph schema",
+                label
+            ))),
+        })
+        .collect::<Result<Vec<String>, ApiError>>()?;
+
+    let graph: GraphData = app_state
+        .graph
+        .traverse(user_id, node_id, &relationship_types, depth)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("Node {} not found", node_id)))?
+        .try_into()?;
 
-    Ok(content)
+    Ok(graph)
 }
@@ -0,0 +1,549 @@
+use std::collections::{HashMap, HashSet};
+
+use neo4rs::{query, BoltNull, BoltType, Query};
+use sqlx::{PgConnection, Postgres, Transaction};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    model::GraphChangeset,
+    types::{
+        node_pattern, ChangeSource, GraphChanges, GraphData, GraphNode, GraphRelationship,
+        NodeChange,
+    },
+    utils::{
+        config::{AppState, Parsable},
+        error::ApiError,
+        graph::get_owned_graph,
+        llm::LlmProvider,
+    },
+};
+
+type RelationshipKey = (String, String, String);
+
+fn relationship_key(rel: &GraphRelationship) -> RelationshipKey {
+    (
+        rel.source_id.clone(),
+        rel.label.clone(),
+        rel.target_id.clone(),
+    )
+}
+
+fn relationship_from_key((source_id, label, target_id): RelationshipKey) -> GraphRelationship {
+    GraphRelationship {
+        source_id,
+        target_id,
+        label,
+    }
+}
+
+fn sorted_nodes(nodes: HashMap<String, GraphNode>) -> Vec<GraphNode> {
+    let mut nodes = nodes.into_values().collect::<Vec<GraphNode>>();
+    nodes.sort_by(|a, b| a.id.cmp(&b.id));
+    nodes
+}
+
+fn sorted_relationships(keys: HashSet<RelationshipKey>) -> Vec<GraphRelationship> {
+    let mut keys = keys.into_iter().collect::<Vec<RelationshipKey>>();
+    keys.sort();
+    keys.into_iter().map(relationship_from_key).collect()
+}
+
+// nodes are compared by id and relationships by their endpoints and type,
+// relationships have no identity of their own
+pub fn diff_graphs(before: &GraphData, after: &GraphData) -> GraphChanges {
+    let before_nodes = before
+        .nodes
+        .iter()
+        .map(|node| (node.id.as_str(), node))
+        .collect::<HashMap<&str, &GraphNode>>();
+    let after_nodes = after
+        .nodes
+        .iter()
+        .map(|node| (node.id.as_str(), node))
+        .collect::<HashMap<&str, &GraphNode>>();
+
+    let mut changes = GraphChanges::default();
+
+    for node in &after.nodes {
+        match before_nodes.get(node.id.as_str()) {
+            None => changes.added_nodes.push(node.clone()),
+            Some(old) if old.label != node.label || old.properties != node.properties => {
+                changes.updated_nodes.push(NodeChange {
+                    before: (*old).clone(),
+                    after: node.clone(),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    changes.removed_nodes = before
+        .nodes
+        .iter()
+        .filter(|node| !after_nodes.contains_key(node.id.as_str()))
+        .cloned()
+        .collect();
+
+    let before_rels = before
+        .relationships
+        .iter()
+        .map(relationship_key)
+        .collect::<HashSet<RelationshipKey>>();
+    let after_rels = after
+        .relationships
+        .iter()
+        .map(relationship_key)
+        .collect::<HashSet<RelationshipKey>>();
+
+    changes.added_relationships =
+        sorted_relationships(after_rels.difference(&before_rels).cloned().collect());
+    changes.removed_relationships =
+        sorted_relationships(before_rels.difference(&after_rels).cloned().collect());
+
+    changes.added_nodes.sort_by(|a, b| a.id.cmp(&b.id));
+    changes
+        .updated_nodes
+        .sort_by(|a, b| a.after.id.cmp(&b.after.id));
+    changes.removed_nodes.sort_by(|a, b| a.id.cmp(&b.id));
+
+    changes
+}
+
+// Folds consecutive changesets into the net change between the first one's
+// starting point and the last one's result. A node added and removed again
+// within the range doesn't show up at all.
+pub fn compose(changesets: &[GraphChangeset]) -> GraphChanges {
+    // id -> (state before the range, state after it), None where the node
+    // didn't exist
+    let mut nodes: HashMap<String, (Option<GraphNode>, Option<GraphNode>)> = HashMap::new();
+    let mut relationships: HashMap<RelationshipKey, (bool, bool)> = HashMap::new();
+
+    for changeset in changesets {
+        let changes = &changeset.changes.0;
+
+        let node_steps = changes
+            .added_nodes
+            .iter()
+            .map(|node| (&node.id, None, Some(node)))
+            .chain(
+                changes
+                    .updated_nodes
+                    .iter()
+                    .map(|change| (&change.after.id, Some(&change.before), Some(&change.after))),
+            )
+            .chain(
+                changes
+                    .removed_nodes
+                    .iter()
+                    .map(|node| (&node.id, Some(node), None)),
+            );
+        for (id, before, after) in node_steps {
+            nodes
+                .entry(id.clone())
+                .or_insert_with(|| (before.cloned(), None))
+                .1 = after.cloned();
+        }
+
+        let rel_steps = changes
+            .added_relationships
+            .iter()
+            .map(|rel| (rel, false, true))
+            .chain(
+                changes
+                    .removed_relationships
+                    .iter()
+                    .map(|rel| (rel, true, false)),
+            );
+        for (rel, before, after) in rel_steps {
+            relationships
+                .entry(relationship_key(rel))
+                .or_insert((before, after))
+                .1 = after;
+        }
+    }
+
+    let mut added = HashMap::new();
+    let mut removed = HashMap::new();
+    let mut changes = GraphChanges::default();
+    for (id, states) in nodes {
+        match states {
+            (None, Some(after)) => {
+                added.insert(id, after);
+            }
+            (Some(before), None) => {
+                removed.insert(id, before);
+            }
+            (Some(before), Some(after))
+                if before.label != after.label || before.properties != after.properties =>
+            {
+                changes.updated_nodes.push(NodeChange { before, after })
+            }
+            _ => {}
+        }
+    }
+    changes.added_nodes = sorted_nodes(added);
+    changes.removed_nodes = sorted_nodes(removed);
+    changes
+        .updated_nodes
+        .sort_by(|a, b| a.after.id.cmp(&b.after.id));
+
+    changes.added_relationships = sorted_relationships(
+        relationships
+            .iter()
+            .filter(|(_, states)| **states == (false, true))
+            .map(|(key, _)| key.clone())
+            .collect(),
+    );
+    changes.removed_relationships = sorted_relationships(
+        relationships
+            .iter()
+            .filter(|(_, states)| **states == (true, false))
+            .map(|(key, _)| key.clone())
+            .collect(),
+    );
+
+    changes
+}
+
+// Recorded writes of a user are serialized on the transaction this returns,
+// so the snapshots taken around a write only see its own changes and the
+// version can't be taken by another write in between.
+async fn lock_history(
+    app_state: &AppState,
+    user_id: &Uuid,
+) -> Result<Transaction<'static, Postgres>, anyhow::Error> {
+    let mut tx = app_state.pool.begin().await?;
+    GraphChangeset::lock_versions(&mut tx, *user_id).await?;
+
+    Ok(tx)
+}
+
+// The part of the user's graph a recorded write is diffed on. Snapshotting
+// the whole graph costs two full reads per write, so writes that know which
+// nodes they touch only read those and their relationships.
+pub enum Scope {
+    Graph,
+    Nodes(Vec<String>),
+}
+
+impl Scope {
+    // every node a change refers to, directly or as a relationship end
+    fn touched_by(changes: &GraphChanges) -> Self {
+        let ids = changes
+            .added_nodes
+            .iter()
+            .chain(&changes.removed_nodes)
+            .chain(changes.updated_nodes.iter().map(|change| &change.after))
+            .map(|node| node.id.clone())
+            .chain(
+                changes
+                    .added_relationships
+                    .iter()
+                    .chain(&changes.removed_relationships)
+                    .flat_map(|rel| [rel.source_id.clone(), rel.target_id.clone()]),
+            )
+            .collect::<HashSet<String>>();
+
+        Scope::Nodes(ids.into_iter().collect())
+    }
+
+    // the nodes of `data` and the ends of its relationships under the ids
+    // into_queries gave them
+    pub fn written(data: &GraphData, node_ids: &HashMap<String, String>) -> Self {
+        let ids = data
+            .nodes
+            .iter()
+            .map(|node| &node.id)
+            .chain(
+                data.relationships
+                    .iter()
+                    .flat_map(|rel| [&rel.source_id, &rel.target_id]),
+            )
+            .map(|id| node_ids.get(id).unwrap_or(id).clone())
+            .collect::<HashSet<String>>();
+
+        Scope::Nodes(ids.into_iter().collect())
+    }
+}
+
+async fn snapshot(
+    app_state: &AppState,
+    user_id: &Uuid,
+    scope: &Scope,
+) -> Result<GraphData, anyhow::Error> {
+    let Scope::Nodes(ids) = scope else {
+        return get_owned_graph(app_state, user_id).await;
+    };
+
+    let mut graph: GraphData = app_state
+        .graph
+        .get_subgraph(user_id, ids)
+        .await?
+        .try_into()?;
+    // neighbours only show up as relationship ends, they are not part of the
+    // snapshot themselves
+    graph.nodes.retain(|node| ids.contains(&node.id));
+
+    Ok(graph)
+}
+
+// The graph write is committed before the version recording it is, when
+// recording fails the write is undone so the graph doesn't drift from its
+// history.
+async fn revert_unrecorded<T>(
+    app_state: &AppState,
+    user_id: &Uuid,
+    changes: Option<&GraphChanges>,
+    result: Result<T, anyhow::Error>,
+) -> Result<T, anyhow::Error> {
+    let (Err(err), Some(changes)) = (&result, changes) else {
+        return result;
+    };
+
+    let reverted = match inverse_queries(app_state, user_id, changes).await {
+        Ok(queries) => app_state
+            .graph
+            .run_queries(queries)
+            .await
+            .map_err(Into::into),
+        Err(err) => Err(err),
+    };
+    match reverted {
+        Ok(()) => warn!(
+            "Reverted graph write of user {} that couldn't be recorded: {}",
+            user_id, err
+        ),
+        Err(revert_err) => error!(
+            "Graph write of user {} couldn't be recorded ({}) nor reverted ({}), its changes are missing from the history: {:?}",
+            user_id, err, revert_err, changes
+        ),
+    }
+
+    result
+}
+
+// runs the queries and records what they changed in `tx`, which has to hold
+// the lock from lock_history
+async fn write_recorded(
+    tx: &mut PgConnection,
+    app_state: &AppState,
+    user_id: &Uuid,
+    source: ChangeSource,
+    chat_id: Option<Uuid>,
+    scope: &Scope,
+    queries: Vec<Query>,
+) -> Result<Option<GraphChangeset>, anyhow::Error> {
+    let before = snapshot(app_state, user_id, scope).await?;
+    app_state.graph.run_queries(queries).await?;
+    let after = snapshot(app_state, user_id, scope)
+        .await
+        .inspect_err(|err| {
+            error!(
+                "Graph write of user {} is not recorded, reading its result failed: {}",
+                user_id, err
+            )
+        })?;
+
+    let changes = diff_graphs(&before, &after);
+    if changes.is_empty() {
+        return Ok(None);
+    }
+
+    let recorded = GraphChangeset::record(tx, *user_id, source, chat_id, changes.clone())
+        .await
+        .map_err(Into::into);
+    let changeset = revert_unrecorded(app_state, user_id, Some(&changes), recorded).await?;
+
+    Ok(Some(changeset))
+}
+
+// Runs a write against the user's graph and records what it changed as the
+// next version, writes that turn out to change nothing don't create a version.
+// Changes outside of `scope` are not recorded.
+pub async fn run_recorded(
+    app_state: &AppState,
+    user_id: &Uuid,
+    source: ChangeSource,
+    chat_id: Option<Uuid>,
+    scope: Scope,
+    queries: Vec<Query>,
+) -> Result<Option<GraphChangeset>, anyhow::Error> {
+    let mut tx = lock_history(app_state, user_id).await?;
+    let changeset = write_recorded(
+        &mut tx, app_state, user_id, source, chat_id, &scope, queries,
+    )
+    .await?;
+
+    let committed = tx.commit().await.map_err(Into::into);
+    revert_unrecorded(
+        app_state,
+        user_id,
+        changeset.as_ref().map(|c| &c.changes.0),
+        committed,
+    )
+    .await?;
+
+    Ok(changeset)
+}
+
+pub async fn diff_versions(
+    app_state: &AppState,
+    user_id: &Uuid,
+    from: i32,
+    to: i32,
+) -> Result<GraphChanges, anyhow::Error> {
+    let latest = GraphChangeset::latest_version(&app_state.pool, *user_id).await?;
+
+    if from < 0 || from >= to || to > latest {
+        return Err(ApiError::BadRequest(format!(
+            "Versions must satisfy 0 <= from < to <= {}",
+            latest
+        ))
+        .into());
+    }
+
+    let changesets = GraphChangeset::between(&app_state.pool, *user_id, from, to).await?;
+
+    Ok(compose(&changesets))
+}
+
+// the queries that turn the graph after `changes` back into the graph before
+// them, the user node is left alone
+async fn inverse_queries(
+    app_state: &AppState,
+    user_id: &Uuid,
+    changes: &GraphChanges,
+) -> Result<Vec<Query>, anyhow::Error> {
+    let mut queries = changes
+        .added_relationships
+        .iter()
+        .map(|rel| rel.delete_query(user_id))
+        .collect::<Result<Vec<Query>, anyhow::Error>>()?;
+
+    for node in changes.added_nodes.iter().filter(|n| n.label != "User") {
+        queries.push(
+            query(&format!(
+                "MATCH {} DETACH DELETE n",
+                node_pattern("n", &node.label, "id")
+            ))
+            .param("id", node.id.clone())
+            .param("user_id", user_id.to_string()),
+        );
+    }
+
+    for change in changes
+        .updated_nodes
+        .iter()
+        .filter(|c| c.before.label != "User")
+    {
+        let mut props = change.before.bolt_properties()?;
+        for key in change.after.properties.keys() {
+            if !change.before.properties.contains_key(key) {
+                props.insert(key.clone(), BoltType::Null(BoltNull));
+            }
+        }
+
+        let embedding_content = change.before.embedding_content()?;
+        if embedding_content != change.after.embedding_content()? {
+            if let Some(content) = embedding_content {
+                let embedding = app_state.llm.get_embedding(content).await?;
+                props.insert("embedding".to_string(), embedding.into());
+            }
+        }
+
+        queries.push(
+            query(&format!(
+                "MATCH {} SET n += $props",
+                node_pattern("n", &change.before.label, "id")
+            ))
+            .param("id", change.before.id.clone())
+            .param("user_id", user_id.to_string())
+            .param("props", props),
+        );
+    }
+
+    // removed nodes come back under their old ids so relationships and
+    // later changesets still refer to them
+    let removed = changes
+        .removed_nodes
+        .iter()
+        .filter(|n| n.label != "User")
+        .collect::<Vec<&GraphNode>>();
+    let embedding_contents = removed
+        .iter()
+        .map(|node| node.embedding_content())
+        .collect::<Result<Vec<Option<String>>, anyhow::Error>>()?;
+    let mut embeddings = app_state
+        .llm
+        .get_embeddings(embedding_contents.iter().flatten().cloned().collect())
+        .await?
+        .into_iter();
+
+    for (node, embedding_content) in removed.into_iter().zip(embedding_contents) {
+        let embedding = match embedding_content {
+            Some(_) => Some(
+                embeddings
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("Missing embedding for node {}", node.id))?,
+            ),
+            None => None,
+        };
+
+        queries.push(node.restore_query(user_id, embedding)?);
+    }
+
+    for rel in &changes.removed_relationships {
+        queries.push(rel.restore_query(user_id)?);
+    }
+
+    Ok(queries)
+}
+
+// Undoes the most recent change that hasn't been undone yet. The rollback is
+// recorded as a version of its own, so it shows up in diffs like any other
+// write. Picking the change, undoing it and marking it as undone happen under
+// one lock, so concurrent rollbacks never undo the same change twice.
+pub async fn rollback_last(
+    app_state: &AppState,
+    user_id: &Uuid,
+) -> Result<Option<GraphChangeset>, anyhow::Error> {
+    let mut tx = lock_history(app_state, user_id).await?;
+
+    let changeset = GraphChangeset::last_undoable(&mut tx, *user_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("No graph changes to roll back".into()))?;
+
+    let queries = inverse_queries(app_state, user_id, &changeset.changes.0).await?;
+
+    let rollback = write_recorded(
+        &mut tx,
+        app_state,
+        user_id,
+        ChangeSource::Rollback,
+        changeset.chat_id,
+        &Scope::touched_by(&changeset.changes.0),
+        queries,
+    )
+    .await?;
+
+    let committed = async {
+        GraphChangeset::mark_rolled_back(&mut tx, changeset.id).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+    .await;
+    revert_unrecorded(
+        app_state,
+        user_id,
+        rollback.as_ref().map(|c| &c.changes.0),
+        committed,
+    )
+    .await?;
+
+    info!(
+        "Rolled back graph version {} of user {}.",
+        changeset.version, user_id
+    );
+
+    Ok(rollback)
+}
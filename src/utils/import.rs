@@ -0,0 +1,209 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use neo4rs::query;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    types::{ChangeSource, GraphData, GraphSchema, ImportGraphRequest, ImportMode, ImportSummary},
+    utils::{
+        config::{AppState, Parsable},
+        error::ApiError,
+        graph::{check_report, get_owned_graph},
+        history::{run_recorded, Scope},
+        merge::plan_merge,
+    },
+};
+
+pub const MAX_IMPORT_NODES: usize = 5000;
+
+// every imported node has to be reachable from the user node, or from a node
+// the user already has, otherwise it would never show up in their graph
+fn check_connected(data: &GraphData, existing: &HashMap<String, String>) -> Result<(), ApiError> {
+    let mut neighbours: HashMap<&str, Vec<&str>> = HashMap::new();
+    for rel in &data.relationships {
+        neighbours
+            .entry(rel.source_id.as_str())
+            .or_default()
+            .push(rel.target_id.as_str());
+        neighbours
+            .entry(rel.target_id.as_str())
+            .or_default()
+            .push(rel.source_id.as_str());
+    }
+
+    let mut queue = data
+        .nodes
+        .iter()
+        .filter(|node| node.label == "User")
+        .map(|node| node.id.as_str())
+        .chain(existing.keys().map(|id| id.as_str()))
+        .collect::<VecDeque<&str>>();
+    let mut reached = queue.iter().copied().collect::<HashSet<&str>>();
+
+    while let Some(id) = queue.pop_front() {
+        for next in neighbours.get(id).into_iter().flatten() {
+            if reached.insert(next) {
+                queue.push_back(next);
+            }
+        }
+    }
+
+    let unreachable = data
+        .nodes
+        .iter()
+        .filter(|node| !reached.contains(node.id.as_str()))
+        .map(|node| node.id.as_str())
+        .collect::<Vec<&str>>();
+
+    match unreachable.is_empty() {
+        true => Ok(()),
+        false => Err(ApiError::BadRequest(format!(
+            "Nodes not connected to the user node: {}",
+            unreachable.join(", ")
+        ))),
+    }
+}
+
+// Replays graph data into the user's graph in a single transaction. Imported
+// ids only need to be unique within the data, nodes are stored under new ids.
+// In merge mode relationships may also point at ids of existing nodes.
+pub async fn import_graph(
+    app_state: &AppState,
+    user_id: &Uuid,
+    request: ImportGraphRequest,
+) -> Result<ImportSummary, anyhow::Error> {
+    let mut data = request.graph;
+
+    if data.nodes.len() > MAX_IMPORT_NODES {
+        return Err(ApiError::BadRequest(format!(
+            "At most {} nodes can be imported at once",
+            MAX_IMPORT_NODES
+        ))
+        .into());
+    }
+
+    if data
+        .nodes
+        .iter()
+        .filter(|node| node.label == "User")
+        .count()
+        != 1
+    {
+        return Err(ApiError::BadRequest(
+            "Imported graph data must contain exactly one User node".into(),
+        )
+        .into());
+    }
+
+    let owned = get_owned_graph(app_state, user_id).await?;
+    let existing_nodes = match request.mode {
+        ImportMode::Merge => owned
+            .nodes
+            .iter()
+            .map(|n| (n.id.clone(), n.label.clone()))
+            .collect::<HashMap<String, String>>(),
+        ImportMode::Replace => HashMap::new(),
+    };
+
+    let report = GraphSchema::get().validate(&mut data, &existing_nodes);
+    let repairs = report.repairs.clone();
+    check_report(report)?;
+    check_connected(&data, &existing_nodes)?;
+
+    let user_node = owned.nodes.iter().find(|n| n.label == "User");
+
+    let summary = match request.mode {
+        ImportMode::Merge => {
+            let embeddings = app_state.graph.get_node_embeddings(user_id).await?;
+            let plan = plan_merge(app_state, &owned, &embeddings, data).await?;
+
+            let queries = plan
+                .add
+                .clone()
+                .into_queries_with(user_id, &app_state.llm, plan.embeddings.clone())
+                .await?;
+            let update_queries = plan.update_queries(user_id)?;
+
+            run_recorded(
+                app_state,
+                user_id,
+                ChangeSource::Import,
+                None,
+                Scope::Graph,
+                queries.queries.into_iter().chain(update_queries).collect(),
+            )
+            .await?;
+
+            ImportSummary {
+                mode: request.mode,
+                nodes_removed: 0,
+                nodes_added: plan.add.nodes.len(),
+                nodes_updated: plan.updates.len(),
+                nodes_already_present: plan.skipped_nodes.len(),
+                relationships_added: plan.add.relationships.len(),
+                relationships_already_present: plan.skipped_relationships,
+                repairs,
+                node_ids: queries.node_ids.into_iter().chain(plan.matches).collect(),
+            }
+        }
+        ImportMode::Replace => {
+            let imported_user_id = data
+                .nodes
+                .iter()
+                .find(|n| n.label == "User")
+                .map(|n| n.id.clone());
+            let nodes_added = data.nodes.len() - 1;
+            let relationships_added = data.relationships.len();
+
+            let clear_query = query(
+                r#"
+                MATCH (n {owner_id: $user_id})
+                WHERE NOT n:User
+                DETACH DELETE n
+                "#,
+            )
+            .param("user_id", user_id.to_string());
+
+            let queries = data.into_queries(user_id, &app_state.llm).await?;
+
+            run_recorded(
+                app_state,
+                user_id,
+                ChangeSource::Import,
+                None,
+                Scope::Graph,
+                std::iter::once(clear_query)
+                    .chain(queries.queries)
+                    .collect(),
+            )
+            .await?;
+
+            let mut node_ids = queries.node_ids;
+            // the user node is kept, so its imported id points at the
+            // existing one
+            if let (Some(imported), Some(user_node)) = (imported_user_id, user_node) {
+                node_ids.insert(imported, user_node.id.clone());
+            }
+
+            ImportSummary {
+                mode: request.mode,
+                nodes_removed: owned.nodes.iter().filter(|n| n.label != "User").count(),
+                nodes_added,
+                nodes_updated: 0,
+                nodes_already_present: 0,
+                relationships_added,
+                relationships_already_present: 0,
+                repairs,
+                node_ids,
+            }
+        }
+    };
+
+    info!(
+        "Imported graph data: {} nodes added, {} removed.",
+        summary.nodes_added, summary.nodes_removed
+    );
+
+    Ok(summary)
+}
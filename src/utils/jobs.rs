@@ -0,0 +1,180 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    model::Job,
+    types::{DedupGraphPayload, ExtractKnowledgePayload, JobKind, ReviewGoalsPayload},
+    utils::{
+        config::AppState, dedup::dedup_graph, error::ApiError, graph::create_knowledge_from_chat,
+        review::review_goals,
+    },
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const STALE_LOCK_AFTER: chrono::Duration = chrono::Duration::minutes(15);
+// running jobs refresh their lock well within STALE_LOCK_AFTER so long jobs
+// aren't claimed a second time
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+const BASE_BACKOFF_SECS: i64 = 30;
+const MAX_BACKOFF_SECS: i64 = 60 * 60;
+
+pub fn spawn_job_workers(app_state: Arc<AppState>, workers: usize) {
+    for worker in 0..workers {
+        let app_state = app_state.clone();
+
+        tokio::spawn(async move {
+            info!("Job worker {} started.", worker);
+
+            loop {
+                let stale_before = Utc::now() - STALE_LOCK_AFTER;
+                match Job::fail_abandoned(&app_state.pool, stale_before).await {
+                    Ok(0) => {}
+                    Ok(failed) => warn!("Failed {} abandoned jobs out of attempts.", failed),
+                    Err(e) => error!("Job worker {} failed to fail abandoned jobs: {}", worker, e),
+                }
+
+                match Job::claim_next(&app_state.pool, stale_before).await {
+                    Ok(Some(job)) => process_job(app_state.clone(), job).await,
+                    Ok(None) => tokio::time::sleep(POLL_INTERVAL).await,
+                    Err(e) => {
+                        error!("Job worker {} failed to claim a job: {}", worker, e);
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn process_job(app_state: Arc<AppState>, job: Job) {
+    info!(
+        "Running job {} ({:?}), attempt {}.",
+        job.id, job.kind, job.attempts
+    );
+
+    let heartbeat = tokio::spawn(heartbeat(app_state.clone(), job.id));
+    let result = run_job(app_state.clone(), &job).await;
+    heartbeat.abort();
+
+    let outcome = match result {
+        Ok(result) => Job::complete(&app_state.pool, job.id, result).await,
+        Err(e) => {
+            let retry_at = (job.attempts < job.max_attempts && !is_permanent(&e)).then(|| {
+                let backoff =
+                    (BASE_BACKOFF_SECS << (job.attempts - 1).clamp(0, 16)).min(MAX_BACKOFF_SECS);
+                Utc::now() + chrono::Duration::seconds(backoff)
+            });
+
+            warn!(
+                "Job {} failed on attempt {}: {}. Retry at: {:?}",
+                job.id, job.attempts, e, retry_at
+            );
+
+            Job::fail(&app_state.pool, job.id, e.to_string(), retry_at).await
+        }
+    };
+
+    if let Err(e) = outcome {
+        error!("Failed to record outcome of job {}: {}", job.id, e);
+    }
+}
+
+async fn heartbeat(app_state: Arc<AppState>, job_id: Uuid) {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    // the first tick completes immediately, the job was only just locked
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if let Err(e) = Job::heartbeat(&app_state.pool, job_id).await {
+            warn!("Failed to refresh the lock of job {}: {}", job_id, e);
+        }
+    }
+}
+
+// errors that would come back on every attempt, like a chat that was deleted
+// or belongs to someone else, fail the job right away
+fn is_permanent(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<ApiError>(),
+        Some(
+            ApiError::BadRequest(_)
+                | ApiError::Unauthorized(_)
+                | ApiError::Forbidden(_)
+                | ApiError::NotFound(_)
+        )
+    )
+}
+
+fn parse_payload<T: DeserializeOwned>(job: &Job) -> Result<T, ApiError> {
+    serde_json::from_value(job.payload.clone())
+        .map_err(|e| ApiError::BadRequest(format!("Malformed payload for job {}: {}", job.id, e)))
+}
+
+async fn run_job(app_state: Arc<AppState>, job: &Job) -> Result<serde_json::Value, anyhow::Error> {
+    match job.kind {
+        JobKind::ExtractKnowledge => {
+            let payload: ExtractKnowledgePayload = parse_payload(job)?;
+
+            let summary =
+                create_knowledge_from_chat(app_state, job.user_id, payload.chat_id).await?;
+
+            Ok(serde_json::to_value(summary)?)
+        }
+        JobKind::DedupGraph => {
+            let payload: DedupGraphPayload = parse_payload(job)?;
+
+            let summary = dedup_graph(&app_state, &job.user_id, payload).await?;
+
+            Ok(serde_json::to_value(summary)?)
+        }
+        JobKind::ReviewGoals => {
+            let payload: ReviewGoalsPayload = parse_payload(job)?;
+
+            let summary = review_goals(&app_state, &job.user_id, payload.chat_id).await?;
+
+            Ok(serde_json::to_value(summary)?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::JobStatus;
+
+    #[test]
+    fn requests_that_cannot_succeed_are_not_retried() {
+        assert!(is_permanent(
+            &ApiError::NotFound(String::from("Chat not found")).into()
+        ));
+        assert!(is_permanent(
+            &ApiError::Forbidden(String::from("Not your chat")).into()
+        ));
+        assert!(!is_permanent(&anyhow::anyhow!("connection reset")));
+
+        let job = Job {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            kind: JobKind::ExtractKnowledge,
+            status: JobStatus::Running,
+            payload: serde_json::json!({ "chat": "not a chat id" }),
+            result: None,
+            idempotency_key: None,
+            attempts: 1,
+            max_attempts: 5,
+            last_error: None,
+            run_at: Utc::now(),
+            locked_at: Some(Utc::now()),
+            created_at: Utc::now(),
+            updated_at: None,
+            completed_at: None,
+        };
+        let malformed = parse_payload::<ExtractKnowledgePayload>(&job).unwrap_err();
+        assert!(is_permanent(&malformed.into()));
+    }
+}
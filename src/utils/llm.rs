@@ -0,0 +1,455 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use async_openai::{
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+        ChatCompletionResponseFormat, ChatCompletionResponseFormatType,
+        ChatCompletionResponseMessage, CreateChatCompletionRequestArgs, CreateEmbeddingRequestArgs,
+        Role,
+    },
+    Client,
+};
+use futures::{stream::BoxStream, StreamExt};
+use sha2::{Digest, Sha256};
+
+use crate::utils::config::AppEnv;
+
+pub const EMBEDDING_DIMENSIONS: usize = 384;
+// well below the number of inputs the embeddings endpoint accepts at once
+const EMBEDDING_BATCH_SIZE: usize = 256;
+
+pub type DeltaStream = BoxStream<'static, Result<String, anyhow::Error>>;
+
+pub trait LlmProvider {
+    fn chat(
+        &self,
+        model: &str,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> impl Future<Output = Result<ChatCompletionResponseMessage, anyhow::Error>>;
+    fn chat_stream(
+        &self,
+        model: &str,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> impl Future<Output = Result<DeltaStream, anyhow::Error>>;
+    fn get_tool_response(
+        &self,
+        prompt: String,
+    ) -> impl Future<Output = Result<String, anyhow::Error>>;
+    fn get_embedding(
+        &self,
+        content: String,
+    ) -> impl Future<Output = Result<Vec<f32>, anyhow::Error>>;
+    fn get_embeddings(
+        &self,
+        contents: Vec<String>,
+    ) -> impl Future<Output = Result<Vec<Vec<f32>>, anyhow::Error>>;
+}
+
+#[derive(Clone)]
+pub enum Provider {
+    OpenAi(Box<OpenAiProvider>),
+    Mock(MockProvider),
+}
+
+impl Provider {
+    pub fn from_env(app_env: &AppEnv) -> Result<Self, anyhow::Error> {
+        let api_key = || {
+            app_env
+                .openai_api_key
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("OPENAI_API_KEY is not set"))
+        };
+
+        let with_models = |provider: OpenAiProvider| {
+            provider.with_models(
+                app_env.llm_chat_model.clone(),
+                app_env.llm_tool_model.clone(),
+                app_env.llm_embedding_model.clone(),
+            )
+        };
+
+        let provider = match app_env.llm_provider.as_str() {
+            "openai" => Provider::OpenAi(Box::new(with_models(OpenAiProvider::openai(api_key()?)))),
+            "openai_compatible" => {
+                let base_url = app_env.llm_base_url.clone().ok_or_else(|| {
+                    anyhow::anyhow!("LLM_BASE_URL is required for the openai_compatible provider")
+                })?;
+
+                Provider::OpenAi(Box::new(with_models(OpenAiProvider::compatible(
+                    base_url,
+                    app_env.openai_api_key.clone().unwrap_or_default(),
+                ))))
+            }
+            "mock" => Provider::Mock(MockProvider::new()),
+            other => return Err(anyhow::anyhow!("Unknown LLM_PROVIDER {}", other)),
+        };
+
+        Ok(provider)
+    }
+
+    // recorded with extracted graph data
+    pub fn tool_model(&self) -> &str {
+        match self {
+            Provider::OpenAi(provider) => &provider.tool_model,
+            Provider::Mock(_) => "mock",
+        }
+    }
+}
+
+impl LlmProvider for Provider {
+    async fn chat(
+        &self,
+        model: &str,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<ChatCompletionResponseMessage, anyhow::Error> {
+        match self {
+            Provider::OpenAi(provider) => provider.chat(model, messages).await,
+            Provider::Mock(provider) => provider.chat(model, messages).await,
+        }
+    }
+
+    async fn chat_stream(
+        &self,
+        model: &str,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<DeltaStream, anyhow::Error> {
+        match self {
+            Provider::OpenAi(provider) => provider.chat_stream(model, messages).await,
+            Provider::Mock(provider) => provider.chat_stream(model, messages).await,
+        }
+    }
+
+    async fn get_tool_response(&self, prompt: String) -> Result<String, anyhow::Error> {
+        match self {
+            Provider::OpenAi(provider) => provider.get_tool_response(prompt).await,
+            Provider::Mock(provider) => provider.get_tool_response(prompt).await,
+        }
+    }
+
+    async fn get_embedding(&self, content: String) -> Result<Vec<f32>, anyhow::Error> {
+        match self {
+            Provider::OpenAi(provider) => provider.get_embedding(content).await,
+            Provider::Mock(provider) => provider.get_embedding(content).await,
+        }
+    }
+
+    async fn get_embeddings(&self, contents: Vec<String>) -> Result<Vec<Vec<f32>>, anyhow::Error> {
+        match self {
+            Provider::OpenAi(provider) => provider.get_embeddings(contents).await,
+            Provider::Mock(provider) => provider.get_embeddings(contents).await,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct OpenAiProvider {
+    client: Client<OpenAIConfig>,
+    // overrides the model requested by the client, local servers usually only
+    // serve the one model they were started with
+    chat_model: Option<String>,
+    tool_model: String,
+    embedding_model: String,
+    // only OpenAI supports shortening embeddings through `dimensions`
+    request_dimensions: bool,
+}
+
+impl OpenAiProvider {
+    pub fn openai(api_key: String) -> Self {
+        Self {
+            client: Client::with_config(OpenAIConfig::new().with_api_key(api_key)),
+            chat_model: None,
+            tool_model: String::from("gpt-4o"),
+            embedding_model: String::from("text-embedding-3-small"),
+            request_dimensions: true,
+        }
+    }
+
+    pub fn compatible(base_url: String, api_key: String) -> Self {
+        Self {
+            client: Client::with_config(
+                OpenAIConfig::new()
+                    .with_api_base(base_url)
+                    .with_api_key(api_key),
+            ),
+            request_dimensions: false,
+            ..Self::openai(String::new())
+        }
+    }
+
+    pub fn with_models(
+        mut self,
+        chat_model: Option<String>,
+        tool_model: Option<String>,
+        embedding_model: Option<String>,
+    ) -> Self {
+        self.chat_model = chat_model.or(self.chat_model);
+        self.tool_model = tool_model.unwrap_or(self.tool_model);
+        self.embedding_model = embedding_model.unwrap_or(self.embedding_model);
+        self
+    }
+
+    fn chat_model<'a>(&'a self, requested: &'a str) -> &'a str {
+        self.chat_model.as_deref().unwrap_or(requested)
+    }
+}
+
+impl LlmProvider for OpenAiProvider {
+    async fn chat(
+        &self,
+        model: &str,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<ChatCompletionResponseMessage, anyhow::Error> {
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(self.chat_model(model))
+            .messages(messages)
+            .build()?;
+
+        let response = self.client.chat().create(request).await?;
+        let message = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or(anyhow::anyhow!("No choices in AI response"))?
+            .message;
+
+        Ok(message)
+    }
+
+    async fn chat_stream(
+        &self,
+        model: &str,
+        messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<DeltaStream, anyhow::Error> {
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(self.chat_model(model))
+            .messages(messages)
+            .build()?;
+
+        let stream = self.client.chat().create_stream(request).await?;
+
+        let deltas = stream.filter_map(|chunk| async move {
+            match chunk {
+                Ok(chunk) => {
+                    let content = chunk
+                        .choices
+                        .into_iter()
+                        .filter_map(|choice| choice.delta.content)
+                        .collect::<String>();
+
+                    (!content.is_empty()).then_some(Ok(content))
+                }
+                Err(e) => Some(Err(e.into())),
+            }
+        });
+
+        Ok(deltas.boxed())
+    }
+
+    async fn get_tool_response(&self, prompt: String) -> Result<String, anyhow::Error> {
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(self.tool_model.clone())
+            .response_format(ChatCompletionResponseFormat {
+                r#type: ChatCompletionResponseFormatType::JsonObject,
+            })
+            .messages(vec![ChatCompletionRequestMessage::System(
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(prompt)
+                    .build()?,
+            )])
+            .build()?;
+
+        let response = self.client.chat().create(request).await?;
+        let content = response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.clone())
+            .ok_or(anyhow::anyhow!("No content in AI response"))?;
+
+        Ok(content)
+    }
+
+    async fn get_embedding(&self, content: String) -> Result<Vec<f32>, anyhow::Error> {
+        self.get_embeddings(vec![content])
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(anyhow::anyhow!("Error creating embedding"))
+    }
+
+    async fn get_embeddings(&self, contents: Vec<String>) -> Result<Vec<Vec<f32>>, anyhow::Error> {
+        let mut embeddings = Vec::with_capacity(contents.len());
+
+        for batch in contents.chunks(EMBEDDING_BATCH_SIZE) {
+            let mut request = CreateEmbeddingRequestArgs::default();
+            request
+                .model(self.embedding_model.clone())
+                .input(batch.to_vec());
+            if self.request_dimensions {
+                request.dimensions(EMBEDDING_DIMENSIONS as u32);
+            }
+
+            let mut data = self
+                .client
+                .embeddings()
+                .create(request.build()?)
+                .await?
+                .data;
+
+            if data.len() != batch.len() {
+                return Err(anyhow::anyhow!(
+                    "Embedding model returned {} embeddings for {} inputs",
+                    data.len(),
+                    batch.len()
+                ));
+            }
+
+            data.sort_by_key(|embedding| embedding.index);
+            for embedding in data {
+                if embedding.embedding.len() != EMBEDDING_DIMENSIONS {
+                    return Err(anyhow::anyhow!(
+                        "Embedding model returned {} dimensions, expected {}",
+                        embedding.embedding.len(),
+                        EMBEDDING_DIMENSIONS
+                    ));
+                }
+
+                embeddings.push(embedding.embedding);
+            }
+        }
+
+        Ok(embeddings)
+    }
+}
+
+// Replays scripted responses in order and falls back to fixed defaults once a
+// script runs out. Embeddings are hashed bags of words, so texts sharing words
+// end up close to each other.
+#[derive(Clone)]
+pub struct MockProvider {
+    chat_responses: Arc<Mutex<VecDeque<String>>>,
+    tool_responses: Arc<Mutex<VecDeque<String>>>,
+    default_chat_response: String,
+    default_tool_response: String,
+}
+
+impl Default for MockProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockProvider {
+    pub fn new() -> Self {
+        Self {
+            chat_responses: Arc::new(Mutex::new(VecDeque::new())),
+            tool_responses: Arc::new(Mutex::new(VecDeque::new())),
+            default_chat_response: String::from("This is a mock response."),
+            default_tool_response: String::from(r#"{"nodes": [], "relationships": []}"#),
+        }
+    }
+
+    pub fn with_chat_responses(self, responses: Vec<String>) -> Self {
+        self.push_chat_responses(responses);
+        self
+    }
+
+    pub fn with_tool_responses(self, responses: Vec<String>) -> Self {
+        self.push_tool_responses(responses);
+        self
+    }
+
+    pub fn push_chat_responses(&self, responses: Vec<String>) {
+        if let Ok(mut queue) = self.chat_responses.lock() {
+            queue.extend(responses);
+        }
+    }
+
+    pub fn push_tool_responses(&self, responses: Vec<String>) {
+        if let Ok(mut queue) = self.tool_responses.lock() {
+            queue.extend(responses);
+        }
+    }
+
+    fn next_chat_response(&self) -> String {
+        Self::next_response(&self.chat_responses).unwrap_or(self.default_chat_response.clone())
+    }
+
+    fn next_response(queue: &Mutex<VecDeque<String>>) -> Option<String> {
+        queue.lock().ok().and_then(|mut queue| queue.pop_front())
+    }
+
+    pub fn embed(content: &str) -> Vec<f32> {
+        let mut embedding = vec![0.0_f32; EMBEDDING_DIMENSIONS];
+
+        for word in content
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            let hash = Sha256::digest(word.to_lowercase().as_bytes());
+            let bucket = u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]) as usize
+                % EMBEDDING_DIMENSIONS;
+            let sign = if hash[4] & 1 == 0 { 1.0 } else { -1.0 };
+
+            embedding[bucket] += sign;
+        }
+
+        let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|v| *v /= norm);
+        }
+
+        embedding
+    }
+}
+
+impl LlmProvider for MockProvider {
+    async fn chat(
+        &self,
+        _model: &str,
+        _messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<ChatCompletionResponseMessage, anyhow::Error> {
+        #[allow(deprecated)]
+        let message = ChatCompletionResponseMessage {
+            content: Some(self.next_chat_response()),
+            tool_calls: None,
+            role: Role::Assistant,
+            function_call: None,
+        };
+
+        Ok(message)
+    }
+
+    async fn chat_stream(
+        &self,
+        _model: &str,
+        _messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<DeltaStream, anyhow::Error> {
+        let deltas = self
+            .next_chat_response()
+            .split_inclusive(' ')
+            .map(|delta| Ok(delta.to_string()))
+            .collect::<Vec<Result<String, anyhow::Error>>>();
+
+        Ok(futures::stream::iter(deltas).boxed())
+    }
+
+    async fn get_tool_response(&self, _prompt: String) -> Result<String, anyhow::Error> {
+        Ok(Self::next_response(&self.tool_responses).unwrap_or(self.default_tool_response.clone()))
+    }
+
+    async fn get_embedding(&self, content: String) -> Result<Vec<f32>, anyhow::Error> {
+        Ok(Self::embed(&content))
+    }
+
+    async fn get_embeddings(&self, contents: Vec<String>) -> Result<Vec<Vec<f32>>, anyhow::Error> {
+        Ok(contents
+            .iter()
+            .map(|content| Self::embed(content))
+            .collect())
+    }
+}
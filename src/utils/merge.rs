@@ -0,0 +1,439 @@
+use std::collections::{HashMap, HashSet};
+
+use neo4rs::{query, Query};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    types::{node_pattern, GraphData, GraphNode, GraphRelationship, ToolPrompts},
+    utils::{
+        config::AppState,
+        llm::{LlmProvider, Provider},
+    },
+};
+
+// cosine similarity above which two nodes of the same label are the same
+// thing, and the band below it that is left for the model to decide
+pub const MATCH_THRESHOLD: f32 = 0.92;
+const AMBIGUOUS_THRESHOLD: f32 = 0.8;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeUpdate {
+    pub node: GraphNode,
+    pub changed: HashMap<String, Value>,
+    // set when the change touches what the embedding is computed from
+    #[serde(skip)]
+    pub embedding: Option<Vec<f32>>,
+}
+
+// What merging extracted graph data into the existing graph comes down to:
+// `add` is written as is, matched nodes are either updated or skipped and
+// relationships pointing at them are rewired to the existing ids.
+#[derive(Debug, Clone, Serialize)]
+pub struct MergePlan {
+    pub add: GraphData,
+    pub updates: Vec<NodeUpdate>,
+    pub skipped_nodes: Vec<String>,
+    pub skipped_relationships: usize,
+    // incoming node ids mapped to the existing nodes they matched
+    pub matches: HashMap<String, String>,
+    // embeddings computed for matching, reused when the nodes are added
+    #[serde(skip)]
+    pub embeddings: HashMap<String, Vec<f32>>,
+}
+
+impl NodeUpdate {
+    pub fn new(existing: &GraphNode, changed: HashMap<String, Value>) -> Self {
+        let mut node = existing.clone();
+        node.properties.extend(changed.clone());
+
+        Self {
+            node,
+            changed,
+            embedding: None,
+        }
+    }
+
+    pub fn query(&self, user_id: &Uuid) -> Result<Query, neo4rs::Error> {
+        let changed = GraphNode {
+            properties: self.changed.clone(),
+            ..self.node.clone()
+        };
+        let mut props = changed.bolt_properties()?;
+        if let Some(embedding) = &self.embedding {
+            props.insert("embedding".to_string(), embedding.clone().into());
+        }
+
+        Ok(query(&format!(
+            "MATCH {} SET n += $props",
+            node_pattern("n", &self.node.label, "id")
+        ))
+        .param("id", self.node.id.clone())
+        .param("user_id", user_id.to_string())
+        .param("props", props))
+    }
+}
+
+// Updates that change what a node's embedding is computed from get a new
+// embedding, requested for all of them in one go. `originals` holds the nodes
+// as they are stored.
+pub async fn refresh_embeddings(
+    llm: &Provider,
+    originals: &HashMap<&str, &GraphNode>,
+    updates: &mut [NodeUpdate],
+) -> Result<(), anyhow::Error> {
+    let mut stale: Vec<(usize, String)> = vec![];
+    for (i, update) in updates.iter().enumerate() {
+        let Some(content) = update.node.embedding_content()? else {
+            continue;
+        };
+        let original = match originals.get(update.node.id.as_str()) {
+            Some(original) => original.embedding_content()?,
+            None => None,
+        };
+
+        if original.as_ref() != Some(&content) {
+            stale.push((i, content));
+        }
+    }
+
+    let embeddings = llm
+        .get_embeddings(stale.iter().map(|(_, content)| content.clone()).collect())
+        .await?;
+    for ((i, _), embedding) in stale.into_iter().zip(embeddings) {
+        updates[i].embedding = Some(embedding);
+    }
+
+    Ok(())
+}
+
+impl MergePlan {
+    pub fn update_queries(&self, user_id: &Uuid) -> Result<Vec<Query>, neo4rs::Error> {
+        self.updates
+            .iter()
+            .map(|update| update.query(user_id))
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MatchDecisions {
+    decisions: Vec<MatchDecision>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MatchDecision {
+    pair: usize,
+    same: bool,
+}
+
+// the properties that identify a node, they are never overwritten by a merge
+pub fn key_properties(label: &str) -> &'static [&'static str] {
+    match label {
+        "Interest" => &["name"],
+        "Goal" => &["description"],
+        "Motivation" => &["title"],
+        "Task" => &["action"],
+        "Date" => &["day", "month", "year"],
+        _ => &[],
+    }
+}
+
+pub fn normalized_key(node: &GraphNode) -> Option<String> {
+    let parts = key_properties(&node.label)
+        .iter()
+        .map(|key| match node.properties.get(*key)? {
+            Value::String(s) => Some(
+                s.to_lowercase()
+                    .chars()
+                    .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+                    .collect::<String>()
+                    .split_whitespace()
+                    .collect::<Vec<&str>>()
+                    .join(" "),
+            ),
+            Value::Null => None,
+            value => Some(value.to_string()),
+        })
+        .collect::<Option<Vec<String>>>()?;
+
+    (!parts.is_empty()).then(|| parts.join("|"))
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    match norm_a * norm_b {
+        norm if norm > 0.0 => dot / norm,
+        _ => 0.0,
+    }
+}
+
+// properties the incoming node sets that the existing node is missing or has
+// a different value for, key properties excluded
+fn changed_properties(existing: &GraphNode, incoming: &GraphNode) -> HashMap<String, Value> {
+    let keys = key_properties(&existing.label);
+
+    incoming
+        .properties
+        .iter()
+        .filter(|(key, value)| {
+            !value.is_null()
+                && !keys.contains(&key.as_str())
+                && existing.properties.get(*key) != Some(value)
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+async fn resolve_ambiguous(
+    app_state: &AppState,
+    pairs: &[(&GraphNode, &GraphNode)],
+) -> Result<HashSet<usize>, anyhow::Error> {
+    let candidate_pairs = pairs
+        .iter()
+        .enumerate()
+        .map(|(i, (incoming, existing))| {
+            json!({
+                "pair": i,
+                "new": { "label": incoming.label, "props": incoming.properties },
+                "existing": { "label": existing.label, "props": existing.properties },
+            })
+        })
+        .collect::<Vec<Value>>();
+
+    let content = app_state
+        .llm
+        .get_tool_response(ToolPrompts::ResolveMatches.prompt_template().replace(
+            "{candidate_pairs}",
+            &serde_json::to_string(&candidate_pairs)?,
+        ))
+        .await?;
+
+    // a response that can't be read keeps the nodes apart, a duplicate is
+    // easier to fix than a lost node
+    let same = match serde_json::from_str::<MatchDecisions>(&content) {
+        Ok(decisions) => decisions
+            .decisions
+            .into_iter()
+            .filter(|d| d.same && d.pair < pairs.len())
+            .map(|d| d.pair)
+            .collect(),
+        Err(e) => {
+            warn!("Could not read match decisions: {}", e);
+            HashSet::new()
+        }
+    };
+
+    Ok(same)
+}
+
+pub async fn plan_merge(
+    app_state: &AppState,
+    existing: &GraphData,
+    existing_embeddings: &HashMap<String, Vec<f32>>,
+    incoming: GraphData,
+) -> Result<MergePlan, anyhow::Error> {
+    let mut matches: HashMap<String, String> = HashMap::new();
+    let mut ambiguous: Vec<(&GraphNode, &GraphNode)> = vec![];
+    // nodes without an exact match, compared by embedding once all of them
+    // are embedded
+    let mut unmatched: Vec<(&GraphNode, Vec<&GraphNode>, String)> = vec![];
+
+    for node in &incoming.nodes {
+        let candidates = existing
+            .nodes
+            .iter()
+            .filter(|c| c.label == node.label)
+            .collect::<Vec<&GraphNode>>();
+
+        if node.label == "User" {
+            if let Some(user) = candidates.first() {
+                matches.insert(node.id.clone(), user.id.clone());
+            }
+            continue;
+        }
+
+        let key = normalized_key(node);
+        if let Some(exact) = candidates
+            .iter()
+            .find(|c| key.is_some() && normalized_key(c) == key)
+        {
+            matches.insert(node.id.clone(), exact.id.clone());
+            continue;
+        }
+
+        // dates only ever match on their exact value
+        if node.label == "Date" {
+            continue;
+        }
+
+        let Some(content) = node.embedding_content()? else {
+            continue;
+        };
+        unmatched.push((node, candidates, content));
+    }
+
+    let embeddings = app_state
+        .llm
+        .get_embeddings(
+            unmatched
+                .iter()
+                .map(|(_, _, content)| content.clone())
+                .collect(),
+        )
+        .await?;
+
+    for ((node, candidates, _), embedding) in unmatched.iter().zip(&embeddings) {
+        let best = candidates
+            .iter()
+            .filter_map(|c| {
+                let score = cosine_similarity(embedding, existing_embeddings.get(&c.id)?);
+                Some((*c, score))
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        match best {
+            Some((candidate, score)) if score >= MATCH_THRESHOLD => {
+                matches.insert(node.id.clone(), candidate.id.clone());
+            }
+            Some((candidate, score)) if score >= AMBIGUOUS_THRESHOLD => {
+                ambiguous.push((*node, candidate));
+            }
+            _ => {}
+        }
+    }
+
+    if !ambiguous.is_empty() {
+        info!(
+            "Asking the model about {} ambiguous pairs.",
+            ambiguous.len()
+        );
+
+        for pair in resolve_ambiguous(app_state, &ambiguous).await? {
+            let (incoming, existing) = ambiguous[pair];
+            matches.insert(incoming.id.clone(), existing.id.clone());
+        }
+    }
+
+    let embeddings = unmatched
+        .into_iter()
+        .map(|(node, _, _)| node.id.clone())
+        .zip(embeddings)
+        .collect::<HashMap<String, Vec<f32>>>();
+
+    let existing_by_id = existing
+        .nodes
+        .iter()
+        .map(|n| (n.id.as_str(), n))
+        .collect::<HashMap<&str, &GraphNode>>();
+
+    let mut add = GraphData {
+        nodes: vec![],
+        relationships: vec![],
+    };
+    let mut updates: Vec<NodeUpdate> = vec![];
+    let mut skipped_nodes = vec![];
+
+    for node in incoming.nodes {
+        let Some(existing_node) = matches
+            .get(&node.id)
+            .and_then(|id| existing_by_id.get(id.as_str()))
+        else {
+            add.nodes.push(node);
+            continue;
+        };
+
+        let changed = changed_properties(existing_node, &node);
+        if changed.is_empty() || updates.iter().any(|u| u.node.id == existing_node.id) {
+            skipped_nodes.push(node.id);
+            continue;
+        }
+
+        updates.push(NodeUpdate::new(existing_node, changed));
+    }
+    refresh_embeddings(&app_state.llm, &existing_by_id, &mut updates).await?;
+
+    let mut seen = existing
+        .relationships
+        .iter()
+        .map(|r| (r.source_id.clone(), r.label.clone(), r.target_id.clone()))
+        .collect::<HashSet<(String, String, String)>>();
+    let mut skipped_relationships = 0;
+
+    for rel in incoming.relationships {
+        let rel = GraphRelationship {
+            source_id: matches
+                .get(&rel.source_id)
+                .unwrap_or(&rel.source_id)
+                .clone(),
+            target_id: matches
+                .get(&rel.target_id)
+                .unwrap_or(&rel.target_id)
+                .clone(),
+            label: rel.label,
+        };
+
+        if !seen.insert((
+            rel.source_id.clone(),
+            rel.label.clone(),
+            rel.target_id.clone(),
+        )) {
+            skipped_relationships += 1;
+            continue;
+        }
+
+        add.relationships.push(rel);
+    }
+
+    Ok(MergePlan {
+        add,
+        updates,
+        skipped_nodes,
+        skipped_relationships,
+        matches,
+        embeddings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::llm::MockProvider;
+
+    #[tokio::test]
+    async fn updates_changing_the_embedding_content_are_embedded_again() {
+        let stored = GraphNode {
+            id: String::from("motivation"),
+            label: String::from("Motivation"),
+            properties: HashMap::from([
+                (String::from("title"), json!("health")),
+                (String::from("reason"), json!("stay fit")),
+            ]),
+        };
+        let originals = HashMap::from([(stored.id.as_str(), &stored)]);
+        let mut updates = vec![
+            NodeUpdate::new(
+                &stored,
+                HashMap::from([(String::from("reason"), json!("run a marathon"))]),
+            ),
+            NodeUpdate::new(
+                &stored,
+                HashMap::from([(String::from("note"), json!("since 2020"))]),
+            ),
+        ];
+
+        let llm = Provider::Mock(MockProvider::new());
+        refresh_embeddings(&llm, &originals, &mut updates)
+            .await
+            .unwrap();
+
+        let content = updates[0].node.embedding_content().unwrap().unwrap();
+        assert_eq!(updates[0].embedding, Some(MockProvider::embed(&content)));
+        assert!(updates[1].embedding.is_none());
+    }
+}
@@ -1,3 +1,16 @@
+pub mod chat;
 pub mod config;
 pub mod constants;
+pub mod dedup;
+pub mod error;
+pub mod export;
 pub mod graph;
+pub mod history;
+pub mod import;
+pub mod jobs;
+pub mod llm;
+pub mod merge;
+pub mod provenance;
+pub mod retrieval;
+pub mod review;
+pub mod tasks;
@@ -0,0 +1,243 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::Utc;
+use neo4rs::Query;
+use uuid::Uuid;
+
+use crate::{
+    model::{Message, MessageEmbedding},
+    types::{GraphData, GraphNode, GraphRelationship, Provenance, ProvenanceExplanation},
+    utils::{
+        config::{AppState, Parsable},
+        error::ApiError,
+        llm::LlmProvider,
+        merge::cosine_similarity,
+    },
+};
+
+// the user's messages in the chat closest to a node are taken as the ones it
+// was extracted from
+const SOURCE_MESSAGES: usize = 3;
+const SOURCE_MIN_SIMILARITY: f32 = 0.2;
+
+fn source_messages(embedding: &[f32], messages: &[MessageEmbedding]) -> Vec<String> {
+    // messages are embedded in sections, a message scores as its best section
+    let mut scores: HashMap<Uuid, f32> = HashMap::new();
+    for message in messages {
+        let score = cosine_similarity(embedding, &message.embedding);
+        if score < SOURCE_MIN_SIMILARITY {
+            continue;
+        }
+
+        let best = scores.entry(message.message_id).or_insert(score);
+        *best = best.max(score);
+    }
+
+    let mut scores = scores.into_iter().collect::<Vec<(Uuid, f32)>>();
+    scores.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    scores
+        .into_iter()
+        .take(SOURCE_MESSAGES)
+        .map(|(message_id, _)| message_id.to_string())
+        .collect()
+}
+
+// The queries recording on every extracted node and relationship that it was
+// seen in `chat_id`, they run with the extraction's own writes. `stored_ids`
+// maps the ids of the extracted data to the ids the nodes are stored with,
+// whether they were just added or matched existing ones. `embeddings` holds
+// the embeddings already computed for extracted nodes, `stored_embeddings`
+// those of the stored nodes, anything missing is embedded in one go.
+pub async fn provenance_queries(
+    app_state: &AppState,
+    user_id: &Uuid,
+    chat_id: Uuid,
+    extracted: &GraphData,
+    stored_ids: &HashMap<String, String>,
+    embeddings: &HashMap<String, Vec<f32>>,
+    stored_embeddings: &HashMap<String, Vec<f32>>,
+) -> Result<Vec<Query>, anyhow::Error> {
+    let extracted_at = Utc::now().to_rfc3339();
+    let messages =
+        MessageEmbedding::user_messages_in_chat(&app_state.pool, *user_id, chat_id).await?;
+
+    // the user node is shared by every chat
+    let nodes = extracted
+        .nodes
+        .iter()
+        .filter(|node| node.label != "User")
+        .filter_map(|node| Some((node, stored_ids.get(&node.id)?)))
+        .collect::<Vec<(&GraphNode, &String)>>();
+
+    let mut node_embeddings: HashMap<&str, Vec<f32>> = HashMap::new();
+    let mut missing: Vec<(&str, String)> = vec![];
+    for (node, stored_id) in &nodes {
+        match embeddings
+            .get(&node.id)
+            .or_else(|| stored_embeddings.get(*stored_id))
+        {
+            Some(embedding) => {
+                node_embeddings.insert(&node.id, embedding.clone());
+            }
+            None => {
+                if let Some(content) = node.embedding_content()? {
+                    missing.push((&node.id, content));
+                }
+            }
+        }
+    }
+    let computed = app_state
+        .llm
+        .get_embeddings(missing.iter().map(|(_, content)| content.clone()).collect())
+        .await?;
+    node_embeddings.extend(missing.into_iter().map(|(id, _)| id).zip(computed));
+
+    let mut node_messages: HashMap<&str, Vec<String>> = HashMap::new();
+    let mut queries: Vec<Query> = vec![];
+
+    for (node, stored_id) in nodes {
+        let message_ids = match node_embeddings.get(node.id.as_str()) {
+            Some(embedding) => source_messages(embedding, &messages),
+            None => vec![],
+        };
+
+        let provenance = Provenance {
+            chat_ids: vec![chat_id.to_string()],
+            message_ids: message_ids.clone(),
+            extracted_at: Some(extracted_at.clone()),
+            extraction_model: Some(app_state.llm.tool_model().to_string()),
+        };
+
+        queries.push(provenance.node_query(&node.label, stored_id, user_id));
+        node_messages.insert(&node.id, message_ids);
+    }
+
+    let mut seen: HashSet<(String, String, String)> = HashSet::new();
+    for rel in &extracted.relationships {
+        let stored = GraphRelationship {
+            source_id: stored_ids
+                .get(&rel.source_id)
+                .unwrap_or(&rel.source_id)
+                .clone(),
+            target_id: stored_ids
+                .get(&rel.target_id)
+                .unwrap_or(&rel.target_id)
+                .clone(),
+            label: rel.label.clone(),
+        };
+
+        if !seen.insert((
+            stored.source_id.clone(),
+            stored.label.clone(),
+            stored.target_id.clone(),
+        )) {
+            continue;
+        }
+
+        // a relationship comes from wherever its two ends were mentioned
+        let mut message_ids: Vec<String> = vec![];
+        for id in [&rel.source_id, &rel.target_id] {
+            for message_id in node_messages.get(id.as_str()).into_iter().flatten() {
+                if !message_ids.contains(message_id) {
+                    message_ids.push(message_id.clone());
+                }
+            }
+        }
+
+        let provenance = Provenance {
+            chat_ids: vec![chat_id.to_string()],
+            message_ids,
+            extracted_at: Some(extracted_at.clone()),
+            extraction_model: Some(app_state.llm.tool_model().to_string()),
+        };
+
+        queries.push(provenance.relationship_query(&stored, user_id)?);
+    }
+
+    Ok(queries)
+}
+
+async fn explain(
+    app_state: &AppState,
+    user_id: &Uuid,
+    provenance: Provenance,
+) -> Result<ProvenanceExplanation, anyhow::Error> {
+    let message_ids = provenance
+        .message_ids
+        .iter()
+        .filter_map(|id| Uuid::parse_str(id).ok())
+        .collect::<Vec<Uuid>>();
+
+    let messages = Message::get_many_for_user(&app_state.pool, *user_id, &message_ids).await?;
+
+    Ok(ProvenanceExplanation {
+        provenance,
+        messages,
+    })
+}
+
+pub async fn explain_node(
+    app_state: &AppState,
+    user_id: &Uuid,
+    node_id: &str,
+) -> Result<ProvenanceExplanation, anyhow::Error> {
+    let provenance = app_state
+        .graph
+        .get_node_provenance(user_id, node_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("Node {} not found", node_id)))?;
+
+    explain(app_state, user_id, provenance).await
+}
+
+pub async fn explain_relationship(
+    app_state: &AppState,
+    user_id: &Uuid,
+    relationship: &GraphRelationship,
+) -> Result<ProvenanceExplanation, anyhow::Error> {
+    let provenance = app_state
+        .graph
+        .get_relationship_provenance(user_id, relationship)
+        .await?
+        .ok_or_else(|| {
+            ApiError::NotFound(format!(
+                "Relationship {} -[{}]-> {} not found",
+                relationship.source_id, relationship.label, relationship.target_id
+            ))
+        })?;
+
+    explain(app_state, user_id, provenance).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(message_id: Uuid, embedding: Vec<f32>) -> MessageEmbedding {
+        MessageEmbedding {
+            id: Uuid::new_v4(),
+            message_id,
+            embedding,
+            section: None,
+        }
+    }
+
+    #[test]
+    fn sources_are_the_closest_messages_by_their_best_section() {
+        let close = Uuid::new_v4();
+        let closer = Uuid::new_v4();
+        let unrelated = Uuid::new_v4();
+        let messages = vec![
+            section(close, vec![1.0, 1.0]),
+            section(closer, vec![0.0, 1.0]),
+            section(closer, vec![1.0, 0.1]),
+            section(unrelated, vec![-1.0, 0.0]),
+        ];
+
+        assert_eq!(
+            source_messages(&[1.0, 0.0], &messages),
+            vec![closer.to_string(), close.to_string()]
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use crate::{
+    model::MessageEmbedding,
+    utils::{
+        config::{AppState, Parsable},
+        constants::SEARCH_TOP_K,
+        llm::LlmProvider,
+    },
+};
+
+const CONTEXT_TOKEN_BUDGET: usize = 1500;
+const MAX_ITEM_TOKENS: usize = 200;
+const MESSAGE_TOP_K: i64 = 8;
+// rough average for english text, good enough to keep the prompt bounded
+// without pulling in a tokenizer
+const CHARS_PER_TOKEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ContextSource {
+    Graph,
+    Message,
+}
+
+#[derive(Debug, Clone)]
+struct ContextItem {
+    source: ContextSource,
+    // what duplicates are detected on, without dates or roles
+    key: String,
+    text: String,
+    score: f32,
+}
+
+fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+fn truncate_to_tokens(text: &str, max_tokens: usize) -> String {
+    let max_chars = max_tokens * CHARS_PER_TOKEN;
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+
+    let truncated: String = text.chars().take(max_chars).collect();
+    format!("{}...", truncated.trim_end())
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+// Keeps the best scoring copy of every item, ranks everything by similarity
+// and fills the budget greedily. Items that don't fit are skipped rather than
+// ending the fill, so a long message doesn't crowd out shorter ones below it.
+fn rank_and_budget(items: Vec<ContextItem>, budget: usize) -> Vec<ContextItem> {
+    let mut unique: HashMap<String, ContextItem> = HashMap::new();
+    for item in items {
+        match unique.get(&item.key) {
+            Some(existing) if existing.score >= item.score => {}
+            _ => {
+                unique.insert(item.key.clone(), item);
+            }
+        }
+    }
+
+    let mut ranked: Vec<ContextItem> = unique.into_values().collect();
+    ranked.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.text.cmp(&b.text)));
+
+    let mut used = 0;
+    ranked
+        .into_iter()
+        .filter(|item| {
+            let tokens = estimate_tokens(&item.text);
+            if used + tokens > budget {
+                return false;
+            }
+            used += tokens;
+            true
+        })
+        .collect()
+}
+
+fn render_sections(items: &[ContextItem]) -> String {
+    let section = |source: ContextSource, title: &str| {
+        let lines = items
+            .iter()
+            .filter(|item| item.source == source)
+            .map(|item| format!("- {}", item.text))
+            .collect::<Vec<String>>();
+
+        match lines.is_empty() {
+            true => format!("## {}\nNothing relevant found.\n", title),
+            false => format!("## {}\n{}\n", title, lines.join("\n")),
+        }
+    };
+
+    format!(
+        "{}\n{}",
+        section(ContextSource::Graph, "Knowledge graph"),
+        section(ContextSource::Message, "Relevant past messages")
+    )
+}
+
+// Builds the `{context}` for a prompt from the user's knowledge graph and
+// their most similar past messages. Messages from `current_chat` are left
+// out since they are already part of the conversation.
+pub async fn retrieve_context(
+    app_state: &AppState,
+    user_id: &Uuid,
+    query: String,
+    threshold: f32,
+    current_chat: Option<Uuid>,
+) -> Result<String, anyhow::Error> {
+    let embedding = app_state.llm.get_embedding(query).await?;
+
+    let graph = app_state
+        .graph
+        .semantic_search(user_id, embedding.clone(), threshold, SEARCH_TOP_K, &[])
+        .await?;
+
+    let messages = MessageEmbedding::nearest(
+        &app_state.pool,
+        *user_id,
+        &embedding,
+        MESSAGE_TOP_K * 2,
+        None,
+        threshold as f64,
+    )
+    .await?;
+
+    let graph_items = graph
+        .to_scored_lines()?
+        .into_iter()
+        .map(|(text, score)| ContextItem {
+            source: ContextSource::Graph,
+            key: normalize(&text),
+            text,
+            score,
+        });
+
+    let message_items = messages
+        .into_iter()
+        .filter(|message| Some(message.chat_id) != current_chat)
+        .take(MESSAGE_TOP_K as usize)
+        .map(|message| ContextItem {
+            source: ContextSource::Message,
+            key: normalize(&message.content),
+            text: format!(
+                "[{}, {}] {}",
+                message.created_at.format("%B %d, %Y"),
+                message.role,
+                truncate_to_tokens(&message.content, MAX_ITEM_TOKENS)
+            ),
+            score: message.similarity as f32,
+        });
+
+    let items = rank_and_budget(
+        graph_items.chain(message_items).collect(),
+        CONTEXT_TOKEN_BUDGET,
+    );
+
+    Ok(render_sections(&items))
+}
@@ -0,0 +1,274 @@
+use std::collections::HashSet;
+
+use chrono::{Duration, Local, Utc};
+use neo4rs::{query, Query};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    model::{Chat, GraphChangeset, Message},
+    types::{
+        node_pattern, ChangeSource, ChatPrompts, GoalProgressUpdate, GoalReviewSummary, GraphData,
+        GraphNode, GraphSchema, ListChatsQuery, ToolPrompts,
+    },
+    utils::{
+        chat::extract_final_message,
+        config::AppState,
+        graph::get_owned_graph,
+        history::{run_recorded, Scope},
+        llm::LlmProvider,
+    },
+};
+
+const REVIEW_DAYS: i64 = 7;
+
+#[derive(Debug, Deserialize)]
+struct GoalDecisions {
+    goals: Vec<GoalDecision>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GoalDecision {
+    id: String,
+    progress: String,
+}
+
+fn text_property<'a>(node: &'a GraphNode, key: &str) -> Option<&'a str> {
+    node.properties.get(key).and_then(|v| v.as_str())
+}
+
+// A plain text summary of the user's goals for the WeeklyReview prompt
+pub fn goal_overview(graph: &GraphData) -> String {
+    let mut lines = graph
+        .nodes
+        .iter()
+        .filter(|node| node.label == "Goal")
+        .filter_map(|goal| {
+            let description = text_property(goal, "description")?;
+            let progress = text_property(goal, "progress").unwrap_or("not reviewed yet");
+
+            Some(match text_property(goal, "timeframe") {
+                Some(timeframe) => format!("- {} ({}, {})", description, timeframe, progress),
+                None => format!("- {} ({})", description, progress),
+            })
+        })
+        .collect::<Vec<String>>();
+    lines.sort();
+
+    match lines.is_empty() {
+        true => String::from("No goals recorded yet."),
+        false => lines.join("\n"),
+    }
+}
+
+// Every task that was added or changed status over the past week, read from
+// the graph's history so changes made through any route show up
+pub async fn task_changes(app_state: &AppState, user_id: &Uuid) -> Result<String, anyhow::Error> {
+    let since = Utc::now() - Duration::days(REVIEW_DAYS);
+    let changesets = GraphChangeset::since(&app_state.pool, *user_id, since).await?;
+
+    let mut lines = vec![];
+    for changeset in &changesets {
+        let day = changeset
+            .created_at
+            .with_timezone(&Local)
+            .format("%A, %B %d");
+        let changes = &changeset.changes.0;
+
+        for node in changes.added_nodes.iter().filter(|n| n.label == "Task") {
+            if let Some(action) = text_property(node, "action") {
+                lines.push(format!("- {}: New task: {}", day, action));
+            }
+        }
+
+        for change in changes
+            .updated_nodes
+            .iter()
+            .filter(|c| c.after.label == "Task")
+        {
+            // tasks without a status haven't been started yet
+            let before = text_property(&change.before, "status").unwrap_or("pending");
+            let after = text_property(&change.after, "status").unwrap_or("pending");
+            if before == after {
+                continue;
+            }
+
+            if let Some(action) = text_property(&change.after, "action") {
+                lines.push(format!(
+                    "- {}: {} went from {} to {}",
+                    day, action, before, after
+                ));
+            }
+        }
+    }
+
+    match lines.is_empty() {
+        true => Ok(String::from("No task changes this week.")),
+        false => Ok(lines.join("\n")),
+    }
+}
+
+// the plans the user settled on in the past week's DailyOutline chats,
+// outlines that were never finished are left out
+pub async fn daily_outlines(app_state: &AppState, user_id: &Uuid) -> Result<String, anyhow::Error> {
+    let mut chats = vec![];
+    for archived in [false, true] {
+        let filter = ListChatsQuery {
+            limit: Some(100),
+            offset: None,
+            flavour: Some(ChatPrompts::DailyOutline),
+            since: Some(Utc::now() - Duration::days(REVIEW_DAYS)),
+            until: None,
+            archived,
+            deleted: false,
+        };
+        chats.extend(Chat::list_for_user(&app_state.pool, *user_id, &filter).await?);
+    }
+    chats.sort_by_key(|chat| chat.created_at);
+
+    let mut lines = vec![];
+    for chat in &chats {
+        let messages = Message::get_all_messages_for_chat(&app_state.pool, chat.id).await?;
+
+        let plan = messages
+            .iter()
+            .rev()
+            .filter(|m| m.role == "assistant")
+            .find_map(|m| extract_final_message(&m.content).transpose())
+            .transpose()?;
+
+        if let Some(plan) = plan {
+            lines.push(format!(
+                "- {}: {}",
+                chat.created_at.with_timezone(&Local).format("%A, %B %d"),
+                plan.trim()
+            ));
+        }
+    }
+
+    match lines.is_empty() {
+        true => Ok(String::from("No daily outlines this week.")),
+        false => Ok(lines.join("\n")),
+    }
+}
+
+// Reads the progress of the user's goals from a finished WeeklyReview chat
+// and stores it on the Goal nodes. Goals the review didn't cover keep their
+// progress.
+pub async fn review_goals(
+    app_state: &AppState,
+    user_id: &Uuid,
+    chat_id: Uuid,
+) -> Result<GoalReviewSummary, anyhow::Error> {
+    Chat::get_for_user(&app_state.pool, chat_id, *user_id)
+        .await?
+        .check_active()?;
+
+    let graph = get_owned_graph(app_state, user_id).await?;
+    let goals = graph
+        .nodes
+        .iter()
+        .filter(|node| node.label == "Goal")
+        .collect::<Vec<&GraphNode>>();
+
+    if goals.is_empty() {
+        return Ok(GoalReviewSummary {
+            chat_id,
+            updates: vec![],
+        });
+    }
+
+    let review = Message::get_all_messages_for_chat(&app_state.pool, chat_id)
+        .await?
+        .iter()
+        .map(|m| format!("{}: {}", m.role, m.content))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let goal_list = goals
+        .iter()
+        .map(|goal| {
+            json!({
+                "id": goal.id,
+                "description": goal.properties.get("description"),
+                "progress": goal.properties.get("progress"),
+            })
+        })
+        .collect::<Vec<Value>>();
+
+    let content = app_state
+        .llm
+        .get_tool_response(
+            ToolPrompts::ReviewGoals
+                .prompt_template()
+                .replace("{review}", &review)
+                .replace("{goals}", &serde_json::to_string(&goal_list)?),
+        )
+        .await?;
+    let decisions = serde_json::from_str::<GoalDecisions>(&content)?;
+
+    let allowed = GraphSchema::get()
+        .node_type("Goal")
+        .and_then(|node_type| node_type.properties.get("progress"))
+        .and_then(|definition| definition.allowed_values.clone())
+        .unwrap_or_default();
+
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut updates = vec![];
+    let mut queries: Vec<Query> = vec![];
+    for decision in decisions.goals {
+        let Some(goal) = goals.iter().find(|goal| goal.id == decision.id) else {
+            warn!("Goal review returned unknown goal {}", decision.id);
+            continue;
+        };
+
+        if !allowed.contains(&decision.progress) {
+            warn!(
+                "Goal review returned invalid progress {} for goal {}",
+                decision.progress, decision.id
+            );
+            continue;
+        }
+
+        let before = text_property(goal, "progress").map(String::from);
+        if before.as_deref() == Some(decision.progress.as_str()) || !seen.insert(goal.id.clone()) {
+            continue;
+        }
+
+        queries.push(
+            query(&format!(
+                "MATCH {} SET n.progress = $progress",
+                node_pattern("n", "Goal", "id")
+            ))
+            .param("id", goal.id.clone())
+            .param("user_id", user_id.to_string())
+            .param("progress", decision.progress.clone()),
+        );
+        updates.push(GoalProgressUpdate {
+            goal_id: goal.id.clone(),
+            description: text_property(goal, "description")
+                .unwrap_or_default()
+                .to_string(),
+            before,
+            after: decision.progress,
+        });
+    }
+
+    if !queries.is_empty() {
+        run_recorded(
+            app_state,
+            user_id,
+            ChangeSource::Review,
+            Some(chat_id),
+            Scope::Nodes(updates.iter().map(|u| u.goal_id.clone()).collect()),
+            queries,
+        )
+        .await?;
+    }
+
+    info!("Updated progress of {} goals.", updates.len());
+
+    Ok(GoalReviewSummary { chat_id, updates })
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use chrono::{Datelike, Duration, Local, NaiveDate};
+use neo4rs::query;
+use uuid::Uuid;
+
+use crate::{
+    types::{
+        node_pattern, ChangeSource, GraphData, GraphNode, GraphRelationship, GraphSchema, TaskItem,
+        TaskStatus,
+    },
+    utils::{
+        config::AppState,
+        error::ApiError,
+        graph::{check_report, get_owned_graph},
+        history::{run_recorded, Scope},
+    },
+};
+
+const NEW_DATE_ID: &str = "new_date";
+const HISTORY_DAYS: i64 = 7;
+
+pub fn today() -> NaiveDate {
+    Local::now().date_naive()
+}
+
+fn node_date(node: &GraphNode) -> Option<NaiveDate> {
+    let part = |key: &str| node.properties.get(key).and_then(|v| v.as_u64());
+
+    NaiveDate::from_ymd_opt(
+        part("year")? as i32,
+        part("month")? as u32,
+        part("day")? as u32,
+    )
+}
+
+fn collect_tasks(graph: &GraphData) -> Vec<TaskItem> {
+    let dates = graph
+        .nodes
+        .iter()
+        .filter(|node| node.label == "Date")
+        .filter_map(|node| Some((node.id.as_str(), node_date(node)?)))
+        .collect::<HashMap<&str, NaiveDate>>();
+
+    let linked_date = |task_id: &str, label: &str| {
+        graph
+            .relationships
+            .iter()
+            .filter(|r| r.source_id == task_id && r.label == label)
+            .filter_map(|r| dates.get(r.target_id.as_str()).copied())
+            .max()
+    };
+
+    let mut tasks = graph
+        .nodes
+        .iter()
+        .filter(|node| node.label == "Task")
+        .map(|node| TaskItem {
+            id: node.id.clone(),
+            action: node
+                .properties
+                .get("action")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            status: TaskStatus::parse(node.properties.get("status").and_then(|v| v.as_str())),
+            created_on: linked_date(&node.id, "CREATED_ON"),
+            completed_on: linked_date(&node.id, "COMPLETED_ON"),
+        })
+        .collect::<Vec<TaskItem>>();
+
+    tasks.sort_by(|a, b| a.created_on.cmp(&b.created_on).then(a.id.cmp(&b.id)));
+
+    tasks
+}
+
+// Everything that is on the user's plate for `date`: tasks created or
+// completed that day plus whatever was still open by then.
+pub async fn list_tasks(
+    app_state: &AppState,
+    user_id: &Uuid,
+    date: NaiveDate,
+) -> Result<Vec<TaskItem>, anyhow::Error> {
+    let graph = get_owned_graph(app_state, user_id).await?;
+
+    let tasks = collect_tasks(&graph)
+        .into_iter()
+        .filter(|task| {
+            task.created_on == Some(date)
+                || task.completed_on == Some(date)
+                || (task.status.is_open() && task.created_on.is_none_or(|d| d <= date))
+        })
+        .collect();
+
+    Ok(tasks)
+}
+
+pub async fn transition_task(
+    app_state: &AppState,
+    user_id: &Uuid,
+    task_id: &str,
+    status: TaskStatus,
+) -> Result<TaskItem, anyhow::Error> {
+    let graph = get_owned_graph(app_state, user_id).await?;
+
+    let mut task = collect_tasks(&graph)
+        .into_iter()
+        .find(|task| task.id == task_id)
+        .ok_or_else(|| ApiError::NotFound(format!("Task {} not found", task_id)))?;
+
+    if !task.status.can_transition_to(status) {
+        return Err(ApiError::BadRequest(format!(
+            "Task cannot go from {} to {}",
+            task.status.as_str(),
+            status.as_str()
+        ))
+        .into());
+    }
+
+    let mut queries = vec![query(&format!(
+        "MATCH {} SET t.status = $status",
+        node_pattern("t", "Task", "id")
+    ))
+    .param("id", task_id)
+    .param("user_id", user_id.to_string())
+    .param("status", status.as_str())];
+
+    let mut scope = Scope::Nodes(vec![task_id.to_string()]);
+    if status == TaskStatus::Completed {
+        let date = today();
+
+        // reuse the user's node for today when there already is one
+        let existing_date = graph
+            .nodes
+            .iter()
+            .find(|node| node.label == "Date" && node_date(node) == Some(date));
+
+        let mut completion = GraphData {
+            nodes: vec![],
+            relationships: vec![],
+        };
+        let date_id = match existing_date {
+            Some(node) => node.id.clone(),
+            None => {
+                completion.nodes.push(GraphNode {
+                    id: NEW_DATE_ID.to_string(),
+                    label: String::from("Date"),
+                    properties: HashMap::from([
+                        ("day".to_string(), date.day().into()),
+                        ("month".to_string(), date.month().into()),
+                        ("year".to_string(), date.year().into()),
+                    ]),
+                });
+                NEW_DATE_ID.to_string()
+            }
+        };
+        completion.relationships.push(GraphRelationship {
+            source_id: task_id.to_string(),
+            target_id: date_id,
+            label: String::from("COMPLETED_ON"),
+        });
+
+        let existing_nodes = graph
+            .nodes
+            .iter()
+            .map(|n| (n.id.clone(), n.label.clone()))
+            .collect::<HashMap<String, String>>();
+        check_report(GraphSchema::get().validate(&mut completion, &existing_nodes))?;
+
+        let completion_queries = completion
+            .clone()
+            .into_queries(user_id, &app_state.llm)
+            .await?;
+        scope = Scope::written(&completion, &completion_queries.node_ids);
+        queries.extend(completion_queries.queries);
+
+        task.completed_on = Some(date);
+    }
+
+    run_recorded(app_state, user_id, ChangeSource::Task, None, scope, queries).await?;
+
+    task.status = status;
+
+    Ok(task)
+}
+
+// A plain text summary of recent and open tasks for the DailyOutline prompt
+pub async fn task_history(app_state: &AppState, user_id: &Uuid) -> Result<String, anyhow::Error> {
+    let graph = get_owned_graph(app_state, user_id).await?;
+    let since = today() - Duration::days(HISTORY_DAYS);
+
+    let lines = collect_tasks(&graph)
+        .into_iter()
+        .filter_map(|task| {
+            let line = match (task.status, task.completed_on) {
+                (TaskStatus::Completed, Some(completed_on)) if completed_on >= since => format!(
+                    "Completed on {}: {}",
+                    completed_on.format("%B %d, %Y"),
+                    task.action
+                ),
+                (TaskStatus::Completed, _) => return None,
+                (TaskStatus::Failed, _) if task.created_on.is_none_or(|d| d >= since) => {
+                    format!("Failed: {}", task.action)
+                }
+                (TaskStatus::Failed, _) => return None,
+                (TaskStatus::InProgress, _) => format!("In progress: {}", task.action),
+                (TaskStatus::Pending, _) => format!("Pending: {}", task.action),
+            };
+
+            Some(format!("- {}", line))
+        })
+        .collect::<Vec<String>>();
+
+    match lines.is_empty() {
+        true => Ok(String::from("No tasks recorded yet.")),
+        false => Ok(lines.join("\n")),
+    }
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use actix_web::{middleware::from_fn, test, web, App};
+use console::{
+    middleware::auth::authenticate_user,
+    model::{Job, Message},
+    routes,
+    types::JobKind,
+    utils::llm::MockProvider,
+};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+#[actix_web::test]
+async fn send_message_stores_the_turn_and_enqueues_extraction() {
+    let mock = MockProvider::new().with_chat_responses(vec![String::from(
+        "<final_message>Thanks, that's all I need.</final_message>",
+    )]);
+    let Some(app_state) = common::app_state(mock).await else {
+        return;
+    };
+    let pool = app_state.pool.clone();
+
+    let app = test::init_service(
+        App::new().app_data(web::Data::new(app_state)).service(
+            web::scope("/ai")
+                .wrap(from_fn(authenticate_user))
+                .service(routes::ai::send_message),
+        ),
+    )
+    .await;
+
+    let user_id = Uuid::new_v4();
+    let chat_id = Uuid::new_v4();
+    let request = test::TestRequest::post()
+        .uri("/ai/send-message")
+        .insert_header(("user-id", user_id.to_string()))
+        .set_json(json!({
+            "chat_id": chat_id,
+            "model": "mock",
+            "message": "I want to run a marathon.",
+            "flavour": "initial_goals",
+        }))
+        .to_request();
+
+    let response: Value = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(
+        response["content"],
+        "<final_message>Thanks, that's all I need.</final_message>"
+    );
+
+    let messages = Message::get_all_messages_for_chat(&pool, chat_id)
+        .await
+        .unwrap();
+    assert_eq!(
+        messages
+            .iter()
+            .map(|m| m.role.as_str())
+            .collect::<Vec<&str>>(),
+        vec!["user", "assistant"]
+    );
+
+    let jobs = Job::list_for_user(&pool, user_id, 10).await.unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].kind, JobKind::ExtractKnowledge);
+    assert_eq!(jobs[0].payload["chat_id"], json!(chat_id));
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use actix_web::{http::StatusCode, middleware::from_fn, test, web, App};
+use console::{
+    middleware::auth::authenticate_user,
+    model::{Chat, User},
+    routes,
+    types::ChatPrompts,
+    utils::{error::ApiError, llm::MockProvider},
+};
+use serde_json::json;
+use uuid::Uuid;
+
+#[actix_web::test]
+async fn get_for_user_rejects_another_users_chat() {
+    let Some(app_state) = common::app_state(MockProvider::new()).await else {
+        return;
+    };
+    let pool = &app_state.pool;
+
+    let owner = Uuid::new_v4();
+    User::get_or_create(pool, owner).await.unwrap();
+    let chat = Chat::new(pool, None, owner, ChatPrompts::InitialGoals)
+        .await
+        .unwrap();
+
+    assert!(Chat::get_for_user(pool, chat.id, owner).await.is_ok());
+    assert!(matches!(
+        Chat::get_for_user(pool, chat.id, Uuid::new_v4()).await,
+        Err(ApiError::Forbidden(_))
+    ));
+    assert!(matches!(
+        Chat::get_for_user(pool, Uuid::new_v4(), owner).await,
+        Err(ApiError::NotFound(_))
+    ));
+}
+
+#[actix_web::test]
+async fn chat_routes_reject_another_user() {
+    let Some(app_state) = common::app_state(MockProvider::new()).await else {
+        return;
+    };
+    let pool = app_state.pool.clone();
+
+    let owner = Uuid::new_v4();
+    User::get_or_create(&pool, owner).await.unwrap();
+    let chat = Chat::new(&pool, None, owner, ChatPrompts::InitialGoals)
+        .await
+        .unwrap();
+
+    let app = test::init_service(
+        App::new().app_data(web::Data::new(app_state)).service(
+            web::scope("/chats")
+                .wrap(from_fn(authenticate_user))
+                .service(routes::chat::get_chat)
+                .service(routes::chat::update_chat)
+                .service(routes::chat::delete_chat),
+        ),
+    )
+    .await;
+
+    let intruder = Uuid::new_v4().to_string();
+    let requests = [
+        test::TestRequest::get().uri(&format!("/chats/{}", chat.id)),
+        test::TestRequest::patch()
+            .uri(&format!("/chats/{}", chat.id))
+            .set_json(json!({ "title": "mine now" })),
+        test::TestRequest::delete().uri(&format!("/chats/{}", chat.id)),
+    ];
+    for request in requests {
+        let response = test::call_service(
+            &app,
+            request
+                .insert_header(("user-id", intruder.as_str()))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri(&format!("/chats/{}", Uuid::new_v4()))
+            .insert_header(("user-id", intruder.as_str()))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // the owner's chat is untouched
+    let stored = Chat::get(&pool, chat.id).await.unwrap().unwrap();
+    assert!(stored.title.is_none());
+    assert!(stored.deleted_at.is_none());
+}
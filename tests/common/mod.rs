@@ -0,0 +1,75 @@
+// shared by every test crate, not all of them use every helper
+#![allow(dead_code)]
+
+use console::{
+    middleware::auth::AuthConfig,
+    utils::{
+        config::{AppEnv, AppState},
+        llm::{MockProvider, Provider},
+    },
+};
+use neo4rs::{ConfigBuilder, Graph};
+use sqlx::PgPool;
+
+fn test_env(database_url: String) -> AppEnv {
+    AppEnv {
+        database_url,
+        openai_api_key: None,
+        neo4j_uri: std::env::var("NEO4J_URI").unwrap_or_else(|_| String::from("127.0.0.1:7687")),
+        neo4j_password: std::env::var("NEO4J_PASSWORD").unwrap_or_default(),
+        jwt_secret: None,
+        jwt_jwks_path: None,
+        jwt_issuer: None,
+        jwt_audience: None,
+        allow_user_id_header: true,
+        job_workers: 0,
+        llm_provider: String::from("mock"),
+        llm_base_url: None,
+        llm_chat_model: None,
+        llm_tool_model: None,
+        llm_embedding_model: None,
+    }
+}
+
+// State backed by the database in DATABASE_URL, None when it isn't set so the
+// tests that need postgres are skipped. Neo4j connections are only opened
+// when a query runs, tests that touch the graph also need NEO4J_URI.
+pub async fn app_state(mock: MockProvider) -> Option<AppState> {
+    let Ok(database_url) = std::env::var("DATABASE_URL") else {
+        eprintln!("DATABASE_URL is not set, skipping");
+        return None;
+    };
+
+    let app_env = test_env(database_url);
+
+    let pool = PgPool::connect(&app_env.database_url)
+        .await
+        .expect("Failed to connect to the test database");
+
+    let graph = Graph::connect(
+        ConfigBuilder::default()
+            .uri(&app_env.neo4j_uri)
+            .user("neo4j")
+            .password(&app_env.neo4j_password)
+            .db("neo4j")
+            .build()
+            .expect("Invalid neo4j config"),
+    )
+    .await
+    .expect("Failed to set up the neo4j pool");
+
+    Some(AppState {
+        pool,
+        graph,
+        llm: Provider::Mock(mock),
+        auth: AuthConfig::new(&app_env).expect("Invalid auth config"),
+    })
+}
+
+pub fn has_neo4j() -> bool {
+    let set = std::env::var("NEO4J_URI").is_ok();
+    if !set {
+        eprintln!("NEO4J_URI is not set, skipping");
+    }
+    set
+}
@@ -0,0 +1,169 @@
+mod common;
+
+use std::collections::HashSet;
+
+use console::{
+    types::{GraphData, GraphNode, GraphRelationship},
+    utils::{
+        config::{AppState, Parsable},
+        llm::MockProvider,
+    },
+};
+use neo4rs::query;
+use serde_json::json;
+use uuid::Uuid;
+
+async fn write_graph(app_state: &AppState, user_id: &Uuid) {
+    let data = GraphData {
+        nodes: vec![
+            GraphNode {
+                id: String::from("user"),
+                label: String::from("User"),
+                properties: Default::default(),
+            },
+            GraphNode {
+                id: String::from("interest"),
+                label: String::from("Interest"),
+                properties: [(String::from("name"), json!("marathon running"))].into(),
+            },
+        ],
+        relationships: vec![GraphRelationship {
+            source_id: String::from("user"),
+            target_id: String::from("interest"),
+            label: String::from("INTERESTED_IN"),
+        }],
+    };
+
+    let queries = data.into_queries(user_id, &app_state.llm).await.unwrap();
+    app_state.graph.run_queries(queries.queries).await.unwrap();
+}
+
+async fn owned_ids(app_state: &AppState, user_id: &Uuid) -> HashSet<String> {
+    let graph: GraphData = app_state
+        .graph
+        .get_full_graph(user_id)
+        .await
+        .unwrap()
+        .try_into()
+        .unwrap();
+
+    graph.nodes.into_iter().map(|node| node.id).collect()
+}
+
+async fn remove_graph(app_state: &AppState, user_id: &Uuid) {
+    app_state
+        .graph
+        .run_queries(vec![query(
+            "MATCH (n {owner_id: $user_id}) DETACH DELETE n",
+        )
+        .param("user_id", user_id.to_string())])
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn users_graphs_stay_disjoint() {
+    if !common::has_neo4j() {
+        return;
+    }
+    let Some(app_state) = common::app_state(MockProvider::new()).await else {
+        return;
+    };
+    app_state.graph.ensure_search_index().await.unwrap();
+
+    // both users get the same interest, so only the owner filter keeps their
+    // graphs apart
+    let alice = Uuid::new_v4();
+    let bob = Uuid::new_v4();
+    write_graph(&app_state, &alice).await;
+    write_graph(&app_state, &bob).await;
+
+    let alice_ids = owned_ids(&app_state, &alice).await;
+    let bob_ids = owned_ids(&app_state, &bob).await;
+    assert_eq!(alice_ids.len(), 2);
+    assert_eq!(bob_ids.len(), 2);
+    assert!(alice_ids.is_disjoint(&bob_ids));
+
+    let embeddings = app_state.graph.get_node_embeddings(&bob).await.unwrap();
+    assert!(embeddings.keys().all(|id| bob_ids.contains(id)));
+
+    let found: GraphData = app_state
+        .graph
+        .semantic_search(
+            &bob,
+            MockProvider::embed("Interest: marathon running"),
+            0.0,
+            10,
+            &[],
+        )
+        .await
+        .unwrap()
+        .try_into()
+        .unwrap();
+    assert!(!found.nodes.is_empty());
+    assert!(found.nodes.iter().all(|node| bob_ids.contains(&node.id)));
+
+    for alice_id in &alice_ids {
+        let subgraph = app_state
+            .graph
+            .traverse(&bob, alice_id, &[], 2)
+            .await
+            .unwrap();
+        assert!(subgraph.is_none());
+    }
+
+    remove_graph(&app_state, &alice).await;
+    remove_graph(&app_state, &bob).await;
+}
+
+#[tokio::test]
+async fn unconnected_nodes_stay_in_the_graph() {
+    if !common::has_neo4j() {
+        return;
+    }
+    let Some(app_state) = common::app_state(MockProvider::new()).await else {
+        return;
+    };
+
+    let alice = Uuid::new_v4();
+    let bob = Uuid::new_v4();
+    write_graph(&app_state, &alice).await;
+    app_state
+        .graph
+        .run_queries(vec![query(
+            "MATCH ({owner_id: $user_id})-[r:INTERESTED_IN]-({owner_id: $user_id}) DELETE r",
+        )
+        .param("user_id", alice.to_string())])
+        .await
+        .unwrap();
+
+    let alice_ids = owned_ids(&app_state, &alice).await;
+    assert_eq!(alice_ids.len(), 2);
+
+    for id in &alice_ids {
+        let node = app_state.graph.get_node(&alice, id).await.unwrap();
+        assert_eq!(node.map(|node| node.id).as_ref(), Some(id));
+        assert!(app_state.graph.get_node(&bob, id).await.unwrap().is_none());
+
+        // a traversal without relationships to follow still has its start
+        let subgraph: GraphData = app_state
+            .graph
+            .traverse(&alice, id, &[], 2)
+            .await
+            .unwrap()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            subgraph
+                .nodes
+                .iter()
+                .map(|node| &node.id)
+                .collect::<Vec<&String>>(),
+            vec![id]
+        );
+        assert!(subgraph.relationships.is_empty());
+    }
+
+    remove_graph(&app_state, &alice).await;
+}
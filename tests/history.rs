@@ -0,0 +1,47 @@
+mod common;
+
+use std::time::Duration;
+
+use console::{model::GraphChangeset, utils::llm::MockProvider};
+use uuid::Uuid;
+
+#[tokio::test]
+async fn recorded_writes_of_a_user_wait_for_each_other() {
+    let Some(app_state) = common::app_state(MockProvider::new()).await else {
+        return;
+    };
+    let pool = app_state.pool;
+    let user_id = Uuid::new_v4();
+
+    let mut first = pool.begin().await.unwrap();
+    GraphChangeset::lock_versions(&mut first, user_id)
+        .await
+        .unwrap();
+
+    // another user isn't held up
+    let mut other = pool.begin().await.unwrap();
+    GraphChangeset::lock_versions(&mut other, Uuid::new_v4())
+        .await
+        .unwrap();
+    other.commit().await.unwrap();
+
+    let waiting = tokio::spawn({
+        let pool = pool.clone();
+        async move {
+            let mut second = pool.begin().await.unwrap();
+            GraphChangeset::lock_versions(&mut second, user_id)
+                .await
+                .unwrap();
+            second.commit().await.unwrap();
+        }
+    });
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!waiting.is_finished());
+
+    first.commit().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), waiting)
+        .await
+        .unwrap()
+        .unwrap();
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use console::{
+    model::{Chat, Message, MessageEmbedding, User},
+    types::ChatPrompts,
+    utils::llm::MockProvider,
+};
+use uuid::Uuid;
+
+#[actix_web::test]
+async fn only_user_messages_are_provenance_sources() {
+    let Some(app_state) = common::app_state(MockProvider::new()).await else {
+        return;
+    };
+    let pool = &app_state.pool;
+
+    let owner = Uuid::new_v4();
+    User::get_or_create(pool, owner).await.unwrap();
+    let chat = Chat::new(pool, None, owner, ChatPrompts::InitialGoals)
+        .await
+        .unwrap();
+
+    let (said, _) = Message::new_with_embedding(
+        pool,
+        &app_state.llm,
+        chat.id,
+        String::from("user"),
+        String::from("I want to run a marathon."),
+    )
+    .await
+    .unwrap();
+    Message::new_with_embedding(
+        pool,
+        &app_state.llm,
+        chat.id,
+        String::from("assistant"),
+        String::from("A marathon is a great goal."),
+    )
+    .await
+    .unwrap();
+
+    let sources = MessageEmbedding::user_messages_in_chat(pool, owner, chat.id)
+        .await
+        .unwrap();
+    assert_eq!(
+        sources.iter().map(|e| e.message_id).collect::<Vec<Uuid>>(),
+        vec![said.id]
+    );
+
+    let intruder = MessageEmbedding::user_messages_in_chat(pool, Uuid::new_v4(), chat.id)
+        .await
+        .unwrap();
+    assert!(intruder.is_empty());
+}